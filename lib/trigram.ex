@@ -3,7 +3,8 @@ defmodule Trigram do
   PostgreSQL pg_trgm-compatible trigram similarity for Elixir.
 
   This module uses a Rust NIF for performance, with a pure Elixir
-  fallback when the NIF is unavailable.
+  fallback when the NIF is unavailable. The rest of the NIF's functions,
+  which have no fallback, are in `Trigram.Native`.
   """
 
   alias Trigram.Elixir, as: ElixirImpl
@@ -12,7 +13,9 @@ defmodule Trigram do
   @doc """
   Calculate trigram similarity between two strings.
 
-  Returns a float between 0.0 and 1.0, where 1.0 means exact match.
+  Returns a float between 0.0 and 1.0, where 1.0 means exact match. Raises
+  `ErlangError` with `:input_too_long` if either string is longer than
+  `Trigram.Native.set_max_input_len/1` allows (64 MiB by default).
   """
   @spec similarity(String.t(), String.t()) :: float()
   def similarity(a, b) do
@@ -21,6 +24,8 @@ defmodule Trigram do
 
   @doc """
   Calculate trigram similarity for multiple pairs.
+
+  Raises like `similarity/2` if any string exceeds the maximum input length.
   """
   @spec similarity_batch([{String.t(), String.t()}]) :: [float()]
   def similarity_batch(pairs) do
@@ -30,11 +35,36 @@ defmodule Trigram do
     )
   end
 
+  @doc """
+  Like `similarity_batch/1`, but scores at most `chunk_size` pairs per NIF
+  call, handing the scheduler back between chunks so a huge batch never
+  occupies it for long. Results are identical to `similarity_batch/1`.
+  """
+  @spec similarity_batch_chunked([{String.t(), String.t()}], pos_integer()) :: [float()]
+  def similarity_batch_chunked(pairs, chunk_size \\ 10_000) do
+    with_native(
+      fn -> pairs |> Native.similarity_batch_start() |> drain_batch(chunk_size, []) end,
+      fn -> ElixirImpl.similarity_batch(pairs) end
+    )
+  end
+
+  # The job is private to this call, so chunks arrive in offset order
+  defp drain_batch(job, chunk_size, acc) do
+    case Native.similarity_batch_step(job, chunk_size) do
+      {:cont, _offset, scores} -> drain_batch(job, chunk_size, [scores | acc])
+      {:done, _offset, scores} -> [scores | acc] |> Enum.reverse() |> Enum.concat()
+    end
+  end
+
   @doc """
   Find the best match for a needle in a list of haystacks.
+
+  Returns `{:error, :internal}` if the NIF hits an unexpected internal
+  failure, and raises like `similarity/2` if any string exceeds the maximum
+  input length.
   """
   @spec best_match(String.t(), [String.t()]) ::
-          {:ok, {non_neg_integer(), float()}} | {:error, :empty_list}
+          {:ok, {non_neg_integer(), float()}} | {:error, :empty_list | :internal}
   def best_match(needle, haystacks) do
     with_native(fn -> Native.best_match(needle, haystacks) end, fn ->
       ElixirImpl.best_match(needle, haystacks)
@@ -43,6 +73,8 @@ defmodule Trigram do
 
   @doc """
   Score all haystacks against a needle and return results above threshold.
+
+  Raises like `similarity/2` if any string exceeds the maximum input length.
   """
   @spec score_all(String.t(), [String.t()], float()) :: [{non_neg_integer(), float()}]
   def score_all(needle, haystacks, min_threshold) do
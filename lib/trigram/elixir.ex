@@ -8,7 +8,8 @@ defmodule Trigram.Elixir do
   - Split into words using alphanumeric-only boundaries
   - Pad each word with two leading spaces and one trailing space
   - Generate trigrams using byte windows, compacting multi-byte sequences
-    with legacy CRC32 and taking the lower 3 bytes (little-endian order)
+    with legacy CRC32 (all 4 bytes, little-endian order; pg_trgm keeps only
+    the lower 3, which can make unrelated multi-byte trigrams collide)
   - Compute similarity using Jaccard: count / (len1 + len2 - count)
   """
 
@@ -328,7 +329,8 @@ defmodule Trigram.Elixir do
   @doc """
   Generate trigrams for a string.
 
-  Returns a list of unique 3-character sequences.
+  Returns a list of unique 3-character sequences. Sequences containing
+  multi-byte characters are returned as their 4-byte CRC32 key.
 
   ## Examples
 
@@ -369,7 +371,7 @@ defmodule Trigram.Elixir do
       bytes
     else
       crc = legacy_crc32(bytes)
-      <<crc::little-32>> |> binary_part(0, 3)
+      <<crc::little-32>>
     end
   end
 
@@ -1,13 +1,30 @@
 defmodule Trigram.Native do
-  @moduledoc false
+  @moduledoc """
+  Direct bindings to the Rust NIF, covering everything beyond the core
+  functions `Trigram` wraps.
+
+  Unlike `Trigram`, these have no pure Elixir fallback: when the NIF is not
+  loaded every call raises `ErlangError` with `:nif_not_loaded`. Functions
+  taking text raise `ErlangError` with `:input_too_long` for input longer
+  than `set_max_input_len/1` allows. An unexpected internal failure raises
+  `ErlangError` with `:internal`, or is returned as `{:error, :internal}` by
+  functions that already return tagged tuples.
+
+  Set resources come from `prepare/1` and `stream_finalize/1`, index
+  resources from `index_build/1` and `index_deserialize/1`.
+  """
 
   version = Mix.Project.config()[:version]
 
+  # Precompiled NIFs are built without features, so parity always builds locally
+  pg_parity = System.get_env("TRIGRAM_PG_PARITY") in ["1", "true"]
+
   use RustlerPrecompiled,
     otp_app: :trigram,
     crate: "trigram_nif",
     base_url: "https://github.com/EnaiaInc/trigram/releases/download/v#{version}",
-    force_build: System.get_env("TRIGRAM_BUILD") in ["1", "true"],
+    force_build: pg_parity or System.get_env("TRIGRAM_BUILD") in ["1", "true"],
+    features: if(pg_parity, do: ["pg_parity"], else: []),
     version: version,
     nif_versions: ["2.17", "2.16", "2.15"],
     targets: [
@@ -17,16 +34,832 @@ defmodule Trigram.Native do
       "x86_64-unknown-linux-gnu"
     ]
 
+  @doc """
+  Trigram similarity between two strings, from 0.0 to 1.0. No fallback;
+  see `Trigram.similarity/2` for the version that has one.
+  """
   @spec similarity(String.t(), String.t()) :: float()
   def similarity(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc """
+  Best `similarity/2` between `input` and any of `needles`, with `input`
+  trigrammed once. Returns 0.0 for an empty `needles` list.
+  """
+  @spec similarity_any(String.t(), [String.t()]) :: float()
+  def similarity_any(_input, _needles), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Whether `similarity(s1, s2) >= threshold`, stopping as soon as the answer
+  is certain.
+  """
+  @spec is_similar(String.t(), String.t(), float()) :: boolean()
+  def is_similar(_s1, _s2, _threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity/2` over raw binaries. Returns `{:ok, score}`, or
+  `{:error, :invalid_utf8}` if either input is not valid UTF-8. Inputs are
+  validated in place, so large documents are never copied.
+  """
+  @spec similarity_bin(binary(), binary()) :: {:ok, float()} | {:error, :invalid_utf8 | :internal}
+  def similarity_bin(_b1, _b2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity/2`, but returns `{:error, :no_trigrams}` instead of 0.0 when
+  either side has no word content (empty, whitespace or punctuation only).
+  """
+  @spec similarity_checked(String.t(), String.t()) ::
+          {:ok, float()} | {:error, :no_trigrams | :internal}
+  def similarity_checked(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `similarity_checked/2`, but the error names the empty side:
+  `{:error, {:no_trigrams, :left | :right | :both}}`.
+  """
+  @spec similarity_strict(String.t(), String.t()) ::
+          {:ok, float()} | {:error, {:no_trigrams, :left | :right | :both} | :internal}
+  def similarity_strict(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Sets the input size at which batch functions switch from sequential to
+  parallel scoring. Defaults to 250; `0` always runs in parallel.
+  """
+  @spec set_parallel_threshold(non_neg_integer()) :: :ok
+  def set_parallel_threshold(_threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Sets the fewest items each parallel task of `score_all/3` and
+  `similarity_batch/1` handles. Defaults to 1. Results are unaffected.
+  """
+  @spec set_min_parallel_chunk(non_neg_integer()) :: :ok
+  def set_min_parallel_chunk(_min_len), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Lets `score_all/3` and `best_match/2` skip haystacks too short to reach the
+  threshold or the best score so far. Off by default. Results are unchanged
+  either way.
+  """
+  @spec set_length_prefilter(boolean()) :: :ok
+  def set_length_prefilter(_enabled), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Lets `similarity/2` and `similarity_batch/1` return 0.0 without
+  trigramming when two strings share no Unicode block. Off by default, and
+  ignored in `pg_parity` builds.
+  """
+  @spec set_script_prefilter(boolean()) :: :ok
+  def set_script_prefilter(_enabled), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Sets the longest string, in bytes, that any function taking text accepts.
+  Longer input raises `ErlangError` with `:input_too_long` before any work
+  starts. Defaults to 64 MiB.
+  """
+  @spec set_max_input_len(non_neg_integer()) :: :ok
+  def set_max_input_len(_max_len), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Runs parallel work on a dedicated pool of `num_threads` threads (`0` picks
+  a default) instead of the global one. The pool can only be configured
+  once; later calls return `{:error, :already_configured}`.
+  """
+  @spec configure_pool(non_neg_integer()) ::
+          :ok | {:error, :already_configured | :pool_build_failed}
+  def configure_pool(_num_threads), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Caches trigram sets for up to `capacity` distinct strings. `0` disables
+  and empties the cache.
+  """
+  @spec configure_cache(non_neg_integer()) :: :ok
+  def configure_cache(_capacity), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `{hits, misses, size, capacity}` of the trigram set cache. The counters
+  reset whenever the cache is reconfigured.
+  """
+  @spec cache_stats() ::
+          {non_neg_integer(), non_neg_integer(), non_neg_integer(), non_neg_integer()}
+  def cache_stats(), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  pg_trgm's `word_similarity`: the greatest similarity between the needle's
+  trigram set and any continuous extent of the haystack's trigrams.
+  """
+  @spec word_similarity(String.t(), String.t()) :: float()
+  def word_similarity(_needle, _haystack), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  pg_trgm's `strict_word_similarity`: like `word_similarity/2`, but extents
+  must start and end on word boundaries of the haystack.
+  """
+  @spec strict_word_similarity(String.t(), String.t()) :: float()
+  def strict_word_similarity(_needle, _haystack), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Whether any single word of `haystack` scores at least `threshold` against
+  `needle`. Cheaper than `word_similarity/2`, which also tries extents
+  spanning several words.
+  """
+  @spec fuzzy_contains(String.t(), String.t(), float()) :: boolean()
+  def fuzzy_contains(_needle, _haystack, _threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  The trigrams of `text`, sorted, like Postgres `show_trgm`. Multi-byte
+  trigrams cannot be decoded and show as the hex of their key.
+  """
+  @spec show_trgm(String.t()) :: [String.t()]
+  def show_trgm(_text), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  How many of `strings` contain each trigram, most common first (ties by
+  label). Trigrams are labelled as in `show_trgm/1`.
+  """
+  @spec trigram_document_frequencies([String.t()]) :: [{String.t(), non_neg_integer()}]
+  def trigram_document_frequencies(_strings), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  The raw trigram keys of `text`, sorted, as little-endian binaries. In
+  `pg_parity` builds these are the bytes Postgres stores per trigram.
+  """
+  @spec trigram_keys(String.t()) :: [binary()]
+  def trigram_keys(_text), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Whether the loaded NIF was built with the `pg_parity` feature.
+  """
+  @spec pg_parity() :: boolean()
+  def pg_parity(), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  The hasher behind trigram sets: `"ahash"` or `"fxhash"`.
+  """
+  @spec hasher_name() :: String.t()
+  def hasher_name(), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  The form trigrams are built from: lowercased, with the combining dot above
+  (U+0307) removed.
+  """
+  @spec normalize(String.t()) :: String.t()
+  def normalize(_text), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `normalize/1` over a list, in input order.
+  """
+  @spec normalize_batch([String.t()]) :: [String.t()]
+  def normalize_batch(_strings), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Number of distinct trigrams in `text`. `0` means it can never match.
+  """
+  @spec trigram_count(String.t()) :: non_neg_integer()
+  def trigram_count(_text), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `{normalize(text), trigram_count(text)}`, lowercasing `text` only once.
+  """
+  @spec normalize_and_count(String.t()) :: {String.t(), non_neg_integer()}
+  def normalize_and_count(_text), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Number of distinct trigrams in either string.
+  """
+  @spec trigram_union_count(String.t(), String.t()) :: non_neg_integer()
+  def trigram_union_count(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Number of trigrams the two strings share.
+  """
+  @spec trigram_intersection_count(String.t(), String.t()) :: non_neg_integer()
+  def trigram_intersection_count(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Number of trigrams in exactly one of the two strings.
+  """
+  @spec trigram_difference_count(String.t(), String.t()) :: non_neg_integer()
+  def trigram_difference_count(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `{union, intersection, difference}` trigram counts, trigramming each
+  string once.
+  """
+  @spec trigram_set_counts(String.t(), String.t()) ::
+          {non_neg_integer(), non_neg_integer(), non_neg_integer()}
+  def trigram_set_counts(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Trigrams `text` once into a set resource for `similarity_precomputed/2`,
+  `similarity_sets/2`, `best_match_precomputed/2` and `best_match_sets/2`.
+  """
+  @spec prepare(String.t()) :: reference()
+  def prepare(_text), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity/2` between a `prepare/1` set and a string.
+  """
+  @spec similarity_precomputed(reference(), String.t()) :: float()
+  def similarity_precomputed(_set, _other), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity over n-grams of size `n` (2, 3 or 4). `n = 3` is
+  `similarity/2`.
+  """
+  @spec similarity_n(String.t(), String.t(), 2..4) :: float()
+  def similarity_n(_a, _b, _n), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `{shared_count, union_count, similarity}`, showing how many trigrams a
+  score is based on.
+  """
+  @spec similarity_detailed(String.t(), String.t()) ::
+          {non_neg_integer(), non_neg_integer(), float()}
+  def similarity_detailed(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity_detailed/2` for every pair, in order.
+  """
+  @spec similarity_detailed_batch([{String.t(), String.t()}]) ::
+          [{non_neg_integer(), non_neg_integer(), float()}]
+  def similarity_detailed_batch(_pairs), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Cosine similarity over trigram frequency vectors, so repeated trigrams
+  count.
+  """
+  @spec cosine_similarity(String.t(), String.t()) :: float()
+  def cosine_similarity(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Overlap coefficient, `|A ∩ B| / min(|A|, |B|)`. A short needle fully
+  contained in a long haystack scores 1.0.
+  """
+  @spec overlap_similarity(String.t(), String.t()) :: float()
+  def overlap_similarity(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Sørensen–Dice coefficient, `2|A ∩ B| / (|A| + |B|)`. Ranks like
+  `similarity/2` but scores higher, so thresholds don't carry over.
+  """
+  @spec dice_similarity(String.t(), String.t()) :: float()
+  def dice_similarity(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Tversky index with weights `alpha` and `beta`. `1, 1` is `similarity/2`
+  and `0.5, 0.5` is `dice_similarity/2`. Raises `ArgumentError` for a
+  negative or NaN weight.
+  """
+  @spec tversky_similarity(String.t(), String.t(), float(), float()) :: float()
+  def tversky_similarity(_s1, _s2, _alpha, _beta), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity/2`, except that strings with fewer word characters than
+  `set_hybrid_min_chars/1` (default 4) are compared with Jaro-Winkler.
+  """
+  @spec hybrid_similarity(String.t(), String.t()) :: float()
+  def hybrid_similarity(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Sets the word-character count below which `hybrid_similarity/2` uses
+  Jaro-Winkler. `0` disables the fallback; above 256 raises
+  `ArgumentError`.
+  """
+  @spec set_hybrid_min_chars(non_neg_integer()) :: :ok
+  def set_hybrid_min_chars(_min_chars), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Levenshtein distance in chars between the normalized strings. Raises
+  `ErlangError` with `:input_too_long` past 10,000 chars.
+  """
+  @spec levenshtein(String.t(), String.t()) :: non_neg_integer()
+  def levenshtein(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `alpha * similarity + (1 - alpha) * edit_similarity`, for breaking ties
+  between candidates with the same trigram overlap. Raises `ArgumentError`
+  unless `alpha` is within `0.0..1.0`, and limits input like
+  `levenshtein/2`.
+  """
+  @spec combined_score(String.t(), String.t(), float()) :: float()
+  def combined_score(_s1, _s2, _alpha), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity that favours matches at the start of the string, so `"app"`
+  shares its opening trigrams with `"apple"` but not with `"my apple"`.
+  """
+  @spec prefix_similarity(String.t(), String.t()) :: float()
+  def prefix_similarity(_needle, _haystack), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity ignoring words shorter than `min_len` chars. A side left with
+  no words scores 0.0.
+  """
+  @spec similarity_minword(String.t(), String.t(), non_neg_integer()) :: float()
+  def similarity_minword(_s1, _s2, _min_len), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity with runs of single-char words joined first, so `"U S A"` and
+  `"U.S.A."` both trigram as `"usa"`.
+  """
+  @spec similarity_joinshort(String.t(), String.t()) :: float()
+  def similarity_joinshort(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity with each set cut off at its first `max_trigrams` trigrams, so
+  a pathological input costs bounded memory. Inputs under the cap score
+  exactly as `similarity/2`.
+  """
+  @spec similarity_capped(String.t(), String.t(), non_neg_integer()) :: float()
+  def similarity_capped(_s1, _s2, _max_trigrams), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity with `leading` and `trailing` spaces (0 to 2 each) padding
+  every word instead of pg_trgm's 2 and 1. Raises `ArgumentError` for more
+  than 2.
+  """
+  @spec similarity_padded(String.t(), String.t(), 0..2, 0..2) :: float()
+  def similarity_padded(_s1, _s2, _leading, _trailing), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Whitespace-sensitive similarity: `"a  b"` and `"a b"`, or a tab and a
+  space, no longer match perfectly.
+  """
+  @spec similarity_ws(String.t(), String.t()) :: float()
+  def similarity_ws(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity with every decimal digit replaced by `0` first, so codes that
+  differ only in their numbers match fully.
+  """
+  @spec similarity_digitclass(String.t(), String.t()) :: float()
+  def similarity_digitclass(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Weighted similarity where trigrams at a word's start or end weigh twice
+  as much as interior ones, so edits at the edges of a word cost more.
+  """
+  @spec weighted_similarity(String.t(), String.t()) :: float()
+  def weighted_similarity(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity over trigrams that span word boundaries, so word order counts:
+  `"john smith"` and `"smith john"` no longer score 1.0.
+  """
+  @spec ordered_similarity(String.t(), String.t()) :: float()
+  def ordered_similarity(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity under the padding `mode`: `:per_word` is `similarity/2` and
+  `:whole_string` is `ordered_similarity/2`.
+  """
+  @spec similarity_boundary(String.t(), String.t(), :per_word | :whole_string) :: float()
+  def similarity_boundary(_s1, _s2, _mode), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  MinHash signature of `text`'s trigram set, `num_hashes` values long.
+  Raises `ArgumentError` for more than 1024 hashes.
+  """
+  @spec minhash(String.t(), non_neg_integer()) :: [non_neg_integer()]
+  def minhash(_text, _num_hashes), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Estimated similarity of two `minhash/2` signatures of the same length.
+  """
+  @spec minhash_similarity([non_neg_integer()], [non_neg_integer()]) :: float()
+  def minhash_similarity(_sig1, _sig2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Per-band bucket hashes of a `minhash/2` signature. Two strings sharing any
+  band hash are candidate near-duplicates. `num_bands` must divide the
+  signature length.
+  """
+  @spec lsh_bands([non_neg_integer()], pos_integer()) :: [non_neg_integer()]
+  def lsh_bands(_signature, _num_bands), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  64-bit SimHash fingerprint of `text`'s trigrams. Similar documents get
+  fingerprints a small `simhash_distance/2` apart.
+  """
+  @spec simhash(String.t()) :: non_neg_integer()
+  def simhash(_text), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Number of differing bits between two `simhash/1` fingerprints.
+  """
+  @spec simhash_distance(non_neg_integer(), non_neg_integer()) :: 0..64
+  def simhash_distance(_h1, _h2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Case-sensitive similarity, so `"ABC"` and `"abc"` share no trigrams.
+  """
+  @spec similarity_cs(String.t(), String.t()) :: float()
+  def similarity_cs(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `similarity/2`, but the combining dot above (U+0307) is kept.
+  """
+  @spec similarity_raw(String.t(), String.t()) :: float()
+  def similarity_raw(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `similarity/2`, but both inputs are NFC-normalized first.
+  """
+  @spec similarity_normalized(String.t(), String.t()) :: float()
+  def similarity_normalized(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Accent-insensitive similarity: nonspacing marks are removed after NFD
+  decomposition.
+  """
+  @spec similarity_unaccent(String.t(), String.t()) :: float()
+  def similarity_unaccent(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity with words split by `pattern` instead of alphanumeric runs.
+  `:alnum` is `similarity/2`.
+  """
+  @spec similarity_with_pattern(String.t(), String.t(), :alnum | :word | :whitespace) :: float()
+  def similarity_with_pattern(_s1, _s2, _pattern), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity over grapheme clusters instead of chars, so a multi-codepoint
+  emoji is one unit of a trigram.
+  """
+  @spec similarity_graphemes(String.t(), String.t()) :: float()
+  def similarity_graphemes(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity/2` for every pair, in order. No fallback; see
+  `Trigram.similarity_batch/1` for the version that has one.
+  """
   @spec similarity_batch([{String.t(), String.t()}]) :: [float()]
   def similarity_batch(_pairs), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc """
+  `similarity(reference, candidate)` for every candidate, in order, with
+  `reference` trigrammed once.
+  """
+  @spec similarity_one_to_many(String.t(), [String.t()]) :: [float()]
+  def similarity_one_to_many(_reference, _candidates), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity_batch/1` as one binary of little-endian `f32`s, in input
+  order. Read it with `for <<score::float-32-little <- bin>>, do: score`.
+  """
+  @spec similarity_batch_binary([{String.t(), String.t()}]) :: binary()
+  def similarity_batch_binary(_pairs), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Starts a job scoring `pairs` in chunks with `similarity_batch_step/2`.
+  `Trigram.similarity_batch_chunked/2` drives one for you.
+  """
+  @spec similarity_batch_start([{String.t(), String.t()}]) :: reference()
+  def similarity_batch_start(_pairs), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Scores the next chunk of at most `max_pairs` pairs of a
+  `similarity_batch_start/1` job. Returns `{:cont, offset, scores}`, or
+  `{:done, offset, scores}` for the last chunk.
+  """
+  @spec similarity_batch_step(reference(), pos_integer()) ::
+          {:cont | :done, non_neg_integer(), [float()]}
+  def similarity_batch_step(_job, _max_pairs), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Starts an empty stream for `stream_feed/2`.
+  """
+  @spec stream_new() :: reference()
+  def stream_new(), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Adds `chunk` to the stream. A word may continue from the previous chunk.
+  Raises `ErlangError` with `:input_too_long` if a word spanning several
+  chunks grows past `set_max_input_len/1`.
+  """
+  @spec stream_feed(reference(), String.t()) :: :ok
+  def stream_feed(_stream, _chunk), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Trigram set of everything fed so far, as a `prepare/1` set. The stream is
+  emptied and can be fed again.
+  """
+  @spec stream_finalize(reference()) :: reference()
+  def stream_finalize(_stream), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity as an integer percentage from 0 to 100, rounded the same way on
+  every node.
+  """
+  @spec similarity_pct(String.t(), String.t()) :: 0..100
+  def similarity_pct(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity_pct/2` for every pair, in order.
+  """
+  @spec similarity_pct_batch([{String.t(), String.t()}]) :: [0..100]
+  def similarity_pct_batch(_pairs), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity/2` at full double precision.
+  """
+  @spec similarity_f64(String.t(), String.t()) :: float()
+  def similarity_f64(_s1, _s2), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity_f64/2` for every pair, in order.
+  """
+  @spec similarity_f64_batch([{String.t(), String.t()}]) :: [float()]
+  def similarity_f64_batch(_pairs), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Trigram distance, `1.0 - similarity`, like pg_trgm's `<->` operator.
+  """
+  @spec distance(String.t(), String.t()) :: float()
+  def distance(_a, _b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `distance/2` for every pair, in order.
+  """
+  @spec distance_batch([{String.t(), String.t()}]) :: [float()]
+  def distance_batch(_pairs), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  All-pairs similarity matrix, as a list of rows.
+  """
+  @spec similarity_matrix([String.t()]) :: [[float()]]
+  def similarity_matrix(_strings), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `similarity_matrix/1` as one binary of `n * n` little-endian `f32`s, row
+  by row, ready for `Nx.from_binary(bin, :f32) |> Nx.reshape({n, n})`.
+  """
+  @spec similarity_matrix_binary([String.t()]) :: binary()
+  def similarity_matrix_binary(_strings), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Groups of near-duplicates: strings linked by a similarity of at least
+  `threshold`, directly or through others. Groups list indices ascending
+  and are ordered by their first index.
+  """
+  @spec cluster([String.t()], float()) :: [[non_neg_integer()]]
+  def cluster(_strings, _threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Every `{i, j, score}` with `similarity(left[i], right[j]) >= threshold`,
+  ordered by `i` then `j`.
+  """
+  @spec fuzzy_join([String.t()], [String.t()], float()) ::
+          [{non_neg_integer(), non_neg_integer(), float()}]
+  def fuzzy_join(_left, _right, _threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  How many pairs `fuzzy_join/3` would return, without building them.
+  """
+  @spec fuzzy_join_count([String.t()], [String.t()], float()) :: non_neg_integer()
+  def fuzzy_join_count(_left, _right, _threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Index and score of the most similar haystack, ties going to the lowest
+  index. No fallback; see `Trigram.best_match/2` for the version that has
+  one.
+  """
   @spec best_match(String.t(), [String.t()]) ::
-          {:ok, {non_neg_integer(), float()}} | {:error, :empty_list}
+          {:ok, {non_neg_integer(), float()}} | {:error, :empty_list | :internal}
   def best_match(_needle, _haystacks), do: :erlang.nif_error(:nif_not_loaded)
 
+  @doc """
+  Like `best_match/2`, but breaks ties at the top score by `tie_break`
+  (`:first`, `:longest` or `:shortest` haystack) before the lowest index.
+  """
+  @spec best_match(String.t(), [String.t()], :first | :longest | :shortest) ::
+          {:ok, {non_neg_integer(), float()}} | {:error, :empty_list | :internal}
+  def best_match(_needle, _haystacks, _tie_break), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `{:ok, {score, indices}}` with the top score and every index reaching it,
+  ascending.
+  """
+  @spec best_match_all_ties(String.t(), [String.t()]) ::
+          {:ok, {float(), [non_neg_integer()]}} | {:error, :empty_list | :internal}
+  def best_match_all_ties(_needle, _haystacks), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `best_match/2`, but also returns the matched haystack.
+  """
+  @spec best_match_str(String.t(), [String.t()]) ::
+          {:ok, {non_neg_integer(), float(), String.t()}} | {:error, :empty_list | :internal}
+  def best_match_str(_needle, _haystacks), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Best haystack for every needle, in needle order. Haystacks are trigrammed
+  once. Raises `ArgumentError` when there are needles but no haystacks.
+  """
+  @spec best_match_many([String.t()], [String.t()]) :: [{non_neg_integer(), float()}]
+  def best_match_many(_needles, _haystacks), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `best_match/2` for a `prepare/1` needle set.
+  """
+  @spec best_match_precomputed(reference(), [String.t()]) ::
+          {:ok, {non_neg_integer(), float()}} | {:error, :empty_list | :internal}
+  def best_match_precomputed(_set, _haystacks), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `best_match/2` that only reports a match scoring at least `min_threshold`,
+  else `{:error, :no_match}`.
+  """
+  @spec best_match_threshold(String.t(), [String.t()], float()) ::
+          {:ok, {non_neg_integer(), float()}} | {:error, :no_match | :empty_list | :internal}
+  def best_match_threshold(_needle, _haystacks, _min_threshold),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Similarity of two `prepare/1` sets.
+  """
+  @spec similarity_sets(reference(), reference()) :: float()
+  def similarity_sets(_set_a, _set_b), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `best_match/2` over `prepare/1` sets, ties going to the lowest index.
+  """
+  @spec best_match_sets(reference(), [reference()]) ::
+          {:ok, {non_neg_integer(), float()}} | {:error, :empty_list | :internal}
+  def best_match_sets(_needle_set, _haystack_sets), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `{index, score}` for every haystack scoring at least `min_threshold`,
+  best first. No fallback; see `Trigram.score_all/3` for the version that
+  has one.
+  """
   @spec score_all(String.t(), [String.t()], float()) :: [{non_neg_integer(), float()}]
   def score_all(_needle, _haystacks, _min_threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Scores every haystack with each trigram weighted by its inverse document
+  frequency across the haystacks, so rare shared trigrams count for more.
+  """
+  @spec idf_similarity(String.t(), [String.t()]) :: [{non_neg_integer(), float()}]
+  def idf_similarity(_needle, _haystacks), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `score_all/3`, but keeps only the best `limit` results (`0` means
+  unlimited).
+  """
+  @spec score_all(String.t(), [String.t()], float(), non_neg_integer()) :: [
+          {non_neg_integer(), float()}
+        ]
+  def score_all(_needle, _haystacks, _min_threshold, _limit),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `score_all/4`, with results ordered by `order`: `:by_score`,
+  `:by_index`, or `:none` for whatever order is cheapest.
+  """
+  @spec score_all(
+          String.t(),
+          [String.t()],
+          float(),
+          non_neg_integer(),
+          :by_score | :by_index | :none
+        ) :: [{non_neg_integer(), float()}]
+  def score_all(_needle, _haystacks, _min_threshold, _limit, _order),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `score_all/3`, with each haystack included in its result tuple.
+  """
+  @spec score_all_str(String.t(), [String.t()], float()) :: [
+          {non_neg_integer(), float(), String.t()}
+        ]
+  def score_all_str(_needle, _haystacks, _min_threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `score_all/3`, also sending `{:trigram_progress, done, total}` to
+  `pid` as large inputs are scored.
+  """
+  @spec score_all_progress(String.t(), [String.t()], float(), pid()) ::
+          [{non_neg_integer(), float()}]
+  def score_all_progress(_needle, _haystacks, _min_threshold, _pid),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `score_all/3` keeping only scores within `min_threshold..max_threshold`.
+  """
+  @spec score_all_range(String.t(), [String.t()], float(), float()) ::
+          [{non_neg_integer(), float()}]
+  def score_all_range(_needle, _haystacks, _min_threshold, _max_threshold),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `score_all/3`, but each distinct haystack is scored once. Faster on
+  repetitive lists.
+  """
+  @spec score_all_dedup(String.t(), [String.t()], float()) :: [{non_neg_integer(), float()}]
+  def score_all_dedup(_needle, _haystacks, _min_threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `score_all/3` over haystacks packed into one binary, haystack `i` starting
+  at `offsets[i]`. Raises `ArgumentError` for bad offsets or invalid UTF-8.
+  """
+  @spec score_all_packed(String.t(), binary(), [non_neg_integer()], float()) ::
+          [{non_neg_integer(), float()}]
+  def score_all_packed(_needle, _blob, _offsets, _min_threshold),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `score_all/3` over the lines of the file at `path`, read in bounded
+  chunks. Returns `{:ok, results, skipped}` with 0-based line numbers, where
+  `skipped` counts lines that were invalid UTF-8 or too long, or
+  `{:error, posix}` if the file can't be read.
+  """
+  @spec score_file(String.t(), binary(), float()) ::
+          {:ok, [{non_neg_integer(), float()}], non_neg_integer()} | {:error, atom()}
+  def score_file(_needle, _path, _min_threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Like `score_file/3`, but keeps only the best `limit` lines (`0` means
+  unlimited).
+  """
+  @spec score_file(String.t(), binary(), float(), non_neg_integer()) ::
+          {:ok, [{non_neg_integer(), float()}], non_neg_integer()} | {:error, atom()}
+  def score_file(_needle, _path, _min_threshold, _limit),
+    do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Top `k` haystacks by score, best first, ties going to the lowest index.
+  """
+  @spec best_matches(String.t(), [String.t()], non_neg_integer()) :: [
+          {non_neg_integer(), float()}
+        ]
+  def best_matches(_needle, _haystacks, _k), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Top `k` haystacks by their best score against any of `needles`. Each
+  haystack appears at most once.
+  """
+  @spec best_matches_multi([String.t()], [String.t()], non_neg_integer()) :: [
+          {non_neg_integer(), float()}
+        ]
+  def best_matches_multi(_needles, _haystacks, _k), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Counts of haystack scores in `num_buckets` equal-width buckets over
+  0.0 to 1.0. `num_buckets` must be between 1 and 65,536.
+  """
+  @spec score_histogram(String.t(), [String.t()], pos_integer()) :: [non_neg_integer()]
+  def score_histogram(_needle, _haystacks, _num_buckets), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Builds an inverted trigram index over `strings` for repeated queries.
+  """
+  @spec index_build([String.t()]) :: reference()
+  def index_build(_strings), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `score_all/3` over an index, visiting only documents that share a trigram
+  with `needle`.
+  """
+  @spec index_query(reference(), String.t(), float()) :: [{non_neg_integer(), float()}]
+  def index_query(_index, _needle, _min_threshold), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Adds `string` to the index under `id`, replacing any document there, or
+  under the next free id when `id` is `nil`. Returns the id. Raises
+  `ArgumentError` for an id past the next free one.
+  """
+  @spec index_add(reference(), non_neg_integer() | nil, String.t()) :: non_neg_integer()
+  def index_add(_index, _id, _string), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  First document scoring at least `good_enough`, else the best candidate,
+  or `nil` when no document shares a trigram with `needle`.
+  """
+  @spec index_query_first(reference(), String.t(), float()) ::
+          {non_neg_integer(), float()} | nil
+  def index_query_first(_index, _needle, _good_enough), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Removes the document at `id`. Returns whether there was one.
+  """
+  @spec index_remove(reference(), non_neg_integer()) :: boolean()
+  def index_remove(_index, _id), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  `{num_documents, num_unique_trigrams, approx_bytes}` of an index.
+  """
+  @spec index_stats(reference()) :: {non_neg_integer(), non_neg_integer(), non_neg_integer()}
+  def index_stats(_index), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Serializes an index to a binary for `index_deserialize/1`.
+  """
+  @spec index_serialize(reference()) :: binary()
+  def index_serialize(_index), do: :erlang.nif_error(:nif_not_loaded)
+
+  @doc """
+  Loads an index from `index_serialize/1` output. Blobs from another version
+  or parity mode return `{:error, :unsupported_index_version}`.
+  """
+  @spec index_deserialize(binary()) ::
+          {:ok, reference()} | {:error, :invalid_index | :unsupported_index_version | :internal}
+  def index_deserialize(_blob), do: :erlang.nif_error(:nif_not_loaded)
 end
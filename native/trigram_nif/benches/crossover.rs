@@ -0,0 +1,112 @@
+//! Sequential vs parallel `score_all` across haystack counts, to check where
+//! the default parallel threshold (250) should sit on a given machine, and
+//! the merge walk vs galloping search for intersecting `sorted_keys` slices of
+//! growing size ratio, to check `SEARCH_RATIO` (add `--features simd` for the
+//! SSE2 merge's ratio), and `score_all` over millions of tiny haystacks at a
+//! few `set_min_parallel_chunk` sizes.
+//!
+//!     cargo bench --bench crossover
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use trigram_nif::core::{
+    merge_count, score_all, search_shared_count, set_min_parallel_chunk, set_parallel_threshold,
+    TrigramKey,
+};
+
+const SIZES: [usize; 5] = [50, 100, 250, 1_000, 10_000];
+
+// Keys in the smaller slice, and how many times longer the larger one is
+const SMALL_KEYS: usize = 16;
+const RATIOS: [usize; 7] = [1, 2, 4, 8, 16, 32, 64];
+
+fn haystacks(count: usize) -> Vec<String> {
+    let words = [
+        "hello",
+        "world",
+        "trigram",
+        "similarity",
+        "postgres",
+        "index",
+    ];
+    (0..count)
+        .map(|i| {
+            format!(
+                "{} {} {}",
+                words[i % words.len()],
+                words[(i / 7) % words.len()],
+                i
+            )
+        })
+        .collect()
+}
+
+fn bench_crossover(c: &mut Criterion) {
+    let mut group = c.benchmark_group("score_all");
+    for size in SIZES {
+        let haystacks = haystacks(size);
+        for (path, threshold) in [("sequential", usize::MAX), ("parallel", 0)] {
+            set_parallel_threshold(threshold);
+            group.bench_with_input(BenchmarkId::new(path, size), &haystacks, |b, haystacks| {
+                b.iter(|| score_all(black_box("hello trigram"), haystacks, 0.1))
+            });
+        }
+    }
+    group.finish();
+    set_parallel_threshold(250);
+}
+
+// Sorted, distinct keys spread like real ones; every other small key is shared
+fn key_slices(ratio: usize) -> (Vec<TrigramKey>, Vec<TrigramKey>) {
+    let large_len = SMALL_KEYS * ratio;
+    let large: Vec<TrigramKey> = (0..large_len as u32).map(|i| i * 2_654_435 + 7).collect();
+    let step = large_len / SMALL_KEYS;
+    let small = (0..SMALL_KEYS)
+        .map(|i| large[i * step] + (i % 2) as u32)
+        .collect();
+    (small, large)
+}
+
+fn bench_intersection(c: &mut Criterion) {
+    let mut group = c.benchmark_group("intersection");
+    for ratio in RATIOS {
+        let slices = key_slices(ratio);
+        group.bench_with_input(
+            BenchmarkId::new("merge", ratio),
+            &slices,
+            |b, (small, large)| b.iter(|| merge_count(black_box(small), black_box(large))),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("search", ratio),
+            &slices,
+            |b, (small, large)| b.iter(|| search_shared_count(black_box(small), black_box(large))),
+        );
+    }
+    group.finish();
+}
+
+fn bench_min_parallel_chunk(c: &mut Criterion) {
+    let haystacks: Vec<String> = (0..2_000_000).map(|i| format!("k{}", i % 997)).collect();
+    set_parallel_threshold(0);
+
+    let mut group = c.benchmark_group("min_parallel_chunk");
+    group.sample_size(10);
+    for min_len in [1, 64, 4096] {
+        set_min_parallel_chunk(min_len);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(min_len),
+            &haystacks,
+            |b, haystacks| b.iter(|| score_all(black_box("k42"), haystacks, 0.5)),
+        );
+    }
+    group.finish();
+    set_min_parallel_chunk(1);
+    set_parallel_threshold(250);
+}
+
+criterion_group!(
+    benches,
+    bench_crossover,
+    bench_intersection,
+    bench_min_parallel_chunk
+);
+criterion_main!(benches);
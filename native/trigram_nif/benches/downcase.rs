@@ -0,0 +1,39 @@
+//! `downcase_into`'s ASCII fast path against the general char-by-char path,
+//! on all-ASCII SKU-like strings.
+//!
+//!     cargo bench --bench downcase
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use trigram_nif::core::{downcase_chars_into, downcase_into};
+
+fn skus() -> Vec<String> {
+    (0..10_000)
+        .map(|i| format!("SKU-{:06}-WIDGET-{}", i, i % 97))
+        .collect()
+}
+
+fn bench_downcase(c: &mut Criterion) {
+    let skus = skus();
+    let mut out = String::new();
+
+    let mut group = c.benchmark_group("downcase");
+    for (path, downcase) in [
+        (
+            "general",
+            downcase_chars_into as fn(&str, bool, &mut String),
+        ),
+        ("ascii", downcase_into),
+    ] {
+        group.bench_function(path, |b| {
+            b.iter(|| {
+                for sku in &skus {
+                    downcase(black_box(sku), true, &mut out);
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_downcase);
+criterion_main!(benches);
@@ -0,0 +1,172 @@
+//! `best_match` with per-thread scratch buffers against trigramming every
+//! haystack into a fresh set, and trigram sets sized by `ngram_capacity`
+//! against the old bytes / 3 hint, counting heap allocations as well as time.
+//!
+//!     cargo bench --bench scratch
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use trigram_nif::core::{
+    best_match, ngram_capacity, set_parallel_threshold, similarity_from_sets, trigrams,
+    TrigramHashSet, TrigramKey,
+};
+
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const HAYSTACKS: usize = 10_000;
+
+fn haystacks() -> Vec<String> {
+    let words = [
+        "hello",
+        "world",
+        "trigram",
+        "similarity",
+        "Ångström",
+        "index",
+    ];
+    (0..HAYSTACKS)
+        .map(|i| {
+            format!(
+                "{} {} {}",
+                words[i % words.len()],
+                words[(i / 7) % words.len()],
+                i
+            )
+        })
+        .collect()
+}
+
+// The search loop as it was before scratch buffers
+fn best_match_fresh(needle: &str, haystacks: &[String]) -> Option<(usize, f32)> {
+    let needle_set = trigrams(needle);
+    haystacks
+        .iter()
+        .enumerate()
+        .map(|(idx, haystack)| (idx, similarity_from_sets(&needle_set, &trigrams(haystack))))
+        .fold(None, |best, (idx, score)| match best {
+            Some((_, best_score)) if best_score >= score => best,
+            _ => Some((idx, score)),
+        })
+}
+
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bench_scratch(c: &mut Criterion) {
+    let haystacks = haystacks();
+    // Sequential, so every haystack goes through one thread's buffers
+    set_parallel_threshold(usize::MAX);
+
+    // Warm the scratch buffers before counting
+    let scratch_best = best_match("hello world", &haystacks);
+    assert_eq!(scratch_best, best_match_fresh("hello world", &haystacks));
+    let scratch = allocations(|| {
+        black_box(best_match("hello world", &haystacks));
+    });
+    let fresh = allocations(|| {
+        black_box(best_match_fresh("hello world", &haystacks));
+    });
+    println!(
+        "{} haystacks: {} allocations with scratch buffers, {} with fresh sets",
+        HAYSTACKS, scratch, fresh
+    );
+
+    let mut group = c.benchmark_group("best_match");
+    group.bench_function("scratch", |b| {
+        b.iter(|| best_match(black_box("hello world"), &haystacks))
+    });
+    group.bench_function("fresh", |b| {
+        b.iter(|| best_match_fresh(black_box("hello world"), &haystacks))
+    });
+    group.finish();
+}
+
+// The set capacity hint as it was before sizing by codepoints
+fn bytes_capacity(normalized: &str) -> usize {
+    (normalized.len() / 3).max(16)
+}
+
+fn filled(capacity: usize, keys: &[TrigramKey]) -> TrigramHashSet {
+    let mut set = TrigramHashSet::with_capacity_and_hasher(capacity, Default::default());
+    set.extend(keys);
+    set
+}
+
+fn bench_capacity(c: &mut Criterion) {
+    // Already normalized, with nearly every trigram distinct: pseudo-random
+    // lowercase ASCII words, and CJK words of 7 chars
+    let mut state = 1u32;
+    let ascii: String = (0..8_000)
+        .map(|i| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let letter = (b'a' + (state >> 16) as u8 % 26) as char;
+            if i % 8 == 7 {
+                ' '
+            } else {
+                letter
+            }
+        })
+        .collect();
+    let cjk: Vec<char> = (0..3_000u32)
+        .map(|i| char::from_u32(0x4E00 + i).unwrap())
+        .collect();
+    let cjk: String = cjk
+        .chunks(7)
+        .map(|word| word.iter().collect::<String>() + " ")
+        .collect();
+
+    let mut group = c.benchmark_group("set_capacity");
+    for (script, text) in [("ascii", &ascii), ("cjk", &cjk)] {
+        let keys: Vec<TrigramKey> = trigrams(text).into_iter().collect();
+        let by_chars = allocations(|| {
+            black_box(filled(ngram_capacity(text), &keys));
+        });
+        let by_bytes = allocations(|| {
+            black_box(filled(bytes_capacity(text), &keys));
+        });
+        println!(
+            "{} {} trigrams: {} allocations sized by chars, {} by bytes / 3",
+            script,
+            keys.len(),
+            by_chars,
+            by_bytes
+        );
+
+        group.bench_function(format!("{}/chars", script), |b| {
+            b.iter(|| filled(ngram_capacity(black_box(text)), &keys))
+        });
+        group.bench_function(format!("{}/bytes", script), |b| {
+            b.iter(|| filled(bytes_capacity(black_box(text)), &keys))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_scratch, bench_capacity);
+criterion_main!(benches);
@@ -0,0 +1,41 @@
+//! The SSE2 block merge against the scalar merge walk for counting the keys
+//! two `sorted_keys` slices share.
+//!
+//!     cargo bench --features simd --bench simd
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use trigram_nif::core::{merge_count, merge_shared_count, TrigramKey};
+
+// Sorted, distinct keys drawn from `0..range` by xorshift64*
+fn random_keys(seed: u64, len: usize, range: u64) -> Vec<TrigramKey> {
+    let mut x = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut keys: Vec<TrigramKey> = (0..len)
+        .map(|_| {
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            (x.wrapping_mul(0x2545_F491_4F6C_DD1D) % range) as TrigramKey
+        })
+        .collect();
+    keys.sort_unstable();
+    keys.dedup();
+    keys
+}
+
+fn bench_shared_count(c: &mut Criterion) {
+    let a = random_keys(1, 4000, 12_000);
+    let b = random_keys(2, 4000, 12_000);
+    assert_eq!(merge_count(&a, &b), merge_shared_count(&a, &b));
+
+    let mut group = c.benchmark_group("shared_count");
+    group.bench_function("scalar", |bench| {
+        bench.iter(|| merge_shared_count(black_box(&a), black_box(&b)))
+    });
+    group.bench_function("simd", |bench| {
+        bench.iter(|| merge_count(black_box(&a), black_box(&b)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_shared_count);
+criterion_main!(benches);
@@ -0,0 +1,165 @@
+//! Optional LRU cache of trigram sets, keyed by the input string.
+//!
+//! Off by default (capacity 0). Once enabled via `configure_cache/1`,
+//! `trigrams` serves repeated strings from here instead of re-trigramming
+//! them. Sets are computed outside the lock, so Rayon workers only contend
+//! for the lookup and insert.
+
+use lru::LruCache;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::TrigramHashSet;
+
+// Longer inputs bypass the cache, so its memory stays bounded by
+// `capacity * MAX_CACHED_LEN` plus the sets themselves
+const MAX_CACHED_LEN: usize = 1024;
+
+// Mirrors the cache capacity so the disabled path never takes the lock
+static CAPACITY: AtomicUsize = AtomicUsize::new(0);
+static CACHE: Mutex<Option<LruCache<String, TrigramHashSet>>> = Mutex::new(None);
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+// Entry count, kept in sync under the lock so `cache_stats` never takes it
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn enabled() -> bool {
+    CAPACITY.load(Ordering::Relaxed) > 0
+}
+
+fn lock() -> MutexGuard<'static, Option<LruCache<String, TrigramHashSet>>> {
+    CACHE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn configure(capacity: usize) {
+    let mut cache = lock();
+    // `LruCache::new` reserves the whole capacity up front, which a large
+    // capacity can't get; an unbounded one grows as entries arrive and
+    // `get_or_compute` evicts past `CAPACITY` itself
+    *cache = (capacity > 0).then(LruCache::unbounded);
+    CAPACITY.store(capacity, Ordering::Relaxed);
+    HITS.store(0, Ordering::Relaxed);
+    MISSES.store(0, Ordering::Relaxed);
+    SIZE.store(0, Ordering::Relaxed);
+}
+
+/// The cached set for `text`, computing and storing it on a miss.
+pub(crate) fn get_or_compute(
+    text: &str,
+    compute: impl FnOnce(&str) -> TrigramHashSet,
+) -> TrigramHashSet {
+    if text.len() > MAX_CACHED_LEN {
+        return compute(text);
+    }
+
+    // Counters only move under the lock, so a reset in `configure` is never
+    // followed by a stale increment
+    {
+        let mut guard = lock();
+        let Some(cache) = guard.as_mut() else {
+            drop(guard);
+            return compute(text);
+        };
+        if let Some(set) = cache.get(text) {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            return set.clone();
+        }
+        MISSES.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let set = compute(text);
+    if let Some(cache) = lock().as_mut() {
+        cache.put(text.to_string(), set.clone());
+        while cache.len() > CAPACITY.load(Ordering::Relaxed) {
+            cache.pop_lru();
+        }
+        SIZE.store(cache.len(), Ordering::Relaxed);
+    }
+    set
+}
+
+/// Cache trigram sets for up to `capacity` distinct strings; `0` disables
+/// and empties the cache.
+#[rustler::nif]
+fn configure_cache(capacity: usize) -> rustler::Atom {
+    configure(capacity);
+    rustler::types::atom::ok()
+}
+
+/// `{hits, misses, size, capacity}`. Hit and miss counters reset whenever the
+/// cache is reconfigured.
+#[rustler::nif]
+fn cache_stats() -> (u64, u64, usize, usize) {
+    stats()
+}
+
+fn stats() -> (u64, u64, usize, usize) {
+    (
+        HITS.load(Ordering::Relaxed),
+        MISSES.load(Ordering::Relaxed),
+        SIZE.load(Ordering::Relaxed),
+        CAPACITY.load(Ordering::Relaxed),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{similarity_from_sets, trigrams};
+
+    #[test]
+    fn test_cache_preserves_scores_and_serves_hits() {
+        let pairs = [
+            ("word", "two words"),
+            ("hello", "hallo"),
+            ("İstanbul", "istanbul"),
+            ("", ""),
+        ];
+        let score = |(a, b): &(&str, &str)| similarity_from_sets(&trigrams(a), &trigrams(b));
+        let expected: Vec<f32> = pairs.iter().map(score).collect();
+
+        // The cache is process-wide, so this is the only test that enables it
+        configure(2);
+        for _ in 0..3 {
+            assert_eq!(pairs.iter().map(score).collect::<Vec<_>>(), expected);
+        }
+        assert_eq!(trigrams("hello"), trigrams("hello"));
+
+        // Other tests may hit the cache concurrently, so only lower bounds hold
+        let (hits, misses, size, capacity) = stats();
+        assert!(hits >= 1);
+        assert!(misses >= 1);
+        assert!((1..=2).contains(&size));
+        assert_eq!(capacity, 2);
+
+        // Nothing is reserved up front, so a huge capacity is fine
+        configure(1 << 40);
+        for text in ["a", "b", "c"] {
+            trigrams(text);
+        }
+        let (_, _, size, capacity) = stats();
+        assert!(size >= 3);
+        assert_eq!(capacity, 1 << 40);
+
+        // Nothing is evicted at this capacity, so a repeat must skip `compute`
+        let computed = AtomicUsize::new(0);
+        let counted = |text: &str| {
+            computed.fetch_add(1, Ordering::Relaxed);
+            crate::ngrams(text, 3)
+        };
+        let first = get_or_compute("counted probe", counted);
+        assert_eq!(computed.load(Ordering::Relaxed), 1);
+        assert_eq!(get_or_compute("counted probe", counted), first);
+        assert_eq!(computed.load(Ordering::Relaxed), 1);
+        // Over-long inputs bypass the cache and are computed every time
+        let long = "x".repeat(MAX_CACHED_LEN + 1);
+        get_or_compute(&long, counted);
+        get_or_compute(&long, counted);
+        assert_eq!(computed.load(Ordering::Relaxed), 3);
+
+        configure(0);
+        assert_eq!(stats(), (0, 0, 0, 0));
+        assert!(!enabled());
+        assert!(lock().is_none());
+    }
+}
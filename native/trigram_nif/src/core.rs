@@ -0,0 +1,941 @@
+//! The trigram engine: tokenizing, keying, Jaccard scoring and the
+//! sequential/parallel ranking loops. Its public functions take and return
+//! plain Rust types, so the NIFs in the crate root only decode and encode
+//! terms around them, and the Criterion benches in `benches/` call them
+//! directly.
+
+use once_cell::sync::{Lazy, OnceCell};
+use rayon::prelude::*;
+use regex::Regex;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+
+use crate::cache;
+
+// Pre-compiled regex for word boundary detection
+pub(crate) static WORD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(WORD_PATTERN).unwrap());
+
+// Postgres' `iswalnum` (glibc) counts alphabetic chars and decimal digits only,
+// so it keeps Devanagari vowel signs and circled letters but drops `²` and `½`.
+#[cfg(feature = "pg_parity")]
+const WORD_PATTERN: &str = r"[\p{Alphabetic}\p{Nd}]+";
+
+#[cfg(not(feature = "pg_parity"))]
+const WORD_PATTERN: &str = r"[\p{L}\p{N}]+";
+
+// HEURISTIC: Only spin up Rayon threads if the batch is large enough to justify
+// the coordination overhead. 250 items is a safe crossover point.
+pub(crate) const DEFAULT_PARALLEL_THRESHOLD: usize = 250;
+
+// Runtime-tunable crossover, see `set_parallel_threshold/1`
+static PARALLEL_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_PARALLEL_THRESHOLD);
+
+// Fewest items per Rayon task in `score_all` and `similarity_batch`, see
+// `set_min_parallel_chunk/1`. 1 leaves splitting entirely to Rayon.
+pub(crate) static MIN_PARALLEL_CHUNK: AtomicUsize = AtomicUsize::new(1);
+
+// Dedicated Rayon pool, see `configure_pool/1`. Unset means the global pool.
+pub(crate) static POOL: OnceCell<rayon::ThreadPool> = OnceCell::new();
+
+// Opt-in length pre-filter for ranking NIFs, see `set_length_prefilter/1`
+pub(crate) static LENGTH_PREFILTER: AtomicBool = AtomicBool::new(false);
+
+// Supported n-gram sizes for `similarity_n`. Keys pack up to 4 ASCII bytes.
+pub(crate) const MIN_NGRAM: usize = 2;
+pub(crate) const MAX_NGRAM: usize = 4;
+
+/// Packed trigram (or n-gram) key: ASCII bytes in little-endian order, or the
+/// full legacy CRC32 for multi-byte sequences.
+///
+/// pg_trgm keeps only the lower 3 bytes of the CRC, which lets unrelated
+/// multi-byte trigrams collide and count as shared. Keeping all 4 bytes makes
+/// such collisions vanishingly rare. The `pg_parity` feature truncates to 3
+/// bytes like Postgres does.
+pub type TrigramKey = u32;
+
+/// Distinct trigram keys of a string.
+pub type TrigramHashSet = HashSet<TrigramKey, TrigramHasher>;
+
+/// Hasher of every `TrigramHashSet`: FxHash by default, or aHash with fixed keys
+/// under the `ahash` feature. Scores never depend on it, only speed does.
+#[cfg(feature = "ahash")]
+pub type TrigramHasher = std::hash::BuildHasherDefault<ahash::AHasher>;
+
+#[cfg(not(feature = "ahash"))]
+pub type TrigramHasher = std::hash::BuildHasherDefault<rustc_hash::FxHasher>;
+
+// Significant bytes of a CRC-compacted key, see `trigram_keys/1`
+#[cfg(feature = "pg_parity")]
+pub(crate) const KEY_BYTES: usize = 3;
+
+#[cfg(not(feature = "pg_parity"))]
+pub(crate) const KEY_BYTES: usize = 4;
+
+/// Index and score of the haystack most similar to `needle`, lowest index
+/// first on ties, or `None` when there are no haystacks.
+pub fn best_match(needle: &str, haystacks: &[String]) -> Option<(usize, f32)> {
+    if haystacks.is_empty() {
+        return None;
+    }
+    Some(best_match_from_set(&trigrams(needle), haystacks))
+}
+
+/// Every haystack scoring at least `min_threshold` against `needle`, as
+/// `(index, score)` sorted by score desc, then index asc.
+pub fn score_all<S: AsRef<str> + Sync>(
+    needle: &str,
+    haystacks: &[S],
+    min_threshold: f32,
+) -> Vec<(usize, f32)> {
+    score_all_from_set(&trigrams(needle), haystacks, min_threshold)
+}
+
+/// Set the input size at which batch functions switch from sequential to
+/// Rayon. Defaults to 250. `0` always runs in parallel, `usize::MAX` never does.
+pub fn set_parallel_threshold(threshold: usize) {
+    PARALLEL_THRESHOLD.store(threshold, AtomicOrdering::Relaxed);
+}
+
+/// Set the fewest items each Rayon task of `score_all` and `similarity_batch`
+/// handles. Defaults to 1; `0` is treated as 1.
+pub fn set_min_parallel_chunk(min_len: usize) {
+    MIN_PARALLEL_CHUNK.store(min_len.max(1), AtomicOrdering::Relaxed);
+}
+
+pub(crate) fn min_parallel_chunk() -> usize {
+    MIN_PARALLEL_CHUNK.load(AtomicOrdering::Relaxed)
+}
+
+pub(crate) fn score_all_from_set<S: AsRef<str> + Sync>(
+    needle_set: &TrigramHashSet,
+    haystacks: &[S],
+    min_threshold: f32,
+) -> Vec<(usize, f32)> {
+    score_all_ordered(needle_set, haystacks, min_threshold, ResultOrder::ByScore)
+}
+
+/// How `score_all` orders its results.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ResultOrder {
+    /// Score desc, then index asc
+    ByScore,
+    /// Index asc
+    ByIndex,
+    /// Whatever order scoring produced them in; unspecified
+    None,
+}
+
+pub(crate) fn score_all_ordered<S: AsRef<str> + Sync>(
+    needle_set: &TrigramHashSet,
+    haystacks: &[S],
+    min_threshold: f32,
+    order: ResultOrder,
+) -> Vec<(usize, f32)> {
+    let reachable = length_prefilter(needle_set.len(), min_threshold);
+
+    let mut results: Vec<(usize, f32)> = if below_parallel_threshold(haystacks.len()) {
+        haystacks
+            .iter()
+            .enumerate()
+            .filter(|(_, haystack)| reachable(haystack.as_ref()))
+            .filter_map(|(idx, haystack)| {
+                let score = with_trigrams(haystack.as_ref(), |haystack_set| {
+                    similarity_at_least(needle_set, haystack_set, min_threshold)
+                })?;
+                Some((idx, score))
+            })
+            .collect()
+    } else {
+        in_pool(|| {
+            haystacks
+                .par_iter()
+                .with_min_len(min_parallel_chunk())
+                .enumerate()
+                .filter(|(_, haystack)| reachable(haystack.as_ref()))
+                .filter_map(|(idx, haystack)| {
+                    let score = with_trigrams(haystack.as_ref(), |haystack_set| {
+                        similarity_at_least(needle_set, haystack_set, min_threshold)
+                    })?;
+                    Some((idx, score))
+                })
+                .collect()
+        })
+    };
+
+    // Both paths collect in index order, so only `ByScore` needs a sort.
+    // Unstable is fine: `rank_order` leaves no two results equal.
+    if order == ResultOrder::ByScore {
+        results.sort_unstable_by(rank_order);
+    }
+
+    results
+}
+
+pub(crate) fn below_parallel_threshold(len: usize) -> bool {
+    len < PARALLEL_THRESHOLD.load(AtomicOrdering::Relaxed)
+}
+
+/// Run a parallel section on the configured pool, or the global one if
+/// `configure_pool/1` was never called.
+pub(crate) fn in_pool<R: Send>(op: impl FnOnce() -> R + Send) -> R {
+    match POOL.get() {
+        Some(pool) => pool.install(op),
+        None => op(),
+    }
+}
+
+/// Result ordering shared by all ranked outputs: score desc, then index asc.
+pub(crate) fn rank_order(
+    (idx_a, score_a): &(usize, f32),
+    (idx_b, score_b): &(usize, f32),
+) -> Ordering {
+    score_b
+        .partial_cmp(score_a)
+        .unwrap_or(Ordering::Equal)
+        .then_with(|| idx_a.cmp(idx_b))
+}
+
+/// Upper bound on the similarity between a needle of `needle_size` trigrams
+/// and `haystack`, from its byte length alone.
+///
+/// Lowercasing never yields more chars than the input has bytes, and a word of
+/// `k` chars yields `k + 1` trigrams, so the haystack has at most `len + 1`.
+/// When that is below `needle_size` the union is at least `needle_size` and
+/// the intersection at most `len + 1`. Longer haystacks can't be bounded this
+/// way since repeated text collapses into few distinct trigrams.
+pub(crate) fn length_bound(needle_size: usize, haystack: &str) -> f32 {
+    let max_haystack_size = haystack.len() + 1;
+    if max_haystack_size >= needle_size {
+        1.0
+    } else {
+        jaccard_from_counts(max_haystack_size, needle_size) as f32
+    }
+}
+
+// Haystack filter for threshold-based ranking; passes everything unless the
+// length pre-filter is enabled
+pub(crate) fn length_prefilter(
+    needle_size: usize,
+    min_threshold: f32,
+) -> impl Fn(&str) -> bool + Sync {
+    let enabled = LENGTH_PREFILTER.load(AtomicOrdering::Relaxed);
+    move |haystack| !enabled || length_bound(needle_size, haystack) >= min_threshold
+}
+
+pub fn similarity_from_sets(a_set: &TrigramHashSet, b_set: &TrigramHashSet) -> f32 {
+    jaccard_from_sets(a_set, b_set) as f32
+}
+
+/// `similarity_from_sets` if it is at least `threshold`, else `None`.
+///
+/// Walks the smaller set and gives up once enough keys have missed that even
+/// sharing every remaining one couldn't reach `threshold`. The bound uses the
+/// same arithmetic as the final score, so it never drops a passing pair.
+pub(crate) fn similarity_at_least(
+    a_set: &TrigramHashSet,
+    b_set: &TrigramHashSet,
+    threshold: f32,
+) -> Option<f32> {
+    let (small, large) = if a_set.len() <= b_set.len() {
+        (a_set, b_set)
+    } else {
+        (b_set, a_set)
+    };
+    let sizes = a_set.len() + b_set.len();
+    let (mut shared, mut missed) = (0, 0);
+    for key in small {
+        if large.contains(key) {
+            shared += 1;
+        } else {
+            missed += 1;
+            let best_shared = small.len() - missed;
+            if (jaccard_from_counts(best_shared, sizes - best_shared) as f32) < threshold {
+                return None;
+            }
+        }
+    }
+
+    #[cfg(test)]
+    FULL_WALKS.with(|walks| walks.set(walks.get() + 1));
+
+    let score = jaccard_from_counts(shared, sizes - shared) as f32;
+    (score >= threshold).then_some(score)
+}
+
+/// Whether `similarity_from_sets(a_set, b_set) >= threshold`, walking the
+/// smaller set only until the answer is settled: enough shared keys that the
+/// score can't fall below `threshold`, or enough misses that it can't reach
+/// it.
+pub(crate) fn reaches_threshold(
+    a_set: &TrigramHashSet,
+    b_set: &TrigramHashSet,
+    threshold: f32,
+) -> bool {
+    let (small, large) = if a_set.len() <= b_set.len() {
+        (a_set, b_set)
+    } else {
+        (b_set, a_set)
+    };
+    let sizes = a_set.len() + b_set.len();
+    let score_with = |shared: usize| jaccard_from_counts(shared, sizes - shared) as f32;
+    let (mut shared, mut missed) = (0, 0);
+    for key in small {
+        if large.contains(key) {
+            shared += 1;
+            // More shared keys only raise the score
+            if score_with(shared) >= threshold {
+                return true;
+            }
+        } else {
+            missed += 1;
+            if score_with(small.len() - missed) < threshold {
+                return false;
+            }
+        }
+    }
+    score_with(shared) >= threshold
+}
+
+// Walks `similarity_at_least` finished on this thread
+#[cfg(test)]
+thread_local! {
+    static FULL_WALKS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+pub(crate) fn jaccard_from_sets(a_set: &TrigramHashSet, b_set: &TrigramHashSet) -> f64 {
+    let (shared, total) = overlap_counts(a_set, b_set);
+    jaccard_from_counts(shared, total)
+}
+
+pub(crate) fn jaccard_from_counts(shared: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        shared as f64 / total as f64
+    }
+}
+
+/// Sizes of the intersection and union of two trigram sets.
+pub(crate) fn overlap_counts(a_set: &TrigramHashSet, b_set: &TrigramHashSet) -> (usize, usize) {
+    let shared = a_set.intersection(b_set).count();
+    let total = a_set.len() + b_set.len() - shared;
+    (shared, total)
+}
+
+/// Compact form of a trigram set: its keys in ascending order. Smaller than a
+/// `TrigramHashSet` and faster to intersect, by a merge walk for sets of
+/// similar size or a binary search when one is far larger.
+pub fn sorted_keys(set: &TrigramHashSet) -> Vec<TrigramKey> {
+    let mut keys: Vec<TrigramKey> = set.iter().copied().collect();
+    keys.sort_unstable();
+    keys
+}
+
+pub fn similarity_from_sorted(a: &[TrigramKey], b: &[TrigramKey]) -> f32 {
+    let (shared, total) = overlap_counts_sorted(a, b);
+    jaccard_from_counts(shared, total) as f32
+}
+
+/// `overlap_counts` for two `sorted_keys` slices.
+pub(crate) fn overlap_counts_sorted(a: &[TrigramKey], b: &[TrigramKey]) -> (usize, usize) {
+    let shared = shared_count_sorted(a, b);
+    (shared, a.len() + b.len() - shared)
+}
+
+// Size ratio past which galloping beats merging: about 8 against the scalar
+// merge and 40 against the SSE2 one, per the `intersection` group of
+// `cargo bench --bench crossover`
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+const SEARCH_RATIO: usize = 40;
+
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+const SEARCH_RATIO: usize = 8;
+
+fn shared_count_sorted(a: &[TrigramKey], b: &[TrigramKey]) -> usize {
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if large.len() / SEARCH_RATIO >= small.len().max(1) {
+        search_shared_count(small, large)
+    } else {
+        merge_count(small, large)
+    }
+}
+
+/// The merge walk `similarity_from_sorted` uses: SSE2 under the `simd`
+/// feature on x86_64, else `merge_shared_count`.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+pub fn merge_count(a: &[TrigramKey], b: &[TrigramKey]) -> usize {
+    crate::simd::shared_count(a, b)
+}
+
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+pub fn merge_count(a: &[TrigramKey], b: &[TrigramKey]) -> usize {
+    merge_shared_count(a, b)
+}
+
+/// Scalar merge walk counting the keys two sorted slices share.
+pub fn merge_shared_count(a: &[TrigramKey], b: &[TrigramKey]) -> usize {
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => i += 1,
+            Ordering::Greater => j += 1,
+            Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    shared
+}
+
+/// Counts the keys of `small` found in `large`, both sorted, by galloping:
+/// each key is searched for in a window doubled from where the last one
+/// landed, so the cost grows with the log of the gaps rather than of `large`.
+pub fn search_shared_count(small: &[TrigramKey], large: &[TrigramKey]) -> usize {
+    let (mut rest, mut shared) = (large, 0);
+    for key in small {
+        let mut window = 1;
+        while window < rest.len() && rest[window - 1] < *key {
+            window *= 2;
+        }
+        match rest[..window.min(rest.len())].binary_search(key) {
+            Ok(i) => {
+                shared += 1;
+                rest = &rest[i + 1..];
+            }
+            Err(i) => rest = &rest[i..],
+        }
+    }
+    shared
+}
+
+pub fn trigrams(text: &str) -> TrigramHashSet {
+    if cache::enabled() {
+        return cache::get_or_compute(text, |text| ngrams(text, 3));
+    }
+    ngrams(text, 3)
+}
+
+/// Buffers one thread reuses across `with_trigrams` calls.
+#[derive(Default)]
+struct Scratch {
+    normalized: String,
+    char_buf: Vec<char>,
+    set: TrigramHashSet,
+}
+
+thread_local! {
+    static SCRATCH: RefCell<Scratch> = RefCell::new(Scratch::default());
+}
+
+// Clearing a set costs its capacity and the buffers just sit on the thread,
+// so one huge input shouldn't leave every later call clearing a huge table or
+// holding a huge string
+const MAX_SCRATCH_CAPACITY: usize = 1 << 12;
+
+/// Calls `f` with the trigrams of `text`, built in this thread's scratch
+/// buffers so batch loops don't allocate a string, buffer and set per item.
+/// The set is only lent to `f`; use `trigrams` to keep one. With the cache
+/// enabled, or when called from inside `f`, this just uses `trigrams`.
+pub(crate) fn with_trigrams<R>(text: &str, f: impl FnOnce(&TrigramHashSet) -> R) -> R {
+    if cache::enabled() {
+        return f(&trigrams(text));
+    }
+    SCRATCH.with(|scratch| {
+        let Ok(mut scratch) = scratch.try_borrow_mut() else {
+            return f(&trigrams(text));
+        };
+        let Scratch {
+            normalized,
+            char_buf,
+            set,
+        } = &mut *scratch;
+        if set.capacity() > MAX_SCRATCH_CAPACITY {
+            *set = TrigramHashSet::default();
+        }
+        if normalized.capacity() > MAX_SCRATCH_CAPACITY {
+            *normalized = String::new();
+        }
+        if char_buf.capacity() > MAX_SCRATCH_CAPACITY {
+            *char_buf = Vec::new();
+        }
+        downcase_into(text, true, normalized);
+        trigrams_into(char_buf, set, normalized);
+        f(set)
+    })
+}
+
+/// Replaces `set` with the trigrams of already-normalized text, reusing
+/// `char_buf` and the set's allocation.
+pub(crate) fn trigrams_into(char_buf: &mut Vec<char>, set: &mut TrigramHashSet, normalized: &str) {
+    set.clear();
+    ngrams_into(normalized, 3, &WORD_RE, char_buf, set);
+}
+
+/// n-grams of each word, padded with `n - 1` leading spaces and one trailing
+/// space. For `n = 3` this is exactly pg_trgm's padding.
+pub(crate) fn ngrams(text: &str, n: usize) -> TrigramHashSet {
+    // CRITICAL: Must normalize (lowercase + remove \u{0307}) BEFORE regex matching
+    // to match PostgreSQL pg_trgm behavior exactly. This order matters for edge cases.
+    ngrams_from_normalized(&pg_downcase(text), n)
+}
+
+pub(crate) fn ngrams_from_normalized(normalized: &str, n: usize) -> TrigramHashSet {
+    ngrams_from_words(normalized, n, &WORD_RE)
+}
+
+// Past this many distinct n-grams the set just grows; natural text saturates
+// well below it, so reserving more for huge inputs would mostly be waste
+const MAX_PREALLOCATED_NGRAMS: usize = 1 << 16;
+
+/// Capacity hint for an n-gram set of `normalized`.
+///
+/// A word of `k` units yields `k + 1` n-grams for any `n`, so the codepoint
+/// count plus one bounds the set size whatever the script. (Bytes / 3 only
+/// fit CJK, and left ASCII sets growing two or three times.) Min 16 to avoid
+/// tiny reallocs.
+pub fn ngram_capacity(normalized: &str) -> usize {
+    (normalized.chars().count() + 1).clamp(16, MAX_PREALLOCATED_NGRAMS)
+}
+
+/// N-grams of every `word_re` match in already-normalized text.
+pub(crate) fn ngrams_from_words(normalized: &str, n: usize, word_re: &Regex) -> TrigramHashSet {
+    let mut set =
+        TrigramHashSet::with_capacity_and_hasher(ngram_capacity(normalized), Default::default());
+
+    // Reusable buffer to avoid allocating a new Vec for every word
+    let mut char_buf: Vec<char> = Vec::with_capacity(64);
+    ngrams_into(normalized, n, word_re, &mut char_buf, &mut set);
+    set
+}
+
+// Adds the n-grams of every `word_re` match to `set`
+fn ngrams_into(
+    normalized: &str,
+    n: usize,
+    word_re: &Regex,
+    char_buf: &mut Vec<char>,
+    set: &mut TrigramHashSet,
+) {
+    for mat in word_re.find_iter(normalized) {
+        char_buf.clear();
+        char_buf.extend(std::iter::repeat_n(' ', n - 1)); // Pre-padding
+
+        // Text is already normalized by the caller (pg_downcase by default)
+        char_buf.extend(mat.as_str().chars());
+
+        char_buf.push(' '); // Post-padding
+
+        for window in char_buf.windows(n) {
+            set.insert(compact_ngram(window));
+        }
+    }
+}
+
+/// Normalize text to match PostgreSQL pg_trgm behavior:
+/// lowercase + remove combining dot above (\u{0307})
+pub(crate) fn pg_downcase(text: &str) -> String {
+    downcase(text, true)
+}
+
+/// Lowercase `text`, optionally dropping the combining dot above (\u{0307})
+/// that `İ` and friends decompose into.
+pub(crate) fn downcase(text: &str, strip_dot_above: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    downcase_into(text, strip_dot_above, &mut result);
+    result
+}
+
+/// `downcase` into a caller-owned buffer, replacing its contents.
+pub fn downcase_into(text: &str, strip_dot_above: bool, out: &mut String) {
+    // ASCII lowercases byte for byte and can't contain \u{0307}
+    if text.is_ascii() {
+        out.clear();
+        out.push_str(text);
+        out.make_ascii_lowercase();
+        return;
+    }
+    downcase_chars_into(text, strip_dot_above, out);
+}
+
+/// General path of `downcase_into`, for text with any non-ASCII char. Public
+/// so `benches/downcase.rs` can time the ASCII fast path against it.
+pub fn downcase_chars_into(text: &str, strip_dot_above: bool, out: &mut String) {
+    out.clear();
+    for c in text.chars() {
+        for lc in c.to_lowercase() {
+            if !strip_dot_above || lc != '\u{0307}' {
+                out.push(lc);
+            }
+        }
+    }
+}
+
+pub(crate) fn compact_ngram(chars: &[char]) -> TrigramKey {
+    // OPTIMIZATION: Stack allocation instead of Heap Vec
+    let mut buf = [0u8; 4];
+    let mut bytes = [0u8; 4 * MAX_NGRAM]; // Max UTF-8 size for MAX_NGRAM chars
+    let mut len = 0;
+
+    for &ch in chars {
+        let encoded = ch.encode_utf8(&mut buf);
+        // SAFETY: We have pre-allocated 4 bytes per char for up to MAX_NGRAM chars.
+        bytes[len..len + encoded.len()].copy_from_slice(encoded.as_bytes());
+        len += encoded.len();
+    }
+
+    if len == chars.len() {
+        // Fast path for ASCII (1 byte per char), packed without hashing
+        let mut key = [0u8; 4];
+        key[..len].copy_from_slice(&bytes[..len]);
+        TrigramKey::from_le_bytes(key)
+    } else {
+        // Fallback for multi-byte chars: Calculate CRC32
+        crc_key(&bytes[..len])
+    }
+}
+
+// CRC fallback key, truncated to `KEY_BYTES`
+pub(crate) fn crc_key(bytes: &[u8]) -> TrigramKey {
+    legacy_crc32(bytes) & (u32::MAX >> (8 * (4 - KEY_BYTES)))
+}
+
+pub(crate) fn legacy_crc32(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+
+    for &byte in bytes {
+        let idx = ((crc >> 24) as u8 ^ byte) as usize & 0xFF;
+        // SAFETY: Table size is 256, idx is masked & 0xFF.
+        let table_val = unsafe { *PG_CRC32_TABLE.get_unchecked(idx) };
+        crc = table_val ^ (crc << 8);
+    }
+
+    crc ^ 0xFFFF_FFFF
+}
+
+// Full PostgreSQL CRC32 Table
+const PG_CRC32_TABLE: [u32; 256] = [
+    0x00000000, 0x77073096, 0xEE0E612C, 0x990951BA, 0x076DC419, 0x706AF48F, 0xE963A535, 0x9E6495A3,
+    0x0EDB8832, 0x79DCB8A4, 0xE0D5E91E, 0x97D2D988, 0x09B64C2B, 0x7EB17CBD, 0xE7B82D07, 0x90BF1D91,
+    0x1DB71064, 0x6AB020F2, 0xF3B97148, 0x84BE41DE, 0x1ADAD47D, 0x6DDDE4EB, 0xF4D4B551, 0x83D385C7,
+    0x136C9856, 0x646BA8C0, 0xFD62F97A, 0x8A65C9EC, 0x14015C4F, 0x63066CD9, 0xFA0F3D63, 0x8D080DF5,
+    0x3B6E20C8, 0x4C69105E, 0xD56041E4, 0xA2677172, 0x3C03E4D1, 0x4B04D447, 0xD20D85FD, 0xA50AB56B,
+    0x35B5A8FA, 0x42B2986C, 0xDBBBC9D6, 0xACBCF940, 0x32D86CE3, 0x45DF5C75, 0xDCD60DCF, 0xABD13D59,
+    0x26D930AC, 0x51DE003A, 0xC8D75180, 0xBFD06116, 0x21B4F4B5, 0x56B3C423, 0xCFBA9599, 0xB8BDA50F,
+    0x2802B89E, 0x5F058808, 0xC60CD9B2, 0xB10BE924, 0x2F6F7C87, 0x58684C11, 0xC1611DAB, 0xB6662D3D,
+    0x76DC4190, 0x01DB7106, 0x98D220BC, 0xEFD5102A, 0x71B18589, 0x06B6B51F, 0x9FBFE4A5, 0xE8B8D433,
+    0x7807C9A2, 0x0F00F934, 0x9609A88E, 0xE10E9818, 0x7F6A0DBB, 0x086D3D2D, 0x91646C97, 0xE6635C01,
+    0x6B6B51F4, 0x1C6C6162, 0x856530D8, 0xF262004E, 0x6C0695ED, 0x1B01A57B, 0x8208F4C1, 0xF50FC457,
+    0x65B0D9C6, 0x12B7E950, 0x8BBEB8EA, 0xFCB9887C, 0x62DD1DDF, 0x15DA2D49, 0x8CD37CF3, 0xFBD44C65,
+    0x4DB26158, 0x3AB551CE, 0xA3BC0074, 0xD4BB30E2, 0x4ADFA541, 0x3DD895D7, 0xA4D1C46D, 0xD3D6F4FB,
+    0x4369E96A, 0x346ED9FC, 0xAD678846, 0xDA60B8D0, 0x44042D73, 0x33031DE5, 0xAA0A4C5F, 0xDD0D7CC9,
+    0x5005713C, 0x270241AA, 0xBE0B1010, 0xC90C2086, 0x5768B525, 0x206F85B3, 0xB966D409, 0xCE61E49F,
+    0x5EDEF90E, 0x29D9C998, 0xB0D09822, 0xC7D7A8B4, 0x59B33D17, 0x2EB40D81, 0xB7BD5C3B, 0xC0BA6CAD,
+    0xEDB88320, 0x9ABFB3B6, 0x03B6E20C, 0x74B1D29A, 0xEAD54739, 0x9DD277AF, 0x04DB2615, 0x73DC1683,
+    0xE3630B12, 0x94643B84, 0x0D6D6A3E, 0x7A6A5AA8, 0xE40ECF0B, 0x9309FF9D, 0x0A00AE27, 0x7D079EB1,
+    0xF00F9344, 0x8708A3D2, 0x1E01F268, 0x6906C2FE, 0xF762575D, 0x806567CB, 0x196C3671, 0x6E6B06E7,
+    0xFED41B76, 0x89D32BE0, 0x10DA7A5A, 0x67DD4ACC, 0xF9B9DF6F, 0x8EBEEFF9, 0x17B7BE43, 0x60B08ED5,
+    0xD6D6A3E8, 0xA1D1937E, 0x38D8C2C4, 0x4FDFF252, 0xD1BB67F1, 0xA6BC5767, 0x3FB506DD, 0x48B2364B,
+    0xD80D2BDA, 0xAF0A1B4C, 0x36034AF6, 0x41047A60, 0xDF60EFC3, 0xA867DF55, 0x316E8EEF, 0x4669BE79,
+    0xCB61B38C, 0xBC66831A, 0x256FD2A0, 0x5268E236, 0xCC0C7795, 0xBB0B4703, 0x220216B9, 0x5505262F,
+    0xC5BA3BBE, 0xB2BD0B28, 0x2BB45A92, 0x5CB36A04, 0xC2D7FFA7, 0xB5D0CF31, 0x2CD99E8B, 0x5BDEAE1D,
+    0x9B64C2B0, 0xEC63F226, 0x756AA39C, 0x026D930A, 0x9C0906A9, 0xEB0E363F, 0x72076785, 0x05005713,
+    0x95BF4A82, 0xE2B87A14, 0x7BB12BAE, 0x0CB61B38, 0x92D28E9B, 0xE5D5BE0D, 0x7CDCEFB7, 0x0BDBDF21,
+    0x86D3D2D4, 0xF1D4E242, 0x68DDB3F8, 0x1FDA836E, 0x81BE16CD, 0xF6B9265B, 0x6FB077E1, 0x18B74777,
+    0x88085AE6, 0xFF0F6A70, 0x66063BCA, 0x11010B5C, 0x8F659EFF, 0xF862AE69, 0x616BFFD3, 0x166CCF45,
+    0xA00AE278, 0xD70DD2EE, 0x4E048354, 0x3903B3C2, 0xA7672661, 0xD06016F7, 0x4969474D, 0x3E6E77DB,
+    0xAED16A4A, 0xD9D65ADC, 0x40DF0B66, 0x37D83BF0, 0xA9BCAE53, 0xDEBB9EC5, 0x47B2CF7F, 0x30B5FFE9,
+    0xBDBDF21C, 0xCABAC28A, 0x53B39330, 0x24B4A3A6, 0xBAD03605, 0xCDD70693, 0x54DE5729, 0x23D967BF,
+    0xB3667A2E, 0xC4614AB8, 0x5D681B02, 0x2A6F2B94, 0xB40BBE37, 0xC30C8EA1, 0x5A05DF1B, 0x2D02EF8D,
+];
+
+/// How `best_match` chooses among haystacks tied at the top score. Remaining
+/// ties always go to the lowest index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TieBreak {
+    First,
+    Longest,
+    Shortest,
+}
+
+/// Whether `candidate` beats `best`: a higher score, then the `tie_break`
+/// preference, then the lower index. This is a total order, so the parallel
+/// reduce picks the same winner whatever order partial results combine in.
+fn beats(
+    candidate: (usize, f32),
+    best: (usize, f32),
+    haystacks: &[String],
+    tie_break: TieBreak,
+) -> bool {
+    match candidate.1.partial_cmp(&best.1) {
+        Some(Ordering::Greater) => return true,
+        Some(Ordering::Equal) => {}
+        _ => return false,
+    }
+    let len = |idx: usize| haystacks[idx].chars().count();
+    let preference = match tie_break {
+        TieBreak::First => Ordering::Equal,
+        TieBreak::Longest => len(candidate.0).cmp(&len(best.0)),
+        TieBreak::Shortest => len(best.0).cmp(&len(candidate.0)),
+    };
+    preference.then_with(|| best.0.cmp(&candidate.0)).is_gt()
+}
+
+pub(crate) fn best_match_from_set(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+) -> (usize, f32) {
+    best_match_by(needle_set, haystacks, TieBreak::First)
+}
+
+pub(crate) fn best_match_by(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    tie_break: TieBreak,
+) -> (usize, f32) {
+    // Defensive sentinel: Jaccard is always >= 0.0.
+    // Starting at -1.0 ensures the first valid comparison always wins.
+    let init_acc = (0, -1.0);
+    let pick = |acc: (usize, f32), x: (usize, f32)| {
+        if beats(x, acc, haystacks, tie_break) {
+            x
+        } else {
+            acc
+        }
+    };
+
+    if LENGTH_PREFILTER.load(AtomicOrdering::Relaxed) {
+        return best_match_prefiltered(needle_set, haystacks, init_acc, pick);
+    }
+
+    if below_parallel_threshold(haystacks.len()) {
+        // Sequential Path (Avoids thread pool overhead)
+        haystacks
+            .iter()
+            .enumerate()
+            .map(|(idx, haystack)| {
+                let score = with_trigrams(haystack, |haystack_set| {
+                    similarity_from_sets(needle_set, haystack_set)
+                });
+                (idx, score)
+            })
+            .fold(init_acc, pick)
+    } else {
+        in_pool(|| {
+            // Parallel Path (Rayon)
+            haystacks
+                .par_iter()
+                .enumerate()
+                .map(|(idx, haystack)| {
+                    let score = with_trigrams(haystack, |haystack_set| {
+                        similarity_from_sets(needle_set, haystack_set)
+                    });
+                    (idx, score)
+                })
+                .reduce(|| init_acc, pick)
+        })
+    }
+}
+
+// Like `best_match_by`, but only trigrams haystacks whose length bound can at
+// least tie the best score found so far (per Rayon task on the parallel path)
+fn best_match_prefiltered(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    init_acc: (usize, f32),
+    pick: impl Fn((usize, f32), (usize, f32)) -> (usize, f32) + Sync,
+) -> (usize, f32) {
+    let step = |acc: (usize, f32), (idx, haystack): (usize, &String)| {
+        if length_bound(needle_set.len(), haystack) < acc.1 {
+            return acc;
+        }
+        pick(
+            acc,
+            (
+                idx,
+                with_trigrams(haystack, |set| similarity_from_sets(needle_set, set)),
+            ),
+        )
+    };
+
+    if below_parallel_threshold(haystacks.len()) {
+        haystacks.iter().enumerate().fold(init_acc, step)
+    } else {
+        in_pool(|| {
+            haystacks
+                .par_iter()
+                .enumerate()
+                .fold(|| init_acc, step)
+                .reduce(|| init_acc, &pick)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_rust_api() {
+        let haystacks: Vec<String> = ["world", "hello", "help", "hello"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        assert_eq!(best_match("hello", &haystacks), Some((1, 1.0)));
+        assert_eq!(best_match("hello", &[]), None);
+
+        let results = score_all("hello", &haystacks, 0.2);
+        assert_eq!(
+            results.iter().map(|&(idx, _)| idx).collect::<Vec<_>>(),
+            [1, 3, 2]
+        );
+        assert_eq!(
+            results[2].1,
+            similarity_from_sets(&trigrams("hello"), &trigrams("help"))
+        );
+        assert!(score_all("hello", &[] as &[&str], 0.0).is_empty());
+    }
+
+    #[test]
+    fn test_similarity_at_least_matches_exact_filter() {
+        let needle_set = trigrams("international business machines");
+        let haystacks: Vec<String> = (0..500)
+            .map(|i| match i % 4 {
+                0 => format!("international business {}", i),
+                1 => format!("business machines {}", i),
+                2 => format!("unrelated text {}", i),
+                _ => "international business machines".to_string(),
+            })
+            .collect();
+        let sets: Vec<_> = haystacks.iter().map(|h| trigrams(h)).collect();
+
+        for threshold in [0.0, 0.1, 0.3, 0.5, 0.7, 1.0] {
+            let exact: Vec<Option<f32>> = sets
+                .iter()
+                .map(|set| Some(similarity_from_sets(&needle_set, set)).filter(|s| *s >= threshold))
+                .collect();
+
+            let before = FULL_WALKS.with(|walks| walks.get());
+            let early: Vec<Option<f32>> = sets
+                .iter()
+                .map(|set| similarity_at_least(&needle_set, set, threshold))
+                .collect();
+            let walked = FULL_WALKS.with(|walks| walks.get()) - before;
+
+            assert_eq!(early, exact, "threshold {}", threshold);
+            // Hopeless haystacks bail out before finishing the walk
+            let passed = exact.iter().flatten().count();
+            assert!(walked >= passed);
+            if threshold > 0.0 {
+                assert!(walked < sets.len(), "{} walks at {}", walked, threshold);
+            }
+        }
+
+        let results = score_all("international business machines", &haystacks, 0.5);
+        assert!(!results.is_empty());
+        for &(idx, score) in &results {
+            assert_eq!(score, similarity_from_sets(&needle_set, &sets[idx]));
+            assert!(score >= 0.5);
+        }
+        assert_eq!(
+            similarity_at_least(&trigrams(""), &trigrams(""), 0.0),
+            Some(0.0)
+        );
+        assert_eq!(similarity_at_least(&trigrams(""), &trigrams(""), 0.1), None);
+    }
+
+    #[test]
+    fn test_ascii_downcase_matches_general_path() {
+        let inputs = [
+            "SKU-12345-AbC",
+            "Hello, World! 0123456789 ~`@#$%^&*()_+-={}[]|\\:;\"'<>,.?/",
+            "",
+            "MiXeD ÄÖÜ İstanbul ΣΊΣΥΦΟΣ",
+            "ascii then é",
+        ];
+        let all_ascii: String = (0..=127u8).map(char::from).collect();
+        for text in inputs.iter().copied().chain([all_ascii.as_str()]) {
+            for strip in [true, false] {
+                let (mut fast, mut general) = (String::from("stale"), String::new());
+                downcase_into(text, strip, &mut fast);
+                downcase_chars_into(text, strip, &mut general);
+                assert_eq!(fast, general, "{:?}", text);
+            }
+        }
+        // Mixed input takes the general path for the whole string
+        assert_eq!(pg_downcase("ABC İ"), "abc i");
+    }
+
+    #[test]
+    fn test_with_trigrams_matches_trigrams() {
+        let texts = [
+            "hello world",
+            "",
+            "ÅNGSTRÖM İstanbul",
+            "a",
+            "東京 タワー",
+            "the quick brown fox jumps over the lazy dog",
+            "hello",
+        ];
+        // Each call must see only its own text, whatever came before
+        for text in texts.iter().chain(texts.iter().rev()) {
+            assert_eq!(with_trigrams(text, |set| set.clone()), trigrams(text));
+        }
+        // Nested calls fall back to a fresh set rather than clobbering
+        let nested = with_trigrams("outer text", |outer| {
+            let inner = with_trigrams("inner", |inner| inner.clone());
+            (outer.clone(), inner)
+        });
+        assert_eq!(nested, (trigrams("outer text"), trigrams("inner")));
+
+        // A huge input's buffers are dropped by the next call, not kept
+        let mut huge: String = (0..MAX_SCRATCH_CAPACITY)
+            .map(|i| format!("w{i} "))
+            .collect();
+        huge.push_str(&"x".repeat(MAX_SCRATCH_CAPACITY));
+        with_trigrams(&huge, |set| assert_eq!(set, &trigrams(&huge)));
+        with_trigrams("hello", |set| assert_eq!(set, &trigrams("hello")));
+        SCRATCH.with_borrow(|scratch| {
+            assert!(scratch.set.capacity() <= MAX_SCRATCH_CAPACITY);
+            assert!(scratch.normalized.capacity() <= MAX_SCRATCH_CAPACITY);
+            assert!(scratch.char_buf.capacity() <= MAX_SCRATCH_CAPACITY);
+        });
+
+        let haystacks: Vec<String> = texts.iter().map(|t| t.to_string()).collect();
+        let mut naive: Vec<(usize, f32)> = haystacks
+            .iter()
+            .enumerate()
+            .map(|(idx, h)| (idx, similarity_from_sets(&trigrams("hello"), &trigrams(h))))
+            .filter(|&(_, score)| score >= 0.1)
+            .collect();
+        naive.sort_by(rank_order);
+        assert_eq!(score_all("hello", &haystacks, 0.1), naive);
+    }
+
+    #[test]
+    fn test_search_shared_count_matches_merge() {
+        let keys = |len: u32, step: u32, offset: u32| -> Vec<TrigramKey> {
+            (0..len).map(|i| i * step + offset).collect()
+        };
+        let large = keys(2_000, 3, 0);
+        for small in [
+            vec![],
+            keys(1, 1, 0),
+            keys(10, 7, 1),
+            keys(50, 120, 0),
+            keys(200, 30, 2),
+            vec![5_997, 6_000, 9_000],
+            large.clone(),
+        ] {
+            let expected = merge_shared_count(&small, &large);
+            assert_eq!(search_shared_count(&small, &large), expected);
+            // The dispatch picks either path, whichever argument is larger
+            assert_eq!(shared_count_sorted(&small, &large), expected);
+            assert_eq!(shared_count_sorted(&large, &small), expected);
+        }
+    }
+}
@@ -0,0 +1,651 @@
+//! Inverted trigram index for repeated searches over a static corpus.
+//!
+//! Every document's trigram set is computed once at build time, alongside a
+//! posting list from each trigram to the documents containing it. A query only
+//! visits documents sharing at least one trigram with the needle, and the
+//! shared count accumulated while walking the postings is exactly the
+//! intersection size, so no set intersection is needed.
+//!
+//! Documents can be added and removed in place. The data lives behind an
+//! `RwLock` so concurrent queries only contend with writers.
+//!
+//! An index can be serialized to a binary and loaded elsewhere. The blob
+//! stores only the per-document trigram sets; postings are rebuilt on load.
+
+use rayon::prelude::*;
+use rustc_hash::{FxHashMap, FxHashSet};
+use rustler::{Binary, Encoder, Env, NifResult, OwnedBinary, ResourceArc, Term};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::{
+    below_parallel_threshold, catch_internal, check_input_len, guard_internal, in_pool,
+    jaccard_from_counts, rank_order, sorted_keys, trigrams, TrigramHashSet, TrigramKey,
+};
+
+const MAGIC: &[u8; 4] = b"TRGI";
+const FORMAT_VERSION: u8 = 1;
+/// Identifies how `TrigramKey`s are derived (packed ASCII bytes, full CRC32
+/// otherwise). Must change whenever `compact_ngram` does, since keys from a
+/// different scheme would silently produce wrong scores. `pg_parity` builds
+/// use scheme 2 (3-byte CRC keys, Postgres word class).
+#[cfg(not(feature = "pg_parity"))]
+const KEY_SCHEME: u8 = 1;
+#[cfg(feature = "pg_parity")]
+const KEY_SCHEME: u8 = 2;
+/// Document length marking a removed id.
+const HOLE: u32 = u32::MAX;
+
+#[derive(Debug, PartialEq)]
+enum DecodeError {
+    /// Not an index blob, or truncated.
+    Invalid,
+    /// A blob from an incompatible version or key scheme.
+    Unsupported,
+}
+
+impl DecodeError {
+    fn reason(&self) -> &'static str {
+        match self {
+            DecodeError::Invalid => "invalid_index",
+            DecodeError::Unsupported => "unsupported_index_version",
+        }
+    }
+}
+
+pub(crate) struct TrigramIndex {
+    data: RwLock<IndexData>,
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for TrigramIndex {}
+
+/// Documents are addressed by their position in `docs`; removed ids leave a
+/// `None` hole so the remaining ids stay stable. Each document keeps its
+/// trigrams as `sorted_keys`, which is all `remove` and scoring need.
+/// Posting lists are kept sorted by id, so `remove` binary-searches them
+/// instead of scanning.
+struct IndexData {
+    docs: Vec<Option<Vec<TrigramKey>>>,
+    postings: FxHashMap<TrigramKey, Vec<usize>>,
+}
+
+impl TrigramIndex {
+    fn build(strings: &[String]) -> Self {
+        TrigramIndex {
+            data: RwLock::new(IndexData::build(strings)),
+        }
+    }
+
+    // Nothing under the write lock panics short of allocation failure,
+    // which aborts anyway, so a poisoned lock still guards consistent data
+    fn read(&self) -> RwLockReadGuard<'_, IndexData> {
+        self.data.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, IndexData> {
+        self.data.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn query(&self, needle: &str, min_threshold: f32) -> Vec<(usize, f32)> {
+        self.read().query(needle, min_threshold)
+    }
+
+    /// Stores `string` under `id`, replacing any existing document with that
+    /// id, or under the next free id when `id` is `None`. Ids past the next
+    /// free one are rejected, so a stray id can't allocate a huge gap.
+    fn add(&self, id: Option<usize>, string: &str) -> Option<usize> {
+        let set = trigrams(string);
+        let mut data = self.write();
+        let next = data.docs.len();
+        let id = id.unwrap_or(next);
+        if id > next {
+            return None;
+        }
+        data.remove(id);
+        data.insert(id, set);
+        Some(id)
+    }
+
+    fn remove(&self, id: usize) -> bool {
+        self.write().remove(id)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.read().to_bytes()
+    }
+
+    fn stats(&self) -> (usize, usize, usize) {
+        self.read().stats()
+    }
+
+    fn query_first(&self, needle: &str, good_enough: f32) -> (Option<(usize, f32)>, usize) {
+        self.read().query_first(needle, good_enough)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Ok(TrigramIndex {
+            data: RwLock::new(IndexData::from_bytes(bytes)?),
+        })
+    }
+}
+
+impl IndexData {
+    fn build(strings: &[String]) -> Self {
+        let sets: Vec<TrigramHashSet> = if below_parallel_threshold(strings.len()) {
+            strings.iter().map(|s| trigrams(s)).collect()
+        } else {
+            in_pool(|| strings.par_iter().map(|s| trigrams(s)).collect())
+        };
+
+        let mut data = IndexData {
+            docs: Vec::with_capacity(sets.len()),
+            postings: FxHashMap::default(),
+        };
+        for (id, set) in sets.into_iter().enumerate() {
+            data.insert(id, set);
+        }
+        data
+    }
+
+    /// `id` must not currently hold a document, and must be at most
+    /// `docs.len()`.
+    fn insert(&mut self, id: usize, set: TrigramHashSet) {
+        let keys = sorted_keys(&set);
+        if id == self.docs.len() {
+            self.docs.push(None);
+        }
+        for &trigram in &keys {
+            let ids = self.postings.entry(trigram).or_default();
+            // Build and load insert in id order, so only re-adds search
+            if ids.last().is_none_or(|&last| last < id) {
+                ids.push(id);
+            } else if let Err(pos) = ids.binary_search(&id) {
+                ids.insert(pos, id);
+            }
+        }
+        self.docs[id] = Some(keys);
+    }
+
+    /// Scores candidates one at a time, in posting order of the needle's
+    /// sorted trigrams, and stops at the first with similarity >= `good_enough`.
+    /// Otherwise returns the best candidate (lowest id on ties), or `None` if
+    /// no document shares a trigram. Also returns how many were scored.
+    fn query_first(&self, needle: &str, good_enough: f32) -> (Option<(usize, f32)>, usize) {
+        let needle_set = trigrams(needle);
+        let mut seen: FxHashSet<usize> = FxHashSet::default();
+        let mut best: Option<(usize, f32)> = None;
+
+        for trigram in sorted_keys(&needle_set) {
+            for &id in self.postings.get(&trigram).into_iter().flatten() {
+                if !seen.insert(id) {
+                    continue;
+                }
+                let Some(keys) = self.docs[id].as_ref() else {
+                    continue;
+                };
+                let shared = keys.iter().filter(|key| needle_set.contains(key)).count();
+                let score =
+                    jaccard_from_counts(shared, needle_set.len() + keys.len() - shared) as f32;
+
+                if score >= good_enough {
+                    return (Some((id, score)), seen.len());
+                }
+                if best.is_none_or(|(best_id, best_score)| {
+                    score > best_score || (score == best_score && id < best_id)
+                }) {
+                    best = Some((id, score));
+                }
+            }
+        }
+        (best, seen.len())
+    }
+
+    /// `(documents, distinct trigrams, approximate heap bytes)`. Bytes count
+    /// allocated capacity of the document and posting vectors plus the
+    /// postings table, so they reflect what is actually held, not just used.
+    fn stats(&self) -> (usize, usize, usize) {
+        let documents = self.docs.iter().flatten().count();
+
+        let doc_bytes = self.docs.capacity() * size_of::<Option<Vec<TrigramKey>>>()
+            + self
+                .docs
+                .iter()
+                .flatten()
+                .map(|keys| keys.capacity() * size_of::<TrigramKey>())
+                .sum::<usize>();
+        // hashbrown keeps one control byte per bucket next to each entry
+        let posting_bytes = self.postings.capacity() * (size_of::<(TrigramKey, Vec<usize>)>() + 1)
+            + self
+                .postings
+                .values()
+                .map(|ids| ids.capacity() * size_of::<usize>())
+                .sum::<usize>();
+
+        (documents, self.postings.len(), doc_bytes + posting_bytes)
+    }
+
+    /// Layout: magic, version byte, key-scheme byte, slot count, then per slot
+    /// a key count (or `HOLE`) followed by the sorted keys. Integers are
+    /// little-endian `u32`s.
+    fn to_bytes(&self) -> Vec<u8> {
+        let keys: usize = self.docs.iter().flatten().map(Vec::len).sum();
+        let mut out = Vec::with_capacity(10 + 4 * (self.docs.len() + keys));
+        out.extend_from_slice(MAGIC);
+        out.push(FORMAT_VERSION);
+        out.push(KEY_SCHEME);
+        out.extend_from_slice(&(self.docs.len() as u32).to_le_bytes());
+
+        for doc in &self.docs {
+            match doc {
+                None => out.extend_from_slice(&HOLE.to_le_bytes()),
+                Some(keys) => {
+                    out.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+                    for key in keys {
+                        out.extend_from_slice(&key.to_le_bytes());
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let header = bytes.get(..6).ok_or(DecodeError::Invalid)?;
+        if &header[..4] != MAGIC {
+            return Err(DecodeError::Invalid);
+        }
+        if header[4] != FORMAT_VERSION || header[5] != KEY_SCHEME {
+            return Err(DecodeError::Unsupported);
+        }
+
+        let mut words = bytes[6..].chunks(4).map(|chunk| {
+            chunk
+                .try_into()
+                .map(u32::from_le_bytes)
+                .map_err(|_| DecodeError::Invalid)
+        });
+        let mut next = || words.next().unwrap_or(Err(DecodeError::Invalid));
+
+        let slots = next()? as usize;
+        let mut data = IndexData {
+            docs: Vec::new(),
+            postings: FxHashMap::default(),
+        };
+        for id in 0..slots {
+            let len = next()?;
+            if len == HOLE {
+                data.docs.push(None);
+                continue;
+            }
+            let set = (0..len).map(|_| next()).collect::<Result<_, _>>()?;
+            data.insert(id, set);
+        }
+
+        if next().is_ok() {
+            return Err(DecodeError::Invalid);
+        }
+        Ok(data)
+    }
+
+    fn remove(&mut self, id: usize) -> bool {
+        let Some(keys) = self.docs.get_mut(id).and_then(Option::take) else {
+            return false;
+        };
+
+        for trigram in &keys {
+            if let Some(ids) = self.postings.get_mut(trigram) {
+                if let Ok(pos) = ids.binary_search(&id) {
+                    ids.remove(pos);
+                }
+                if ids.is_empty() {
+                    self.postings.remove(trigram);
+                }
+            }
+        }
+        true
+    }
+
+    /// Same results, in the same order, as `score_all` over the indexed corpus.
+    fn query(&self, needle: &str, min_threshold: f32) -> Vec<(usize, f32)> {
+        let needle_set = trigrams(needle);
+
+        let mut shared: FxHashMap<usize, usize> = FxHashMap::default();
+        for trigram in &needle_set {
+            if let Some(ids) = self.postings.get(trigram) {
+                for &id in ids {
+                    *shared.entry(id).or_insert(0) += 1;
+                }
+            }
+        }
+
+        let score = |keys: &Vec<TrigramKey>, count: usize| {
+            let total = needle_set.len() + keys.len() - count;
+            jaccard_from_counts(count, total) as f32
+        };
+
+        let mut results: Vec<(usize, f32)> = if min_threshold <= 0.0 {
+            // Documents without a shared trigram still satisfy a zero threshold
+            self.docs
+                .iter()
+                .enumerate()
+                .filter_map(|(id, set)| {
+                    let set = set.as_ref()?;
+                    Some((id, score(set, shared.get(&id).copied().unwrap_or(0))))
+                })
+                .filter(|(_, score)| *score >= min_threshold)
+                .collect()
+        } else {
+            shared
+                .into_iter()
+                .filter_map(|(id, count)| Some((id, score(self.docs[id].as_ref()?, count))))
+                .filter(|(_, score)| *score >= min_threshold)
+                .collect()
+        };
+
+        results.sort_unstable_by(rank_order);
+        results
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn index_build(strings: Vec<String>) -> NifResult<ResourceArc<TrigramIndex>> {
+    check_input_len(&[&strings])?;
+    guard_internal(|| ResourceArc::new(TrigramIndex::build(&strings)))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn index_query(
+    index: ResourceArc<TrigramIndex>,
+    needle: &str,
+    min_threshold: f32,
+) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle])?;
+    guard_internal(|| index.query(needle, min_threshold))
+}
+
+/// Raises `ArgumentError` for an id past the next free one.
+///
+/// Dirty because the write lock waits out any in-flight query.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn index_add(
+    index: ResourceArc<TrigramIndex>,
+    id: Option<usize>,
+    string: &str,
+) -> NifResult<usize> {
+    check_input_len(&[&string])?;
+    guard_internal(|| index.add(id, string))?.ok_or(rustler::Error::BadArg)
+}
+
+/// First document scoring at least `good_enough` as `{id, score}`, else the
+/// best candidate, or `nil` when no document shares a trigram with `needle`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn index_query_first(
+    index: ResourceArc<TrigramIndex>,
+    needle: &str,
+    good_enough: f32,
+) -> NifResult<Option<(usize, f32)>> {
+    check_input_len(&[&needle])?;
+    guard_internal(|| index.query_first(needle, good_enough).0)
+}
+
+/// `{num_documents, num_unique_trigrams, approx_bytes}` for capacity planning.
+#[rustler::nif]
+fn index_stats(index: ResourceArc<TrigramIndex>) -> (usize, usize, usize) {
+    index.stats()
+}
+
+/// Dirty for the same reason as `index_add`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn index_remove(index: ResourceArc<TrigramIndex>, id: usize) -> bool {
+    index.remove(id)
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn index_serialize(env: Env<'_>, index: ResourceArc<TrigramIndex>) -> NifResult<Binary<'_>> {
+    let bytes = guard_internal(|| index.to_bytes())?;
+    let mut binary = OwnedBinary::new(bytes.len()).ok_or(rustler::Error::BadArg)?;
+    binary.as_mut_slice().copy_from_slice(&bytes);
+    Ok(binary.release(env))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn index_deserialize<'a>(env: Env<'a>, blob: Binary<'a>) -> NifResult<Term<'a>> {
+    let (tag, value) = match catch_internal(|| TrigramIndex::from_bytes(blob.as_slice())) {
+        Ok(Ok(index)) => ("ok", ResourceArc::new(index).encode(env)),
+        Ok(Err(err)) => (
+            "error",
+            rustler::types::atom::Atom::from_str(env, err.reason())?.to_term(env),
+        ),
+        Err(reason) => (
+            "error",
+            rustler::types::atom::Atom::from_str(env, reason)?.to_term(env),
+        ),
+    };
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            rustler::types::atom::Atom::from_str(env, tag)?.to_term(env),
+            value,
+        ],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{score_all_from_set, similarity_from_sets};
+
+    fn corpus() -> Vec<String> {
+        [
+            "hello world",
+            "hallo welt",
+            "help desk",
+            "",
+            "yellow submarine",
+            "hello",
+            "привет мир",
+            "東京タワー",
+            "world peace",
+            "hello world",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect()
+    }
+
+    #[test]
+    fn test_query_matches_score_all() {
+        let corpus = corpus();
+        let index = TrigramIndex::build(&corpus);
+
+        for needle in ["hello", "world", "привет", "東京", "", "zzz", "hello wrld"] {
+            for min_threshold in [0.0, 0.1, 0.3, 0.5, 1.0] {
+                assert_eq!(
+                    index.query(needle, min_threshold),
+                    score_all_from_set(&trigrams(needle), &corpus, min_threshold),
+                    "needle {:?} threshold {}",
+                    needle,
+                    min_threshold
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_query_only_visits_candidates() {
+        let index = TrigramIndex::build(&corpus());
+        let results = index.query("submarine", 0.1);
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, 4);
+        assert!(index.query("qqq", 0.01).is_empty());
+    }
+
+    #[test]
+    fn test_add_remove_matches_fresh_build() {
+        let corpus = corpus();
+        let index = TrigramIndex::build(&corpus[..4]);
+
+        assert_eq!(index.add(None, &corpus[4]), Some(4));
+        assert_eq!(index.add(Some(5), "placeholder"), Some(5));
+        assert_eq!(index.add(Some(6), &corpus[6]), Some(6));
+        assert!(index.remove(2));
+        assert!(!index.remove(2));
+        assert!(!index.remove(42));
+        assert_eq!(index.add(Some(5), &corpus[5]), Some(5));
+        for (id, doc) in corpus.iter().enumerate().skip(7) {
+            assert_eq!(index.add(None, doc), Some(id));
+        }
+        assert!(index.remove(0));
+        assert!(index.read().postings.values().all(|ids| ids.is_sorted()));
+
+        let live: Vec<usize> = (1..corpus.len()).filter(|&id| id != 2).collect();
+        let fresh_corpus: Vec<String> = live.iter().map(|&id| corpus[id].clone()).collect();
+        let fresh = TrigramIndex::build(&fresh_corpus);
+
+        for needle in ["hello", "help", "world", "placeholder", ""] {
+            for min_threshold in [0.0, 0.2, 0.5] {
+                let mut expected: Vec<(usize, f32)> = fresh
+                    .query(needle, min_threshold)
+                    .into_iter()
+                    .map(|(i, score)| (live[i], score))
+                    .collect();
+                expected.sort_unstable_by(rank_order);
+
+                let results = index.query(needle, min_threshold);
+                assert!(results.iter().all(|(id, _)| *id != 0 && *id != 2));
+                assert_eq!(
+                    results, expected,
+                    "needle {:?} threshold {}",
+                    needle, min_threshold
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let index = TrigramIndex::build(&corpus());
+        index.remove(3);
+        index.add(Some(10), "hello again");
+        index.remove(2);
+
+        let bytes = index.to_bytes();
+        let loaded = TrigramIndex::from_bytes(&bytes).unwrap();
+
+        assert_eq!(loaded.to_bytes(), bytes);
+        for needle in ["hello", "world", "東京", "again", ""] {
+            for min_threshold in [0.0, 0.3] {
+                let original = index.query(needle, min_threshold);
+                let restored = loaded.query(needle, min_threshold);
+                let bits = |results: &[(usize, f32)]| -> Vec<(usize, u32)> {
+                    results
+                        .iter()
+                        .map(|(id, score)| (*id, score.to_bits()))
+                        .collect()
+                };
+                assert_eq!(bits(&restored), bits(&original));
+            }
+        }
+        assert_eq!(loaded.add(None, "next"), Some(11));
+    }
+
+    #[test]
+    fn test_deserialize_rejects_bad_blobs() {
+        let bytes = TrigramIndex::build(&corpus()).to_bytes();
+
+        let decode = |bytes: &[u8]| TrigramIndex::from_bytes(bytes).err();
+        assert_eq!(decode(b""), Some(DecodeError::Invalid));
+        assert_eq!(decode(b"not an index"), Some(DecodeError::Invalid));
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 1]),
+            Some(DecodeError::Invalid)
+        );
+        assert_eq!(
+            decode(&[&bytes[..], &[0; 4]].concat()),
+            Some(DecodeError::Invalid)
+        );
+
+        let mut other_scheme = bytes.clone();
+        other_scheme[5] = KEY_SCHEME + 1;
+        assert_eq!(decode(&other_scheme), Some(DecodeError::Unsupported));
+        let mut other_version = bytes;
+        other_version[4] = FORMAT_VERSION + 1;
+        assert_eq!(decode(&other_version), Some(DecodeError::Unsupported));
+    }
+
+    #[test]
+    fn test_stats_track_add_and_remove() {
+        let index = TrigramIndex::build(&corpus()[..4]);
+        let (docs, trigrams, bytes) = index.stats();
+        assert_eq!(docs, 4);
+        assert!(trigrams > 0 && bytes > 0);
+
+        index.add(None, "a completely new document about submarines");
+        let (grown_docs, grown_trigrams, grown_bytes) = index.stats();
+        assert_eq!(grown_docs, 5);
+        assert!(grown_trigrams > trigrams);
+        assert!(grown_bytes > bytes);
+
+        index.remove(4);
+        let (shrunk_docs, shrunk_trigrams, shrunk_bytes) = index.stats();
+        assert_eq!(shrunk_docs, 4);
+        assert_eq!(shrunk_trigrams, trigrams);
+        assert!(shrunk_bytes < grown_bytes);
+
+        assert_eq!(TrigramIndex::build(&[]).stats().0, 0);
+    }
+
+    #[test]
+    fn test_query_first_stops_at_good_enough() {
+        let corpus = corpus();
+        let index = TrigramIndex::build(&corpus);
+        let candidates = index.query("hello world", f32::MIN_POSITIVE).len();
+
+        let (found, scanned) = index.query_first("hello world", 0.3);
+        let (id, score) = found.unwrap();
+        assert!(score >= 0.3);
+        assert_eq!(
+            score,
+            similarity_from_sets(&trigrams("hello world"), &trigrams(&corpus[id]))
+        );
+        assert!(scanned <= candidates);
+
+        // Nothing is good enough: every candidate is scanned, the best returned
+        let (found, scanned) = index.query_first("hello world", 1.1);
+        assert_eq!(found, Some((0, 1.0)));
+        assert_eq!(scanned, candidates);
+
+        assert_eq!(index.query_first("zzz", 0.1), (None, 0));
+    }
+
+    #[test]
+    fn test_add_rejects_ids_past_the_end() {
+        let index = TrigramIndex::build(&corpus()[..3]);
+        let before = index.to_bytes();
+
+        assert_eq!(index.add(Some(4), "gap"), None);
+        assert_eq!(index.add(Some(1_000_000_000), "huge"), None);
+        assert_eq!(index.add(Some(usize::MAX), "wraps"), None);
+        assert_eq!(index.to_bytes(), before);
+
+        assert_eq!(index.add(Some(3), "next"), Some(3));
+        assert_eq!(index.query("next", 1.0), [(3, 1.0)]);
+    }
+
+    #[test]
+    fn test_poisoned_lock_is_recovered() {
+        let index = std::sync::Arc::new(TrigramIndex::build(&corpus()));
+        let poisoner = std::sync::Arc::clone(&index);
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.data.write().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(index.data.is_poisoned());
+
+        assert_eq!(index.query("hello world", 1.0), [(0, 1.0), (9, 1.0)]);
+        assert_eq!(index.add(None, "after"), Some(10));
+        assert!(index.remove(10));
+    }
+}
@@ -0,0 +1,129 @@
+//! Resumable batch jobs, so a huge batch never runs as one long NIF call.
+//!
+//! `similarity_batch_start/1` only stores the pairs; each
+//! `similarity_batch_step/2` call then scores the next chunk and returns,
+//! giving the scheduler back between chunks. The Elixir side loops until the
+//! job reports `:done`. Steps on one job run one at a time, and each chunk
+//! comes back with the index of its first pair, so concurrent callers can
+//! still put the scores in order.
+
+use rustler::{Atom, Env, NifResult, ResourceArc};
+use std::sync::Mutex;
+
+use crate::{check_input_len, guard_internal, similarity_pairs};
+
+pub(crate) struct SimilarityJob {
+    pairs: Vec<(String, String)>,
+    // Index of the first pair not yet scored, held for the whole step
+    cursor: Mutex<usize>,
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for SimilarityJob {}
+
+impl SimilarityJob {
+    fn new(pairs: Vec<(String, String)>) -> Self {
+        SimilarityJob {
+            pairs,
+            cursor: Mutex::new(0),
+        }
+    }
+
+    /// Index of the first of the next `max_pairs` pairs (at least one), their
+    /// scores, and whether the job is now exhausted. The cursor only moves
+    /// once the chunk is scored, so a panic mid-step leaves it unconsumed.
+    fn step(&self, max_pairs: usize) -> (usize, Vec<f32>, bool) {
+        let mut cursor = self.cursor.lock().unwrap_or_else(|e| e.into_inner());
+        let start = *cursor;
+        let end = (start + max_pairs.max(1)).min(self.pairs.len());
+        let scores = similarity_pairs(&self.pairs[start..end]);
+        *cursor = end;
+        (start, scores, end == self.pairs.len())
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_batch_start(pairs: Vec<(String, String)>) -> NifResult<ResourceArc<SimilarityJob>> {
+    check_input_len(&[&pairs])?;
+    guard_internal(|| ResourceArc::new(SimilarityJob::new(pairs)))
+}
+
+/// `{:cont, offset, scores}` for the next chunk of at most `max_pairs` pairs,
+/// starting at pair `offset`, or `{:done, offset, scores}` for the last one.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_batch_step(
+    env: Env<'_>,
+    job: ResourceArc<SimilarityJob>,
+    max_pairs: usize,
+) -> NifResult<(Atom, usize, Vec<f32>)> {
+    let (offset, scores, done) = guard_internal(|| job.step(max_pairs))?;
+    let tag = if done { "done" } else { "cont" };
+    Ok((Atom::from_str(env, tag)?, offset, scores))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_batch_matches_all_at_once() {
+        let words = ["hello", "hallo", "world", "word", "two words", "", "東京"];
+        let pairs: Vec<(String, String)> = (0..10_000)
+            .map(|i| {
+                (
+                    words[i % words.len()].to_string(),
+                    format!("{} {}", words[(i / 7) % words.len()], i % 13),
+                )
+            })
+            .collect();
+        let expected = similarity_pairs(&pairs);
+
+        for chunk in [1_000, 3_333, 10_000, 50_000] {
+            let job = SimilarityJob::new(pairs.clone());
+            let mut scores = Vec::new();
+            let mut steps = 0;
+            loop {
+                let (offset, chunk_scores, done) = job.step(chunk);
+                assert_eq!(offset, scores.len());
+                scores.extend(chunk_scores);
+                steps += 1;
+                if done {
+                    break;
+                }
+            }
+            assert_eq!(scores, expected);
+            assert_eq!(steps, pairs.len().div_ceil(chunk));
+        }
+
+        let empty = SimilarityJob::new(Vec::new());
+        assert_eq!(empty.step(10), (0, Vec::new(), true));
+
+        // Concurrent steps each get a distinct chunk, which its offset places
+        let job = SimilarityJob::new(pairs.clone());
+        let mut chunks: Vec<(usize, Vec<f32>)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut mine = Vec::new();
+                        loop {
+                            let (offset, chunk_scores, done) = job.step(97);
+                            if !chunk_scores.is_empty() {
+                                mine.push((offset, chunk_scores));
+                            }
+                            if done {
+                                return mine;
+                            }
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().unwrap())
+                .collect()
+        });
+        chunks.sort_by_key(|&(offset, _)| offset);
+        let scores: Vec<f32> = chunks.into_iter().flat_map(|(_, s)| s).collect();
+        assert_eq!(scores, expected);
+    }
+}
@@ -1,450 +1,5464 @@
 use once_cell::sync::Lazy;
 use rayon::prelude::*;
 use regex::Regex;
-use rustc_hash::FxHashSet;
-use rustler::{Encoder, Env, NifResult, Term};
+use rustc_hash::FxHashMap;
+use rustler::{Binary, Encoder, Env, LocalPid, NifResult, OwnedBinary, ResourceArc, Term};
 use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
 
-// Pre-compiled regex for word boundary detection
-static WORD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"[\p{L}\p{N}]+").unwrap());
+use crate::core::{
+    below_parallel_threshold, best_match_by, best_match_from_set, compact_ngram, crc_key, downcase,
+    downcase_into, in_pool, jaccard_from_counts, jaccard_from_sets, length_prefilter,
+    min_parallel_chunk, ngram_capacity, ngrams, ngrams_from_normalized, ngrams_from_words,
+    overlap_counts, pg_downcase, rank_order, reaches_threshold, score_all_from_set,
+    score_all_ordered, similarity_from_sets, similarity_from_sorted, sorted_keys, trigrams,
+    with_trigrams, ResultOrder, TieBreak, TrigramHashSet, TrigramKey, KEY_BYTES, LENGTH_PREFILTER,
+    MAX_NGRAM, MIN_NGRAM, POOL, WORD_RE,
+};
 
-// HEURISTIC: Only spin up Rayon threads if the batch is large enough to justify
-// the coordination overhead. 250 items is a safe crossover point.
-const PARALLEL_THRESHOLD: usize = 250;
+mod cache;
+pub mod core;
+mod index;
+mod job;
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd;
+mod sketch;
+mod stream;
 
+// Alternate word patterns for `similarity_with_pattern`
+static IDENT_WORD_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\w+").unwrap());
+static NON_SPACE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\S+").unwrap());
+
+// Grapheme mode words: alphanumerics plus everything that can make up an emoji
+// cluster (marks and variation selectors, modifiers, ZWJ, flags, tag chars)
+static GRAPHEME_WORD_RE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"[\p{L}\p{N}\p{M}\p{Extended_Pictographic}\p{Emoji_Modifier}\p{Regional_Indicator}\u{200D}\u{E0020}-\u{E007F}]+",
+    )
+    .unwrap()
+});
+
+// Whitespace runs, tokenized by `similarity_ws`
+static WHITESPACE_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\s+").unwrap());
+
+// Decimal digits of any script, collapsed by `similarity_digitclass`
+static DIGIT_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\p{Nd}").unwrap());
+
+// Nonspacing marks, dropped after NFD decomposition in unaccent mode
+static NONSPACING_MARK_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\p{Mn}+").unwrap());
+
+// Opt-in cross-script shortcut for pairwise similarity, see `set_script_prefilter/1`
+static SCRIPT_PREFILTER: AtomicBool = AtomicBool::new(false);
+
+// Under `pg_parity` trigram keys are CRCs, which can collide across scripts,
+// so the shortcut could drop a real match and stays off there
+fn script_prefilter_enabled() -> bool {
+    !cfg!(feature = "pg_parity") && SCRIPT_PREFILTER.load(AtomicOrdering::Relaxed)
+}
+
+// `hybrid_similarity` switches to Jaro-Winkler below this many word chars,
+// see `set_hybrid_min_chars/1`
+const DEFAULT_HYBRID_MIN_CHARS: usize = 4;
+// Keeps the short side of a Jaro-Winkler fallback short, so its cost stays
+// linear in the other string
+const MAX_HYBRID_MIN_CHARS: usize = 256;
+static HYBRID_MIN_CHARS: AtomicUsize = AtomicUsize::new(DEFAULT_HYBRID_MIN_CHARS);
+
+// Longest input, in bytes, the guarded entry points accept, see
+// `set_max_input_len/1`
+const DEFAULT_MAX_INPUT_LEN: usize = 64 * 1024 * 1024;
+pub(crate) static MAX_INPUT_LEN: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_INPUT_LEN);
+
+// Longest normalized input, in chars, the quadratic edit-distance NIFs
+// accept: 10k x 10k cells is still well under a second
+const MAX_EDIT_CHARS: usize = 10_000;
+
+// Weight of trigrams holding word padding in `weighted_similarity`; interior
+// trigrams weigh 1
+const BOUNDARY_WEIGHT: f64 = 2.0;
+
+// Word-boundary flags for trigram sequences, as in pg_trgm's TrgmBound
+const TRGM_BOUND_LEFT: u8 = 0x01;
+const TRGM_BOUND_RIGHT: u8 = 0x02;
+
+/// Raises `:input_too_long` if either string exceeds `set_max_input_len/1`.
+#[rustler::nif]
+fn similarity(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| pair_similarity(s1, s2))
+}
+
+fn pair_similarity(s1: &str, s2: &str) -> f32 {
+    if script_prefilter_enabled() && script_mask(s1) & script_mask(s2) == 0 {
+        return 0.0;
+    }
+    if cache::enabled() {
+        // Cached sets are keyed by the raw string, so only raw duplicates
+        // can skip a lookup here
+        if s1 == s2 {
+            return identical_similarity(&trigrams(s1));
+        }
+        return similarity_from_sets(&trigrams(s1), &trigrams(s2));
+    }
+
+    // Equal normalized text means equal sets: build one, intersect none
+    let (normalized1, normalized2) = (pg_downcase(s1), pg_downcase(s2));
+    let s1_set = ngrams_from_normalized(&normalized1, 3);
+    if normalized1 == normalized2 {
+        return identical_similarity(&s1_set);
+    }
+    similarity_from_sets(&s1_set, &ngrams_from_normalized(&normalized2, 3))
+}
+
+/// Whether `similarity(s1, s2) >= threshold`, stopping as soon as the
+/// answer is certain. Raises `:input_too_long` like `similarity/2`.
+#[rustler::nif]
+fn is_similar(s1: &str, s2: &str, threshold: f32) -> NifResult<bool> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| pair_reaches(s1, s2, threshold))
+}
+
+fn pair_reaches(s1: &str, s2: &str, threshold: f32) -> bool {
+    if script_prefilter_enabled() && script_mask(s1) & script_mask(s2) == 0 {
+        return 0.0 >= threshold;
+    }
+    reaches_threshold(&trigrams(s1), &trigrams(s2), threshold)
+}
+
+// `similarity_from_sets(set, set)`: 1.0, or 0.0 for an empty set
+fn identical_similarity(set: &TrigramHashSet) -> f32 {
+    if set.is_empty() {
+        0.0
+    } else {
+        1.0
+    }
+}
+
+/// Best `similarity` between `input` and any of `needles`, trigramming
+/// `input` once; `0.0` for no needles. Raises `:input_too_long` like
+/// `similarity/2`.
+#[rustler::nif]
+fn similarity_any(input: &str, needles: Vec<String>) -> NifResult<f32> {
+    check_input_len(&[&input, &needles])?;
+    guard_internal(|| max_similarity(input, &needles))
+}
+
+fn max_similarity(input: &str, needles: &[String]) -> f32 {
+    let input_set = trigrams(input);
+    let input_mask = script_prefilter_enabled().then(|| script_mask(input));
+    needles
+        .iter()
+        .filter(|needle| input_mask.is_none_or(|mask| mask & script_mask(needle) != 0))
+        .map(|needle| with_trigrams(needle, |set| similarity_from_sets(&input_set, set)))
+        .fold(0.0, f32::max)
+}
+
+/// pg_trgm `word_similarity`: the greatest similarity between the needle's
+/// trigram set and any continuous extent of the haystack's trigram sequence.
+#[rustler::nif]
+fn word_similarity(needle: &str, haystack: &str) -> NifResult<f32> {
+    check_input_len(&[&needle, &haystack])?;
+    guard_internal(|| {
+        let needle_set = trigrams(needle);
+        let (haystack_seq, _) = trigram_sequence(haystack);
+        word_similarity_from_sets(&needle_set, &haystack_seq, None)
+    })
+}
+
+/// pg_trgm `strict_word_similarity`: like `word_similarity`, but extents must
+/// start and end on word boundaries of the haystack.
+#[rustler::nif]
+fn strict_word_similarity(needle: &str, haystack: &str) -> NifResult<f32> {
+    check_input_len(&[&needle, &haystack])?;
+    guard_internal(|| {
+        let needle_set = trigrams(needle);
+        let (haystack_seq, bounds) = trigram_sequence(haystack);
+        word_similarity_from_sets(&needle_set, &haystack_seq, Some(&bounds))
+    })
+}
+
+/// Whether any single word of `haystack` scores at least `threshold` against
+/// `needle`. A cheaper yes/no than `word_similarity`, which also tries
+/// extents spanning several words.
+#[rustler::nif]
+fn fuzzy_contains(needle: &str, haystack: &str, threshold: f32) -> NifResult<bool> {
+    check_input_len(&[&needle, &haystack])?;
+    guard_internal(|| contains_similar_word(&trigrams(needle), haystack, threshold))
+}
+
+fn contains_similar_word(needle_set: &TrigramHashSet, haystack: &str, threshold: f32) -> bool {
+    let normalized = pg_downcase(haystack);
+    WORD_RE.find_iter(&normalized).any(|word| {
+        similarity_from_sets(needle_set, &ngrams_from_normalized(word.as_str(), 3)) >= threshold
+    })
+}
+
+/// Human-readable trigrams of `text`, like Postgres `show_trgm`.
+///
+/// Multi-byte trigrams are CRC-compacted and cannot be decoded, so they are
+/// rendered as the hex of their packed key (`0x1a2b3c4d`). Output is sorted.
 #[rustler::nif]
-fn similarity(s1: &str, s2: &str) -> f32 {
-    let s1_set = trigrams(s1);
-    let s2_set = trigrams(s2);
-    similarity_from_sets(&s1_set, &s2_set)
+fn show_trgm(text: &str) -> NifResult<Vec<String>> {
+    check_input_len(&[&text])?;
+    guard_internal(|| show_trigrams(text))
 }
 
+/// How many of `strings` contain each trigram, most common first (ties by
+/// label). Trigrams are labelled as in `show_trgm`, so multi-byte ones show
+/// as the hex of their packed key.
 #[rustler::nif(schedule = "DirtyCpu")]
-fn similarity_batch(pairs: Vec<(String, String)>) -> Vec<f32> {
-    // HYBRID APPROACH: Sequential for small inputs, Parallel for large
-    if pairs.len() < PARALLEL_THRESHOLD {
-        pairs
-            .iter()
-            .map(|(s1, s2)| {
-                let s1_set = trigrams(s1);
-                let s2_set = trigrams(s2);
-                similarity_from_sets(&s1_set, &s2_set)
-            })
-            .collect()
+fn trigram_document_frequencies(strings: Vec<String>) -> NifResult<Vec<(String, u32)>> {
+    check_input_len(&[&strings])?;
+    guard_internal(|| document_frequencies(&strings))
+}
+
+/// Raw trigram keys of `text`, sorted, each as its `KEY_BYTES` little-endian
+/// bytes. Under `pg_parity` these are the bytes Postgres stores per trigram.
+#[rustler::nif]
+fn trigram_keys<'a>(env: Env<'a>, text: &str) -> NifResult<Vec<Binary<'a>>> {
+    check_input_len(&[&text])?;
+    guard_internal(|| trigram_key_bytes(text))?
+        .into_iter()
+        .map(|key| {
+            let mut binary = OwnedBinary::new(KEY_BYTES).ok_or(rustler::Error::BadArg)?;
+            binary.as_mut_slice().copy_from_slice(&key);
+            Ok(binary.release(env))
+        })
+        .collect()
+}
+
+/// Whether the NIF was built with the `pg_parity` feature.
+#[rustler::nif]
+fn pg_parity() -> bool {
+    cfg!(feature = "pg_parity")
+}
+
+/// Hasher behind trigram sets: `"ahash"` when built with the `ahash` feature,
+/// else `"fxhash"`.
+#[rustler::nif]
+fn hasher_name() -> &'static str {
+    if cfg!(feature = "ahash") {
+        "ahash"
     } else {
-        pairs
-            .par_iter() // Rayon parallel iterator
-            .map(|(s1, s2)| {
-                let s1_set = trigrams(s1);
-                let s2_set = trigrams(s2);
-                similarity_from_sets(&s1_set, &s2_set)
-            })
-            .collect()
+        "fxhash"
     }
 }
 
+/// The normalized form trigrams are built from: lowercased, with the
+/// combining dot above (\u{0307}) removed.
+#[rustler::nif]
+fn normalize(text: &str) -> NifResult<String> {
+    check_input_len(&[&text])?;
+    guard_internal(|| pg_downcase(text))
+}
+
+/// `normalize/1` over a list, in input order.
 #[rustler::nif(schedule = "DirtyCpu")]
-fn best_match<'a>(env: Env<'a>, needle: &str, haystacks: Vec<String>) -> NifResult<Term<'a>> {
-    if haystacks.is_empty() {
-        return Ok(rustler::types::tuple::make_tuple(
+fn normalize_batch(strings: Vec<String>) -> NifResult<Vec<String>> {
+    check_input_len(&[&strings])?;
+    guard_internal(|| normalize_all(&strings))
+}
+
+fn normalize_all(strings: &[String]) -> Vec<String> {
+    if below_parallel_threshold(strings.len()) {
+        strings.iter().map(|s| pg_downcase(s)).collect()
+    } else {
+        in_pool(|| strings.par_iter().map(|s| pg_downcase(s)).collect())
+    }
+}
+
+/// Number of distinct trigrams in `text`; `0` means it can never match.
+#[rustler::nif]
+fn trigram_count(text: &str) -> NifResult<usize> {
+    check_input_len(&[&text])?;
+    guard_internal(|| trigrams(text).len())
+}
+
+/// `{normalize(text), trigram_count(text)}`, lowercasing `text` only once.
+#[rustler::nif]
+fn normalize_and_count(text: &str) -> NifResult<(String, usize)> {
+    check_input_len(&[&text])?;
+    guard_internal(|| normalized_with_count(text))
+}
+
+fn normalized_with_count(text: &str) -> (String, usize) {
+    let normalized = pg_downcase(text);
+    let count = ngrams_from_normalized(&normalized, 3).len();
+    (normalized, count)
+}
+
+/// `|A ∪ B|`: distinct trigrams in either string.
+#[rustler::nif]
+fn trigram_union_count(s1: &str, s2: &str) -> NifResult<usize> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| set_algebra_counts(s1, s2).0)
+}
+
+/// `|A ∩ B|`: trigrams the strings share. `similarity` is this over the union.
+#[rustler::nif]
+fn trigram_intersection_count(s1: &str, s2: &str) -> NifResult<usize> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| set_algebra_counts(s1, s2).1)
+}
+
+/// `|A Δ B|`: trigrams in exactly one of the strings, i.e. union minus
+/// intersection.
+#[rustler::nif]
+fn trigram_difference_count(s1: &str, s2: &str) -> NifResult<usize> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| set_algebra_counts(s1, s2).2)
+}
+
+/// All three counts at once, `{union, intersection, difference}`, trigramming
+/// each string only once.
+#[rustler::nif]
+fn trigram_set_counts(s1: &str, s2: &str) -> NifResult<(usize, usize, usize)> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| set_algebra_counts(s1, s2))
+}
+
+/// `(union, intersection, symmetric difference)` sizes of the trigram sets.
+fn set_algebra_counts(s1: &str, s2: &str) -> (usize, usize, usize) {
+    let (shared, total) = overlap_counts(&trigrams(s1), &trigrams(s2));
+    (total, shared, total - shared)
+}
+
+/// Similarity over n-grams of size `n` (2, 3 or 4). `n = 3` is `similarity`.
+#[rustler::nif]
+fn similarity_n(s1: &str, s2: &str, n: usize) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    if !(MIN_NGRAM..=MAX_NGRAM).contains(&n) {
+        return Err(rustler::Error::BadArg);
+    }
+    guard_internal(|| similarity_from_sets(&ngrams(s1, n), &ngrams(s2, n)))
+}
+
+/// Set the input size at which batch NIFs switch from sequential to Rayon.
+///
+/// Defaults to 250. `0` always runs in parallel, `usize::MAX` never does.
+#[rustler::nif]
+fn set_parallel_threshold(threshold: usize) -> rustler::Atom {
+    crate::core::set_parallel_threshold(threshold);
+    rustler::types::atom::ok()
+}
+
+/// Set the fewest items each Rayon task of `score_all` and `similarity_batch`
+/// handles, so millions of tiny strings aren't split into millions of tasks.
+/// Defaults to 1; `0` is treated as 1. Results are unaffected.
+#[rustler::nif]
+fn set_min_parallel_chunk(min_len: usize) -> rustler::Atom {
+    crate::core::set_min_parallel_chunk(min_len);
+    rustler::types::atom::ok()
+}
+
+/// Let `score_all` and `best_match` skip trigramming haystacks too short to
+/// reach the threshold (or the best score so far). Off by default.
+///
+/// The bound never rejects a haystack that could qualify, so results are
+/// unchanged either way.
+#[rustler::nif]
+fn set_length_prefilter(enabled: bool) -> rustler::Atom {
+    LENGTH_PREFILTER.store(enabled, AtomicOrdering::Relaxed);
+    rustler::types::atom::ok()
+}
+
+/// Let `similarity` and `similarity_batch` return `0.0` without trigramming
+/// when two strings share no Unicode block (e.g. Cyrillic vs Latin), since
+/// they can't share a trigram. Off by default, and ignored in `pg_parity`
+/// builds, whose CRC keys can collide across blocks.
+#[rustler::nif]
+fn set_script_prefilter(enabled: bool) -> rustler::Atom {
+    SCRIPT_PREFILTER.store(enabled, AtomicOrdering::Relaxed);
+    rustler::types::atom::ok()
+}
+
+/// Set the longest string, in bytes, that any NIF taking text accepts, so one
+/// runaway blob can't make a dirty scheduler allocate without bound. Longer
+/// input raises `:input_too_long` (an `ErlangError`) before any work starts.
+/// Defaults to 64 MiB.
+#[rustler::nif]
+fn set_max_input_len(max_len: usize) -> rustler::Atom {
+    MAX_INPUT_LEN.store(max_len, AtomicOrdering::Relaxed);
+    rustler::types::atom::ok()
+}
+
+// Text arguments as NIFs decode them, so one guard covers every shape
+pub(crate) trait InputTexts {
+    fn longest_len(&self) -> usize;
+}
+
+impl InputTexts for &str {
+    fn longest_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl InputTexts for String {
+    fn longest_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl InputTexts for Binary<'_> {
+    fn longest_len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: InputTexts> InputTexts for Vec<T> {
+    fn longest_len(&self) -> usize {
+        self.iter().map(InputTexts::longest_len).max().unwrap_or(0)
+    }
+}
+
+impl InputTexts for (String, String) {
+    fn longest_len(&self) -> usize {
+        self.0.len().max(self.1.len())
+    }
+}
+
+/// The guard every text-taking NIF runs first: raises `:input_too_long` if
+/// any string is longer than `set_max_input_len/1` allows.
+pub(crate) fn check_input_len(inputs: &[&dyn InputTexts]) -> NifResult<()> {
+    let max_len = MAX_INPUT_LEN.load(AtomicOrdering::Relaxed);
+    if inputs.iter().any(|input| input.longest_len() > max_len) {
+        return Err(rustler::Error::RaiseAtom("input_too_long"));
+    }
+    Ok(())
+}
+
+/// Run parallel work on a dedicated pool of `num_threads` threads (`0` lets
+/// Rayon pick) instead of the global one shared with other NIFs.
+///
+/// The pool can only be configured once: later calls return
+/// `{:error, :already_configured}` and leave it unchanged.
+#[rustler::nif]
+fn configure_pool(env: Env<'_>, num_threads: usize) -> NifResult<Term<'_>> {
+    match build_pool(num_threads) {
+        Ok(()) => Ok(rustler::types::atom::ok().to_term(env)),
+        Err(reason) => Ok(rustler::types::tuple::make_tuple(
             env,
             &[
                 rustler::types::atom::Atom::from_str(env, "error")?.to_term(env),
-                rustler::types::atom::Atom::from_str(env, "empty_list")?.to_term(env),
+                rustler::types::atom::Atom::from_str(env, reason)?.to_term(env),
             ],
-        ));
+        )),
+    }
+}
+
+fn build_pool(num_threads: usize) -> Result<(), &'static str> {
+    if POOL.get().is_some() {
+        return Err("already_configured");
     }
+    POOL.set(new_pool(num_threads)?)
+        .map_err(|_| "already_configured")
+}
 
-    // Optimization: Calculate needle trigrams exactly ONCE
-    let needle_set = trigrams(needle);
+fn new_pool(num_threads: usize) -> Result<rayon::ThreadPool, &'static str> {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(|i| format!("trigram-{}", i))
+        .build()
+        .map_err(|_| "pool_build_failed")
+}
 
-    // Defensive sentinel: Jaccard is always >= 0.0.
-    // Starting at -1.0 ensures the first valid comparison always wins.
-    let init_acc = (0, -1.0);
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_batch(pairs: Vec<(String, String)>) -> NifResult<Vec<f32>> {
+    check_input_len(&[&pairs])?;
+    guard_internal(|| similarity_pairs(&pairs))
+}
 
-    let (best_idx, best_score) = if haystacks.len() < PARALLEL_THRESHOLD {
-        // Sequential Path (Avoids thread pool overhead)
-        haystacks
+/// `similarity(reference, candidate)` for every candidate, in order, with
+/// `reference` trigrammed once. Like `score_all/3` without the threshold
+/// or the sort.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_one_to_many(reference: &str, candidates: Vec<String>) -> NifResult<Vec<f32>> {
+    check_input_len(&[&reference, &candidates])?;
+    guard_internal(|| one_to_many(reference, &candidates))
+}
+
+fn one_to_many(reference: &str, candidates: &[String]) -> Vec<f32> {
+    let reference_set = trigrams(reference);
+    let reference_mask = script_prefilter_enabled().then(|| script_mask(reference));
+    let score = |candidate: &String| {
+        if reference_mask.is_some_and(|mask| mask & script_mask(candidate) == 0) {
+            return 0.0;
+        }
+        with_trigrams(candidate, |set| similarity_from_sets(&reference_set, set))
+    };
+    if below_parallel_threshold(candidates.len()) {
+        candidates.iter().map(score).collect()
+    } else {
+        in_pool(|| {
+            candidates
+                .par_iter()
+                .with_min_len(min_parallel_chunk())
+                .map(score)
+                .collect()
+        })
+    }
+}
+
+/// `similarity_batch/1` as one binary instead of a list: 4 bytes per pair, in
+/// input order, each an IEEE-754 `f32` in little-endian byte order. Read it
+/// with `for <<score::float-32-little <- bin>>, do: score` or
+/// `Nx.from_binary(bin, :f32)`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_batch_binary(env: Env<'_>, pairs: Vec<(String, String)>) -> NifResult<Binary<'_>> {
+    check_input_len(&[&pairs])?;
+    let scores = guard_internal(|| similarity_pairs(&pairs))?;
+    let mut binary =
+        OwnedBinary::new(scores.len() * size_of::<f32>()).ok_or(rustler::Error::BadArg)?;
+    pack_f32_le(&scores, binary.as_mut_slice());
+    Ok(binary.release(env))
+}
+
+/// Writes `scores` into `out` (exactly 4 bytes per score) as little-endian.
+fn pack_f32_le(scores: &[f32], out: &mut [u8]) {
+    for (chunk, score) in out.chunks_exact_mut(size_of::<f32>()).zip(scores) {
+        chunk.copy_from_slice(&score.to_le_bytes());
+    }
+}
+
+/// `similarity/2` at full `f64` precision, for callers that accumulate scores.
+#[rustler::nif]
+fn similarity_f64(s1: &str, s2: &str) -> NifResult<f64> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| jaccard_from_sets(&trigrams(s1), &trigrams(s2)))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_f64_batch(pairs: Vec<(String, String)>) -> NifResult<Vec<f64>> {
+    check_input_len(&[&pairs])?;
+    let score = |(s1, s2): &(String, String)| jaccard_from_sets(&trigrams(s1), &trigrams(s2));
+    guard_internal(|| {
+        if below_parallel_threshold(pairs.len()) {
+            pairs.iter().map(score).collect()
+        } else {
+            in_pool(|| pairs.par_iter().map(score).collect())
+        }
+    })
+}
+
+/// Similarity as an integer percentage in `0..=100`, rounded half to even from
+/// the exact trigram counts so every node stores the same integer.
+#[rustler::nif]
+fn similarity_pct(s1: &str, s2: &str) -> NifResult<u8> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| pct_from_sets(&trigrams(s1), &trigrams(s2)))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_pct_batch(pairs: Vec<(String, String)>) -> NifResult<Vec<u8>> {
+    check_input_len(&[&pairs])?;
+    let pct = |(s1, s2): &(String, String)| pct_from_sets(&trigrams(s1), &trigrams(s2));
+    guard_internal(|| {
+        if below_parallel_threshold(pairs.len()) {
+            pairs.iter().map(pct).collect()
+        } else {
+            in_pool(|| pairs.par_iter().map(pct).collect())
+        }
+    })
+}
+
+fn pct_from_sets(a_set: &TrigramHashSet, b_set: &TrigramHashSet) -> u8 {
+    let (shared, total) = overlap_counts(a_set, b_set);
+    pct_from_counts(shared, total)
+}
+
+// Integer arithmetic throughout, so no float rounding can leak in
+fn pct_from_counts(shared: usize, total: usize) -> u8 {
+    if total == 0 {
+        return 0;
+    }
+    let scaled = 100 * shared;
+    let (quotient, remainder) = (scaled / total, scaled % total);
+    let round_up = match (2 * remainder).cmp(&total) {
+        Ordering::Greater => true,
+        Ordering::Equal => quotient % 2 == 1,
+        Ordering::Less => false,
+    };
+    (quotient + usize::from(round_up)) as u8
+}
+
+fn similarity_pairs(pairs: &[(String, String)]) -> Vec<f32> {
+    // HYBRID APPROACH: Sequential for small inputs, Parallel for large
+    if below_parallel_threshold(pairs.len()) {
+        pairs
             .iter()
-            .enumerate()
-            .map(|(idx, haystack)| {
-                let haystack_set = trigrams(haystack);
-                let score = similarity_from_sets(&needle_set, &haystack_set);
-                (idx, score)
-            })
-            .fold(init_acc, |acc, x| if x.1 > acc.1 { x } else { acc })
+            .map(|(s1, s2)| pair_similarity(s1, s2))
+            .collect()
     } else {
-        // Parallel Path (Rayon)
-        haystacks
-            .par_iter()
-            .enumerate()
-            .map(|(idx, haystack)| {
-                let haystack_set = trigrams(haystack);
-                let score = similarity_from_sets(&needle_set, &haystack_set);
-                (idx, score)
-            })
-            .reduce(|| init_acc, |acc, x| if x.1 > acc.1 { x } else { acc })
+        in_pool(|| {
+            pairs
+                .par_iter() // Rayon parallel iterator
+                .with_min_len(min_parallel_chunk())
+                .map(|(s1, s2)| pair_similarity(s1, s2))
+                .collect()
+        })
+    }
+}
+
+/// All-pairs similarity matrix. Each string is trigrammed once and only the
+/// upper triangle is computed, then mirrored.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_matrix(strings: Vec<String>) -> NifResult<Vec<Vec<f32>>> {
+    check_input_len(&[&strings])?;
+    guard_internal(|| similarity_matrix_from_sets(&sorted_trigram_sets(&strings)))
+}
+
+/// `similarity_matrix/1` as one binary of `n * n` little-endian `f32`s, row
+/// by row, ready for `Nx.from_binary(bin, :f32) |> Nx.reshape({n, n})`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_matrix_binary(env: Env<'_>, strings: Vec<String>) -> NifResult<Binary<'_>> {
+    check_input_len(&[&strings])?;
+    let n = strings.len();
+    let mut binary = OwnedBinary::new(n * n * size_of::<f32>()).ok_or(rustler::Error::BadArg)?;
+    guard_internal(|| write_matrix_le(&sorted_trigram_sets(&strings), binary.as_mut_slice()))?;
+    Ok(binary.release(env))
+}
+
+fn trigram_sets(strings: &[String]) -> Vec<TrigramHashSet> {
+    if below_parallel_threshold(strings.len()) {
+        strings.iter().map(|s| trigrams(s)).collect()
+    } else {
+        in_pool(|| strings.par_iter().map(|s| trigrams(s)).collect())
+    }
+}
+
+// All-pairs NIFs compare every set many times, so they intersect
+// `sorted_keys` slices rather than hash sets
+fn sorted_trigram_sets(strings: &[String]) -> Vec<Vec<TrigramKey>> {
+    let sorted = |s: &String| sorted_keys(&trigrams(s));
+    if below_parallel_threshold(strings.len()) {
+        strings.iter().map(sorted).collect()
+    } else {
+        in_pool(|| strings.par_iter().map(sorted).collect())
+    }
+}
+
+fn similarity_matrix_from_sets(sets: &[Vec<TrigramKey>]) -> Vec<Vec<f32>> {
+    let upper = upper_triangle(sets);
+    (0..sets.len())
+        .map(|i| matrix_row(sets, &upper, i).collect())
+        .collect()
+}
+
+/// Writes the `n`×`n` matrix into `out` as row-major little-endian `f32`s,
+/// scoring the upper triangle and diagonal in place, then mirroring it, so
+/// nothing beyond `out` is allocated.
+fn write_matrix_le(sets: &[Vec<TrigramKey>], out: &mut [u8]) {
+    const F32: usize = size_of::<f32>();
+    let n = sets.len();
+    debug_assert_eq!(out.len(), n * n * F32);
+    if n == 0 {
+        return;
+    }
+    let fill_row = |(i, row): (usize, &mut [u8])| {
+        for (j, cell) in row.chunks_exact_mut(F32).enumerate().skip(i) {
+            // Identical sets score 1.0, or 0.0 when empty
+            let score = similarity_from_sorted(&sets[i], &sets[j]);
+            cell.copy_from_slice(&score.to_le_bytes());
+        }
     };
+    if below_parallel_threshold(n) {
+        out.chunks_mut(n * F32).enumerate().for_each(fill_row);
+    } else {
+        in_pool(|| out.par_chunks_mut(n * F32).enumerate().for_each(fill_row));
+    }
 
-    Ok(rustler::types::tuple::make_tuple(
-        env,
-        &[
-            rustler::types::atom::Atom::from_str(env, "ok")?.to_term(env),
-            rustler::types::tuple::make_tuple(env, &[best_idx.encode(env), best_score.encode(env)]),
-        ],
-    ))
+    for i in 1..n {
+        for j in 0..i {
+            let upper = (j * n + i) * F32;
+            out.copy_within(upper..upper + F32, (i * n + j) * F32);
+        }
+    }
+}
+
+// Row `i` holds the scores of set `i` against sets `i + 1..n`
+fn upper_triangle(sets: &[Vec<TrigramKey>]) -> Vec<Vec<f32>> {
+    let upper_row = |i: usize| -> Vec<f32> {
+        sets[i + 1..]
+            .iter()
+            .map(|other| similarity_from_sorted(&sets[i], other))
+            .collect()
+    };
+    if below_parallel_threshold(sets.len()) {
+        (0..sets.len()).map(upper_row).collect()
+    } else {
+        in_pool(|| (0..sets.len()).into_par_iter().map(upper_row).collect())
+    }
+}
+
+/// Row `i` of the full matrix, mirrored from `upper` below the diagonal.
+fn matrix_row<'a>(
+    sets: &'a [Vec<TrigramKey>],
+    upper: &'a [Vec<f32>],
+    i: usize,
+) -> impl Iterator<Item = f32> + 'a {
+    let below = (0..i).map(move |j| upper[j][i - j - 1]);
+    // Identical sets score 1.0, or 0.0 when empty
+    let diagonal = similarity_from_sorted(&sets[i], &sets[i]);
+    below
+        .chain(std::iter::once(diagonal))
+        .chain(upper[i].iter().copied())
+}
+
+/// Groups of near-duplicates: connected components of the graph linking every
+/// pair with similarity >= `threshold`. Each group lists indices ascending,
+/// groups are ordered by their first index, and unmatched strings form
+/// single-element groups.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn cluster(strings: Vec<String>, threshold: f32) -> NifResult<Vec<Vec<usize>>> {
+    check_input_len(&[&strings])?;
+    guard_internal(|| cluster_sets(&sorted_trigram_sets(&strings), threshold))
+}
+
+fn cluster_sets(sets: &[Vec<TrigramKey>], threshold: f32) -> Vec<Vec<usize>> {
+    let row_matches = |i: usize| {
+        (i + 1..sets.len())
+            .filter(move |&j| similarity_from_sorted(&sets[i], &sets[j]) >= threshold)
+    };
+
+    // Edges are unioned row by row as they're found, so at most one row's
+    // worth per worker is ever held, never the whole (up to n^2) edge list
+    let mut components = UnionFind::new(sets.len());
+    if below_parallel_threshold(sets.len()) {
+        for i in 0..sets.len() {
+            for j in row_matches(i) {
+                components.union(i, j);
+            }
+        }
+    } else {
+        // Scoring dominates; unions are cheap enough to take turns on a lock
+        let shared = Mutex::new(components);
+        in_pool(|| {
+            (0..sets.len()).into_par_iter().for_each(|i| {
+                let row: Vec<usize> = row_matches(i).collect();
+                if row.is_empty() {
+                    return;
+                }
+                let mut components = shared.lock().unwrap_or_else(|e| e.into_inner());
+                for j in row {
+                    components.union(i, j);
+                }
+            })
+        });
+        components = shared.into_inner().unwrap_or_else(|e| e.into_inner());
+    }
+
+    let mut group_of_root: FxHashMap<usize, usize> = FxHashMap::default();
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for i in 0..sets.len() {
+        let root = components.find(i);
+        let group = *group_of_root.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(i);
+    }
+    groups
+}
+
+/// Similarity inner join: every `(i, j, score)` with
+/// `similarity(left[i], right[j]) >= threshold`, ordered by `i` then `j`.
+///
+/// Jaccard can't exceed the ratio of the smaller set size to the larger, so
+/// each left string is only scored against right strings whose trigram count
+/// is close enough to its own; the rest are skipped unscored.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn fuzzy_join(
+    left: Vec<String>,
+    right: Vec<String>,
+    threshold: f32,
+) -> NifResult<Vec<(usize, usize, f32)>> {
+    check_input_len(&[&left, &right])?;
+    guard_internal(|| {
+        join_sets(
+            &sorted_trigram_sets(&left),
+            &sorted_trigram_sets(&right),
+            threshold,
+        )
+    })
 }
 
+/// How many pairs `fuzzy_join/3` would return, counted without building
+/// them, to size a join before running it.
 #[rustler::nif(schedule = "DirtyCpu")]
-fn score_all(needle: &str, haystacks: Vec<String>, min_threshold: f32) -> Vec<(usize, f32)> {
-    let needle_set = trigrams(needle);
+fn fuzzy_join_count(left: Vec<String>, right: Vec<String>, threshold: f32) -> NifResult<u64> {
+    check_input_len(&[&left, &right])?;
+    guard_internal(|| {
+        join_count(
+            &sorted_trigram_sets(&left),
+            &sorted_trigram_sets(&right),
+            threshold,
+        )
+    })
+}
+
+/// Right-hand sets of a join, indexed by set size so each left set only
+/// scans the contiguous size range that could reach the threshold.
+struct JoinIndex<'a> {
+    right: &'a [Vec<TrigramKey>],
+    by_size: Vec<usize>,
+    threshold: f32,
+}
+
+impl<'a> JoinIndex<'a> {
+    fn new(right: &'a [Vec<TrigramKey>], threshold: f32) -> Self {
+        let mut by_size: Vec<usize> = (0..right.len()).collect();
+        by_size.sort_unstable_by_key(|&j| right[j].len());
+        JoinIndex {
+            right,
+            by_size,
+            threshold,
+        }
+    }
+
+    /// `(j, score)` for every right set `left_set` matches, in size order.
+    fn matches<'s>(
+        &'s self,
+        left_set: &'s [TrigramKey],
+    ) -> impl Iterator<Item = (usize, f32)> + 's {
+        let ratio_fails = |small: usize, large: usize| {
+            (jaccard_from_counts(small, large) as f32) < self.threshold
+        };
+        let size = left_set.len();
+        let len = |j: usize| self.right[j].len();
+        let start = self
+            .by_size
+            .partition_point(|&j| len(j) < size && ratio_fails(len(j), size));
+        let end = self
+            .by_size
+            .partition_point(|&j| len(j) <= size || !ratio_fails(size, len(j)));
+        self.by_size[start..end].iter().filter_map(move |&j| {
+            let score = similarity_from_sorted(left_set, &self.right[j]);
+            (score >= self.threshold).then_some((j, score))
+        })
+    }
+}
+
+fn join_sets(
+    left: &[Vec<TrigramKey>],
+    right: &[Vec<TrigramKey>],
+    threshold: f32,
+) -> Vec<(usize, usize, f32)> {
+    let index = JoinIndex::new(right, threshold);
+    let row_matches = |i: usize| -> Vec<(usize, usize, f32)> {
+        let mut matches: Vec<(usize, usize, f32)> = index
+            .matches(&left[i])
+            .map(|(j, score)| (i, j, score))
+            .collect();
+        matches.sort_unstable_by_key(|&(_, j, _)| j);
+        matches
+    };
+
+    if below_parallel_threshold(left.len()) {
+        (0..left.len()).flat_map(row_matches).collect()
+    } else {
+        in_pool(|| {
+            (0..left.len())
+                .into_par_iter()
+                .flat_map_iter(row_matches)
+                .collect()
+        })
+    }
+}
+
+fn join_count(left: &[Vec<TrigramKey>], right: &[Vec<TrigramKey>], threshold: f32) -> u64 {
+    let index = JoinIndex::new(right, threshold);
+    let row_count = |left_set: &Vec<TrigramKey>| index.matches(left_set).count() as u64;
+    if below_parallel_threshold(left.len()) {
+        left.iter().map(row_count).sum()
+    } else {
+        in_pool(|| left.par_iter().map(row_count).sum())
+    }
+}
+
+/// Disjoint-set forest with path halving and union by size.
+struct UnionFind {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl UnionFind {
+    fn new(len: usize) -> Self {
+        UnionFind {
+            parent: (0..len).collect(),
+            size: vec![1; len],
+        }
+    }
+
+    fn find(&mut self, mut x: usize) -> usize {
+        while self.parent[x] != x {
+            self.parent[x] = self.parent[self.parent[x]];
+            x = self.parent[x];
+        }
+        x
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        if self.size[a] < self.size[b] {
+            std::mem::swap(&mut a, &mut b);
+        }
+        self.parent[b] = a;
+        self.size[a] += self.size[b];
+    }
+}
+
+/// Trigram set computed once by `prepare/1` and reused across calls.
+///
+/// Immutable after construction, so it is safe to share between processes.
+struct TrigramSet {
+    set: TrigramHashSet,
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for TrigramSet {}
+
+#[rustler::nif]
+fn prepare(text: &str) -> NifResult<ResourceArc<TrigramSet>> {
+    check_input_len(&[&text])?;
+    guard_internal(|| {
+        ResourceArc::new(TrigramSet {
+            set: trigrams(text),
+        })
+    })
+}
+
+#[rustler::nif]
+fn similarity_precomputed(set: ResourceArc<TrigramSet>, other: &str) -> NifResult<f32> {
+    check_input_len(&[&other])?;
+    guard_internal(|| similarity_from_sets(&set.set, &trigrams(other)))
+}
+
+/// Similarity of two prepared sets, neither string re-trigrammed.
+#[rustler::nif]
+fn similarity_sets(
+    set_a: ResourceArc<TrigramSet>,
+    set_b: ResourceArc<TrigramSet>,
+) -> NifResult<f32> {
+    guard_internal(|| similarity_from_sets(&set_a.set, &set_b.set))
+}
+
+/// `{shared_count, union_count, similarity}`, so callers can judge how many
+/// trigrams a score is based on.
+#[rustler::nif]
+fn similarity_detailed(s1: &str, s2: &str) -> NifResult<(usize, usize, f32)> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| detailed_counts(s1, s2))
+}
+
+fn detailed_counts(s1: &str, s2: &str) -> (usize, usize, f32) {
+    let (shared, total) = overlap_counts(&trigrams(s1), &trigrams(s2));
+    (shared, total, jaccard_from_counts(shared, total) as f32)
+}
+
+/// `similarity_detailed/2` for every pair, in order.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_detailed_batch(pairs: Vec<(String, String)>) -> NifResult<Vec<(u32, u32, f32)>> {
+    check_input_len(&[&pairs])?;
+    guard_internal(|| detailed_pairs(&pairs))
+}
+
+fn detailed_pairs(pairs: &[(String, String)]) -> Vec<(u32, u32, f32)> {
+    let detailed = |(s1, s2): &(String, String)| {
+        let (shared, total, score) = detailed_counts(s1, s2);
+        (shared as u32, total as u32, score)
+    };
+    if below_parallel_threshold(pairs.len()) {
+        pairs.iter().map(detailed).collect()
+    } else {
+        in_pool(|| {
+            pairs
+                .par_iter()
+                .with_min_len(min_parallel_chunk())
+                .map(detailed)
+                .collect()
+        })
+    }
+}
+
+/// Cosine similarity over trigram frequency vectors, so repeated trigrams count.
+#[rustler::nif]
+fn cosine_similarity(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| cosine_from_counts(&trigram_counts(s1), &trigram_counts(s2)))
+}
+
+/// Overlap (Szymkiewicz–Simpson) coefficient: `|A ∩ B| / min(|A|, |B|)`.
+///
+/// Unlike Jaccard, a short needle fully contained in a long haystack scores 1.0.
+#[rustler::nif]
+fn overlap_similarity(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| overlap_from_sets(&trigrams(s1), &trigrams(s2)))
+}
+
+/// Sørensen–Dice coefficient: `2|A ∩ B| / (|A| + |B|)`. Ranks like Jaccard
+/// but scores higher, so thresholds don't carry over.
+#[rustler::nif]
+fn dice_similarity(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| dice_from_sets(&trigrams(s1), &trigrams(s2)))
+}
+
+/// Tversky index: `|A ∩ B| / (|A ∩ B| + alpha |A − B| + beta |B − A|)`.
+/// `alpha = beta = 1` is `similarity`, `0.5` is `dice_similarity`. Raises
+/// `ArgumentError` for a negative or NaN weight.
+#[rustler::nif]
+fn tversky_similarity(s1: &str, s2: &str, alpha: f64, beta: f64) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    if !(alpha >= 0.0 && beta >= 0.0) {
+        return Err(rustler::Error::BadArg);
+    }
+    guard_internal(|| tversky_from_sets(&trigrams(s1), &trigrams(s2), alpha, beta))
+}
+
+fn tversky_from_sets(a_set: &TrigramHashSet, b_set: &TrigramHashSet, alpha: f64, beta: f64) -> f32 {
+    let (shared, _) = overlap_counts(a_set, b_set);
+    if shared == 0 {
+        return 0.0;
+    }
+    let (only_a, only_b) = (a_set.len() - shared, b_set.len() - shared);
+    let shared = shared as f64;
+    (shared / (shared + alpha * only_a as f64 + beta * only_b as f64)) as f32
+}
+
+/// Trigram similarity, except that when either string has fewer than
+/// `HYBRID_MIN_CHARS` word characters (default 4) it falls back to
+/// Jaro-Winkler over the normalized words (joined by single spaces), where
+/// padding would otherwise dominate the trigrams.
+#[rustler::nif]
+fn hybrid_similarity(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| hybrid(s1, s2))
+}
+
+/// Sets the word-character count below which `hybrid_similarity` uses
+/// Jaro-Winkler. `0` disables the fallback; above 256 raises `ArgumentError`.
+#[rustler::nif]
+fn set_hybrid_min_chars(min_chars: usize) -> NifResult<rustler::Atom> {
+    if min_chars > MAX_HYBRID_MIN_CHARS {
+        return Err(rustler::Error::BadArg);
+    }
+    HYBRID_MIN_CHARS.store(min_chars, AtomicOrdering::Relaxed);
+    Ok(rustler::types::atom::ok())
+}
+
+fn hybrid(s1: &str, s2: &str) -> f32 {
+    let (n1, n2) = (pg_downcase(s1), pg_downcase(s2));
+    let min_chars = HYBRID_MIN_CHARS.load(AtomicOrdering::Relaxed);
+    if word_char_count(&n1) < min_chars || word_char_count(&n2) < min_chars {
+        // Only word chars, so long punctuation runs can't make the short
+        // side long
+        jaro_winkler(&word_text(&n1), &word_text(&n2))
+    } else {
+        similarity_from_sets(
+            &ngrams_from_normalized(&n1, 3),
+            &ngrams_from_normalized(&n2, 3),
+        )
+    }
+}
+
+fn word_text(normalized: &str) -> String {
+    let words: Vec<&str> = WORD_RE
+        .find_iter(normalized)
+        .map(|mat| mat.as_str())
+        .collect();
+    words.join(" ")
+}
+
+fn word_char_count(normalized: &str) -> usize {
+    WORD_RE
+        .find_iter(normalized)
+        .map(|mat| mat.as_str().chars().count())
+        .sum()
+}
+
+/// Jaro-Winkler similarity with the usual prefix scale of 0.1 over at most 4
+/// leading chars. Two empty strings score 0.0, like trigram similarity.
+fn jaro_winkler(s1: &str, s2: &str) -> f32 {
+    let (a, b): (Vec<char>, Vec<char>) = (s1.chars().collect(), s2.chars().collect());
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+
+    let window = (a.len().max(b.len()) / 2).saturating_sub(1);
+    let mut b_matched = vec![false; b.len()];
+    let mut a_matches = Vec::with_capacity(a.len());
+    for (i, &ch) in a.iter().enumerate() {
+        let lo = i.saturating_sub(window);
+        let hi = (i + window + 1).min(b.len());
+        if let Some(j) = (lo..hi).find(|&j| !b_matched[j] && b[j] == ch) {
+            b_matched[j] = true;
+            a_matches.push(ch);
+        }
+    }
+    if a_matches.is_empty() {
+        return 0.0;
+    }
+
+    let b_matches = b
+        .iter()
+        .zip(&b_matched)
+        .filter(|(_, &hit)| hit)
+        .map(|(&ch, _)| ch);
+    let transpositions = a_matches
+        .iter()
+        .zip(b_matches)
+        .filter(|(x, y)| **x != *y)
+        .count();
+
+    let m = a_matches.len() as f64;
+    let jaro =
+        (m / a.len() as f64 + m / b.len() as f64 + (m - transpositions as f64 / 2.0) / m) / 3.0;
+    let prefix = a.iter().zip(&b).take(4).take_while(|(x, y)| x == y).count();
+    (jaro + prefix as f64 * 0.1 * (1.0 - jaro)) as f32
+}
+
+/// Levenshtein distance in chars between the `pg_downcase`-normalized forms.
+/// Raises `:input_too_long` if either has more than `MAX_EDIT_CHARS` (10,000)
+/// chars, since the cost is their product.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn levenshtein(s1: &str, s2: &str) -> NifResult<usize> {
+    check_input_len(&[&s1, &s2])?;
+    let (n1, n2) = (pg_downcase(s1), pg_downcase(s2));
+    check_edit_len(&n1, &n2)?;
+    guard_internal(|| edit_distance(&n1, &n2))
+}
+
+fn check_edit_len(n1: &str, n2: &str) -> NifResult<()> {
+    if exceeds_edit_chars(n1) || exceeds_edit_chars(n2) {
+        return Err(rustler::Error::RaiseAtom("input_too_long"));
+    }
+    Ok(())
+}
+
+fn exceeds_edit_chars(text: &str) -> bool {
+    // Byte length bounds the char count, so most inputs skip the count
+    text.len() > MAX_EDIT_CHARS && text.chars().count() > MAX_EDIT_CHARS
+}
+
+/// `alpha * similarity + (1 - alpha) * edit_similarity`, for breaking ties
+/// between candidates with the same trigram overlap. Edit similarity is
+/// `1 - levenshtein / max(len1, len2)` in chars, and 1.0 for two empty
+/// strings. Raises `ArgumentError` unless `alpha` is within `0.0..=1.0`, and
+/// `:input_too_long` on the same inputs as `levenshtein/2`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn combined_score(s1: &str, s2: &str, alpha: f32) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    if !(0.0..=1.0).contains(&alpha) {
+        return Err(rustler::Error::BadArg);
+    }
+    let (n1, n2) = (pg_downcase(s1), pg_downcase(s2));
+    check_edit_len(&n1, &n2)?;
+    guard_internal(|| combined(&n1, &n2, alpha))
+}
+
+// `combined_score` over already-normalized text
+fn combined(n1: &str, n2: &str, alpha: f32) -> f32 {
+    let trigram = similarity_from_sets(
+        &ngrams_from_normalized(n1, 3),
+        &ngrams_from_normalized(n2, 3),
+    );
+    let alpha = alpha as f64;
+    (alpha * trigram as f64 + (1.0 - alpha) * edit_similarity(n1, n2)) as f32
+}
+
+fn edit_similarity(s1: &str, s2: &str) -> f64 {
+    let longest = s1.chars().count().max(s2.chars().count());
+    if longest == 0 {
+        return 1.0;
+    }
+    1.0 - edit_distance(s1, s2) as f64 / longest as f64
+}
+
+// Two-row Wagner-Fischer over chars
+fn edit_distance(s1: &str, s2: &str) -> usize {
+    let b: Vec<char> = s2.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    let mut curr = vec![0; b.len() + 1];
+
+    for (i, ca) in s1.chars().enumerate() {
+        curr[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != cb);
+            curr[j + 1] = substitution.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+/// Similarity that favours matches at the start: the first word of each string
+/// is padded with a start-of-string marker instead of spaces, so `"app"`
+/// shares its opening trigrams with `"apple"` but not with `"my apple"`.
+#[rustler::nif]
+fn prefix_similarity(needle: &str, haystack: &str) -> NifResult<f32> {
+    check_input_len(&[&needle, &haystack])?;
+    guard_internal(|| similarity_from_sets(&prefix_trigrams(needle), &prefix_trigrams(haystack)))
+}
+
+/// Similarity ignoring words shorter than `min_len` chars, so one- and
+/// two-letter tokens don't contribute their mostly-padding trigrams. `0` and
+/// `1` keep every word; a side left with no words scores 0.0.
+#[rustler::nif]
+fn similarity_minword(s1: &str, s2: &str, min_len: usize) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| {
+        similarity_from_sets(
+            &trigrams_minword(s1, min_len),
+            &trigrams_minword(s2, min_len),
+        )
+    })
+}
+
+fn trigrams_minword(text: &str, min_len: usize) -> TrigramHashSet {
+    let normalized = pg_downcase(text);
+    let kept: Vec<&str> = WORD_RE
+        .find_iter(&normalized)
+        .map(|mat| mat.as_str())
+        .filter(|word| word.chars().count() >= min_len)
+        .collect();
+    ngrams_from_normalized(&kept.join(" "), 3)
+}
+
+/// Similarity with runs of single-char words joined into one word first, so
+/// initialisms like `"U S A"` and `"U.S.A."` trigram as `"usa"`. Only
+/// whitespace and periods may separate the joined chars.
+#[rustler::nif]
+fn similarity_joinshort(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| similarity_from_sets(&trigrams_joinshort(s1), &trigrams_joinshort(s2)))
+}
+
+fn trigrams_joinshort(text: &str) -> TrigramHashSet {
+    let normalized = pg_downcase(text);
+    let mut words: Vec<String> = Vec::new();
+    // Whether the last word is a run of single chars that may grow
+    let mut joining = false;
+    let mut prev_end = 0;
+
+    for mat in WORD_RE.find_iter(&normalized) {
+        let single = mat.as_str().chars().count() == 1;
+        let gap = &normalized[prev_end..mat.start()];
+        let joins = single && joining && gap.chars().all(|c| c.is_whitespace() || c == '.');
+        match words.last_mut() {
+            Some(run) if joins => run.push_str(mat.as_str()),
+            _ => words.push(mat.as_str().to_string()),
+        }
+        joining = single;
+        prev_end = mat.end();
+    }
+    ngrams_from_normalized(&words.join(" "), 3)
+}
+
+/// Similarity with each set cut off at its first `max_trigrams` distinct
+/// trigrams, taken word by word in text order, so a pathological input
+/// (say one 50KB token) costs bounded memory. Inputs under the cap score
+/// exactly as `similarity/2`; past it the score only approximates the
+/// uncapped one and can land on either side of it.
+#[rustler::nif]
+fn similarity_capped(s1: &str, s2: &str, max_trigrams: usize) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| {
+        similarity_from_sets(
+            &trigrams_capped(s1, max_trigrams),
+            &trigrams_capped(s2, max_trigrams),
+        )
+    })
+}
+
+fn trigrams_capped(text: &str, max_trigrams: usize) -> TrigramHashSet {
+    let normalized = pg_downcase(text);
+    let mut set = TrigramHashSet::with_capacity_and_hasher(
+        ngram_capacity(&normalized).min(max_trigrams),
+        Default::default(),
+    );
+    if max_trigrams == 0 {
+        return set;
+    }
+    for word in WORD_RE.find_iter(&normalized) {
+        // Slide over the padded word instead of buffering it, so memory
+        // stays bounded by the cap however long the word is
+        let mut window = [' '; 3];
+        for c in word.as_str().chars().chain([' ']) {
+            window = [window[1], window[2], c];
+            set.insert(compact_ngram(&window));
+            if set.len() == max_trigrams {
+                return set;
+            }
+        }
+    }
+    set
+}
+
+/// Similarity with `leading` and `trailing` spaces (0 to 2 each) padding
+/// every word instead of pg_trgm's 2 and 1. Fewer pads mean fewer boundary
+/// trigrams, so scores put more weight on word interiors.
+///
+/// The pad is always a space: words never contain whitespace or punctuation,
+/// so any other non-word pad would only rename the boundary trigrams.
+#[rustler::nif]
+fn similarity_padded(s1: &str, s2: &str, leading: usize, trailing: usize) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| padded_similarity(s1, s2, leading, trailing))?
+}
+
+fn padded_similarity(s1: &str, s2: &str, leading: usize, trailing: usize) -> NifResult<f32> {
+    if leading > 2 || trailing > 2 {
+        return Err(rustler::Error::BadArg);
+    }
+    Ok(similarity_from_sets(
+        &trigrams_padded(s1, leading, trailing),
+        &trigrams_padded(s2, leading, trailing),
+    ))
+}
+
+fn trigrams_padded(text: &str, leading: usize, trailing: usize) -> TrigramHashSet {
+    let normalized = pg_downcase(text);
+    let mut set =
+        TrigramHashSet::with_capacity_and_hasher(ngram_capacity(&normalized), Default::default());
+    let mut char_buf: Vec<char> = Vec::with_capacity(64);
+
+    for mat in WORD_RE.find_iter(&normalized) {
+        char_buf.clear();
+        char_buf.extend(std::iter::repeat_n(' ', leading));
+        char_buf.extend(mat.as_str().chars());
+        char_buf.extend(std::iter::repeat_n(' ', trailing));
+
+        for window in char_buf.windows(3) {
+            set.insert(compact_ngram(window));
+        }
+    }
+    set
+}
+
+/// Whitespace-sensitive similarity: besides the usual word trigrams, every
+/// whitespace run adds trigrams of its exact content, so `"a  b"` and
+/// `"a b"` (or a tab and a space) no longer match perfectly.
+#[rustler::nif]
+fn similarity_ws(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| similarity_from_sets(&trigrams_ws(s1), &trigrams_ws(s2)))
+}
+
+// Delimits whitespace runs in `trigrams_ws`; can't be whitespace itself
+const WHITESPACE_MARK: char = '\u{3}';
+
+fn trigrams_ws(text: &str) -> TrigramHashSet {
+    let mut set = trigrams(text);
+    let mut char_buf: Vec<char> = Vec::with_capacity(16);
+
+    for mat in WHITESPACE_RE.find_iter(text) {
+        char_buf.clear();
+        char_buf.push(WHITESPACE_MARK);
+        char_buf.extend(mat.as_str().chars());
+        char_buf.push(WHITESPACE_MARK);
+
+        for window in char_buf.windows(3) {
+            set.insert(compact_ngram(window));
+        }
+    }
+    set
+}
+
+/// Similarity with every decimal digit replaced by `0` first, so codes that
+/// differ only in their numbers (`"ABC-123"`, `"ABC-456"`) match fully. Digit
+/// runs of three or more then yield the same trigrams whatever their length.
+#[rustler::nif]
+fn similarity_digitclass(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| similarity_from_sets(&trigrams_digitclass(s1), &trigrams_digitclass(s2)))
+}
+
+fn trigrams_digitclass(text: &str) -> TrigramHashSet {
+    ngrams_from_normalized(&DIGIT_RE.replace_all(&pg_downcase(text), "0"), 3)
+}
+
+/// Weighted Jaccard similarity where trigrams touching a word's start or end
+/// (those holding padding) weigh twice as much as interior ones: the summed
+/// minimum weight of each trigram over the summed maximum. Differences at
+/// the edges of a word, such as a surname's first letters, cost more than
+/// the same edit in the middle.
+#[rustler::nif]
+fn weighted_similarity(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| weighted_jaccard(&trigram_weights(s1), &trigram_weights(s2)))
+}
+
+/// Trigrams of `text` with their `weighted_similarity` weight; a trigram
+/// seen in several positions keeps its largest weight.
+fn trigram_weights(text: &str) -> FxHashMap<TrigramKey, f64> {
+    let normalized = pg_downcase(text);
+    let mut weights =
+        FxHashMap::with_capacity_and_hasher(ngram_capacity(&normalized), Default::default());
+    let mut char_buf: Vec<char> = Vec::with_capacity(64);
+
+    for mat in WORD_RE.find_iter(&normalized) {
+        char_buf.clear();
+        char_buf.extend([' ', ' ']);
+        char_buf.extend(mat.as_str().chars());
+        char_buf.push(' ');
+
+        for window in char_buf.windows(3) {
+            let weight = if window.contains(&' ') {
+                BOUNDARY_WEIGHT
+            } else {
+                1.0
+            };
+            let entry = weights.entry(compact_ngram(window)).or_insert(weight);
+            *entry = entry.max(weight);
+        }
+    }
+    weights
+}
+
+fn weighted_jaccard(
+    a_weights: &FxHashMap<TrigramKey, f64>,
+    b_weights: &FxHashMap<TrigramKey, f64>,
+) -> f32 {
+    // min + max = a + b, so the union's max-sum follows from the shared min-sum
+    let shared: f64 = a_weights
+        .iter()
+        .filter_map(|(key, &a)| b_weights.get(key).map(|&b| a.min(b)))
+        .sum();
+    let total = a_weights.values().sum::<f64>() + b_weights.values().sum::<f64>() - shared;
+    if total == 0.0 {
+        0.0
+    } else {
+        (shared / total) as f32
+    }
+}
+
+/// Similarity over trigrams shingled across word boundaries: words are
+/// joined by single spaces and padded once as a whole, so trigrams spanning
+/// two words record their order. `"john smith"` and `"smith john"` score 1.0
+/// under `similarity` but less here.
+#[rustler::nif]
+fn ordered_similarity(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| ordered_pair_similarity(s1, s2))
+}
+
+fn ordered_pair_similarity(s1: &str, s2: &str) -> f32 {
+    similarity_from_sets(&ordered_trigrams(s1), &ordered_trigrams(s2))
+}
+
+fn ordered_trigrams(text: &str) -> TrigramHashSet {
+    let normalized = pg_downcase(text);
+    let mut char_buf: Vec<char> = Vec::with_capacity(normalized.len() + 3);
+    for mat in WORD_RE.find_iter(&normalized) {
+        // Two pads before the first word, one space between the others
+        char_buf.extend(if char_buf.is_empty() { "  " } else { " " }.chars());
+        char_buf.extend(mat.as_str().chars());
+    }
+    if char_buf.is_empty() {
+        return TrigramHashSet::default();
+    }
+    char_buf.push(' ');
+    char_buf.windows(3).map(compact_ngram).collect()
+}
+
+/// How words are padded, decoded from `:per_word` and `:whole_string`.
+#[derive(rustler::NifUnitEnum, Clone, Copy, Debug, PartialEq)]
+enum BoundaryMode {
+    /// Each word padded on its own, as pg_trgm does
+    PerWord,
+    /// The whole string padded once, single spaces kept between words
+    WholeString,
+}
+
+/// Similarity under the given padding `mode`. `:per_word` is exactly
+/// `similarity/2`; `:whole_string` is `ordered_similarity/2`, whose trigrams
+/// span word boundaries.
+#[rustler::nif]
+fn similarity_boundary(s1: &str, s2: &str, mode: BoundaryMode) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| boundary_similarity(s1, s2, mode))
+}
+
+fn boundary_similarity(s1: &str, s2: &str, mode: BoundaryMode) -> f32 {
+    match mode {
+        BoundaryMode::PerWord => pair_similarity(s1, s2),
+        BoundaryMode::WholeString => ordered_pair_similarity(s1, s2),
+    }
+}
+
+/// Case-sensitive similarity: skips `pg_downcase`, so `"ABC"` and `"abc"`
+/// share no trigrams.
+#[rustler::nif]
+fn similarity_cs(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| similarity_from_sets(&trigrams_cs(s1), &trigrams_cs(s2)))
+}
+
+/// Like `similarity`, but the combining dot above (\u{0307}) is kept instead
+/// of being stripped for pg_trgm parity.
+#[rustler::nif]
+fn similarity_raw(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| similarity_from_sets(&trigrams_raw(s1), &trigrams_raw(s2)))
+}
+
+/// Like `similarity`, but both inputs are NFC-normalized first. Off by default
+/// because Postgres compares code points as stored.
+#[rustler::nif]
+fn similarity_normalized(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| similarity_from_sets(&trigrams_nfc(s1), &trigrams_nfc(s2)))
+}
+
+/// Word tokenization modes, decoded from the atoms `:alnum`, `:word` and
+/// `:whitespace`.
+#[derive(rustler::NifUnitEnum, Clone, Copy)]
+enum WordPattern {
+    /// `[\p{L}\p{N}]+`, the pg_trgm default
+    Alnum,
+    /// `\w+`: also keeps underscores (and other connector punctuation) inside
+    /// words, for code identifiers
+    Word,
+    /// `\S+`: only whitespace separates words, so `.` and `-` are kept too
+    Whitespace,
+}
+
+impl WordPattern {
+    fn regex(self) -> &'static Regex {
+        match self {
+            WordPattern::Alnum => &WORD_RE,
+            WordPattern::Word => &IDENT_WORD_RE,
+            WordPattern::Whitespace => &NON_SPACE_RE,
+        }
+    }
+}
+
+/// Similarity with words split by `pattern` instead of the default
+/// alphanumeric runs. `:alnum` is identical to `similarity/2`.
+#[rustler::nif]
+fn similarity_with_pattern(s1: &str, s2: &str, pattern: WordPattern) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| {
+        similarity_from_sets(
+            &trigrams_with_pattern(s1, pattern),
+            &trigrams_with_pattern(s2, pattern),
+        )
+    })
+}
+
+/// `similarity/2` over raw binaries: returns `{:ok, score}`, or
+/// `{:error, :invalid_utf8}` if either input is not valid UTF-8.
+///
+/// Both inputs are validated in place and lowercased into one reused buffer,
+/// so megabyte-scale documents are never copied into an owned `String`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_bin<'a>(env: Env<'a>, b1: Binary<'a>, b2: Binary<'a>) -> NifResult<Term<'a>> {
+    check_input_len(&[&b1, &b2])?;
+    let (tag, value) = match catch_internal(|| similarity_bytes(b1.as_slice(), b2.as_slice())) {
+        Ok(Ok(score)) => ("ok", score.encode(env)),
+        Ok(Err(_)) => (
+            "error",
+            rustler::types::atom::Atom::from_str(env, "invalid_utf8")?.to_term(env),
+        ),
+        Err(reason) => (
+            "error",
+            rustler::types::atom::Atom::from_str(env, reason)?.to_term(env),
+        ),
+    };
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            rustler::types::atom::Atom::from_str(env, tag)?.to_term(env),
+            value,
+        ],
+    ))
+}
+
+/// `similarity/2`, but returns `{:error, :no_trigrams}` instead of `0.0` when
+/// either side has no word content (empty, whitespace or punctuation only).
+#[rustler::nif]
+fn similarity_checked<'a>(env: Env<'a>, s1: &str, s2: &str) -> NifResult<Term<'a>> {
+    check_input_len(&[&s1, &s2])?;
+    let (tag, value) = match catch_internal(|| checked_similarity(s1, s2)) {
+        Ok(Some(score)) => ("ok", score.encode(env)),
+        Ok(None) => (
+            "error",
+            rustler::types::atom::Atom::from_str(env, "no_trigrams")?.to_term(env),
+        ),
+        Err(reason) => (
+            "error",
+            rustler::types::atom::Atom::from_str(env, reason)?.to_term(env),
+        ),
+    };
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            rustler::types::atom::Atom::from_str(env, tag)?.to_term(env),
+            value,
+        ],
+    ))
+}
+
+fn checked_similarity(s1: &str, s2: &str) -> Option<f32> {
+    let (s1_set, s2_set) = (trigrams(s1), trigrams(s2));
+    if s1_set.is_empty() || s2_set.is_empty() {
+        None
+    } else {
+        Some(similarity_from_sets(&s1_set, &s2_set))
+    }
+}
+
+/// Like `similarity_checked/2`, but the error names the empty side:
+/// `{:error, {:no_trigrams, :left | :right | :both}}`.
+#[rustler::nif]
+fn similarity_strict<'a>(env: Env<'a>, s1: &str, s2: &str) -> NifResult<Term<'a>> {
+    check_input_len(&[&s1, &s2])?;
+    let (tag, value) = match catch_internal(|| strict_similarity(s1, s2)) {
+        Ok(Ok(score)) => ("ok", score.encode(env)),
+        Err(reason) => (
+            "error",
+            rustler::types::atom::Atom::from_str(env, reason)?.to_term(env),
+        ),
+        Ok(Err(side)) => (
+            "error",
+            rustler::types::tuple::make_tuple(
+                env,
+                &[
+                    rustler::types::atom::Atom::from_str(env, "no_trigrams")?.to_term(env),
+                    rustler::types::atom::Atom::from_str(env, side.name())?.to_term(env),
+                ],
+            ),
+        ),
+    };
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            rustler::types::atom::Atom::from_str(env, tag)?.to_term(env),
+            value,
+        ],
+    ))
+}
+
+/// Which input of a pair produced no trigrams.
+#[derive(Debug, PartialEq)]
+enum EmptySide {
+    Left,
+    Right,
+    Both,
+}
+
+impl EmptySide {
+    fn name(&self) -> &'static str {
+        match self {
+            EmptySide::Left => "left",
+            EmptySide::Right => "right",
+            EmptySide::Both => "both",
+        }
+    }
+}
+
+fn strict_similarity(s1: &str, s2: &str) -> Result<f32, EmptySide> {
+    let (s1_set, s2_set) = (trigrams(s1), trigrams(s2));
+    match (s1_set.is_empty(), s2_set.is_empty()) {
+        (true, true) => Err(EmptySide::Both),
+        (true, false) => Err(EmptySide::Left),
+        (false, true) => Err(EmptySide::Right),
+        (false, false) => Ok(similarity_from_sets(&s1_set, &s2_set)),
+    }
+}
+
+fn similarity_bytes(b1: &[u8], b2: &[u8]) -> Result<f32, std::str::Utf8Error> {
+    let (s1, s2) = (std::str::from_utf8(b1)?, std::str::from_utf8(b2)?);
+
+    let mut buf = String::with_capacity(s1.len().max(s2.len()));
+    downcase_into(s1, true, &mut buf);
+    let set1 = ngrams_from_normalized(&buf, 3);
+    downcase_into(s2, true, &mut buf);
+    let set2 = ngrams_from_normalized(&buf, 3);
+
+    Ok(similarity_from_sets(&set1, &set2))
+}
+
+/// Similarity over extended grapheme clusters instead of chars, so a ZWJ
+/// emoji sequence, flag or skin-toned emoji is one unit of a trigram. Emoji
+/// count as word characters here. Off by default for pg_trgm parity.
+#[rustler::nif]
+fn similarity_graphemes(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| similarity_from_sets(&trigrams_graphemes(s1), &trigrams_graphemes(s2)))
+}
+
+/// Accent-insensitive similarity: all nonspacing marks are removed after NFD
+/// decomposition. Off by default for pg_trgm parity.
+#[rustler::nif]
+fn similarity_unaccent(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| similarity_from_sets(&trigrams_unaccent(s1), &trigrams_unaccent(s2)))
+}
+
+/// Trigram distance, `1.0 - similarity`, like pg_trgm's `<->` operator.
+#[rustler::nif]
+fn distance(s1: &str, s2: &str) -> NifResult<f32> {
+    check_input_len(&[&s1, &s2])?;
+    guard_internal(|| distance_from_sets(&trigrams(s1), &trigrams(s2)))
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn distance_batch(pairs: Vec<(String, String)>) -> NifResult<Vec<f32>> {
+    check_input_len(&[&pairs])?;
+    guard_internal(|| {
+        if below_parallel_threshold(pairs.len()) {
+            pairs
+                .iter()
+                .map(|(s1, s2)| distance_from_sets(&trigrams(s1), &trigrams(s2)))
+                .collect()
+        } else {
+            in_pool(|| {
+                pairs
+                    .par_iter()
+                    .map(|(s1, s2)| distance_from_sets(&trigrams(s1), &trigrams(s2)))
+                    .collect()
+            })
+        }
+    })
+}
+
+/// Index and score of the most similar haystack. Ties go to the lowest index
+/// on both the sequential and parallel paths.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_match<'a>(env: Env<'a>, needle: &str, haystacks: Vec<String>) -> NifResult<Term<'a>> {
+    check_input_len(&[&needle, &haystacks])?;
+    match catch_internal(|| crate::core::best_match(needle, &haystacks)) {
+        Ok(Some(best)) => encode_best_match(env, best),
+        Ok(None) => encode_empty_list(env),
+        Err(reason) => encode_error(env, reason),
+    }
+}
+
+/// `TieBreak`, decoded from `:first`, `:longest` and `:shortest`.
+#[derive(rustler::NifUnitEnum, Clone, Copy)]
+enum TieBreakArg {
+    First,
+    Longest,
+    Shortest,
+}
+
+impl From<TieBreakArg> for TieBreak {
+    fn from(arg: TieBreakArg) -> Self {
+        match arg {
+            TieBreakArg::First => TieBreak::First,
+            TieBreakArg::Longest => TieBreak::Longest,
+            TieBreakArg::Shortest => TieBreak::Shortest,
+        }
+    }
+}
+
+/// `best_match/3`: like `best_match/2`, but breaks ties at the top score by
+/// `tie_break` (`:first`, `:longest` or `:shortest` haystack, counted in
+/// chars) before falling back to the lowest index.
+#[rustler::nif(name = "best_match", schedule = "DirtyCpu")]
+fn best_match_tie_break<'a>(
+    env: Env<'a>,
+    needle: &str,
+    haystacks: Vec<String>,
+    tie_break: TieBreakArg,
+) -> NifResult<Term<'a>> {
+    check_input_len(&[&needle, &haystacks])?;
+    if haystacks.is_empty() {
+        return encode_empty_list(env);
+    }
+
+    match catch_internal(|| best_match_by(&trigrams(needle), &haystacks, tie_break.into())) {
+        Ok(best) => encode_best_match(env, best),
+        Err(reason) => encode_error(env, reason),
+    }
+}
+
+/// `{:ok, {score, indices}}` with the top score and every index reaching it,
+/// ascending, instead of the single pick `best_match/2` makes.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_match_all_ties<'a>(
+    env: Env<'a>,
+    needle: &str,
+    haystacks: Vec<String>,
+) -> NifResult<Term<'a>> {
+    check_input_len(&[&needle, &haystacks])?;
+    if haystacks.is_empty() {
+        return encode_empty_list(env);
+    }
+
+    let (score, indices) =
+        match catch_internal(|| best_ties_from_set(&trigrams(needle), &haystacks)) {
+            Ok(ties) => ties,
+            Err(reason) => return encode_error(env, reason),
+        };
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            rustler::types::atom::Atom::from_str(env, "ok")?.to_term(env),
+            rustler::types::tuple::make_tuple(env, &[score.encode(env), indices.encode(env)]),
+        ],
+    ))
+}
+
+/// Top score and all indices achieving it, ascending. `(-1.0, [])` when there
+/// are no haystacks.
+fn best_ties_from_set(needle_set: &TrigramHashSet, haystacks: &[String]) -> (f32, Vec<usize>) {
+    let push = |(best, mut indices): (f32, Vec<usize>), (idx, score): (usize, f32)| {
+        if score > best {
+            (score, vec![idx])
+        } else {
+            if score == best {
+                indices.push(idx);
+            }
+            (best, indices)
+        }
+    };
+    let merge = |a: (f32, Vec<usize>), b: (f32, Vec<usize>)| match a.0.partial_cmp(&b.0) {
+        Some(Ordering::Less) => b,
+        Some(Ordering::Greater) => a,
+        _ => (a.0, [a.1, b.1].concat()),
+    };
+    let score = |(idx, haystack): (usize, &String)| {
+        (idx, similarity_from_sets(needle_set, &trigrams(haystack)))
+    };
+
+    let (best, mut indices) = if below_parallel_threshold(haystacks.len()) {
+        haystacks
+            .iter()
+            .enumerate()
+            .map(score)
+            .fold((-1.0, Vec::new()), push)
+    } else {
+        in_pool(|| {
+            haystacks
+                .par_iter()
+                .enumerate()
+                .map(score)
+                .fold(|| (-1.0, Vec::new()), push)
+                .reduce(|| (-1.0, Vec::new()), merge)
+        })
+    };
+    indices.sort_unstable();
+    (best, indices)
+}
+
+/// `best_match/2` that only reports a match scoring at least `min_threshold`:
+/// `{:ok, {index, score}}`, else `{:error, :no_match}`, or
+/// `{:error, :empty_list}` for no haystacks.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_match_threshold<'a>(
+    env: Env<'a>,
+    needle: &str,
+    haystacks: Vec<String>,
+    min_threshold: f32,
+) -> NifResult<Term<'a>> {
+    check_input_len(&[&needle, &haystacks])?;
+    if haystacks.is_empty() {
+        return encode_empty_list(env);
+    }
+
+    match catch_internal(|| best_match_above(&trigrams(needle), &haystacks, min_threshold)) {
+        Ok(Some(best)) => encode_best_match(env, best),
+        Ok(None) => encode_error(env, "no_match"),
+        Err(reason) => encode_error(env, reason),
+    }
+}
+
+fn best_match_above(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    min_threshold: f32,
+) -> Option<(usize, f32)> {
+    let best = best_match_from_set(needle_set, haystacks);
+    (best.1 >= min_threshold).then_some(best)
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_match_precomputed<'a>(
+    env: Env<'a>,
+    set: ResourceArc<TrigramSet>,
+    haystacks: Vec<String>,
+) -> NifResult<Term<'a>> {
+    check_input_len(&[&haystacks])?;
+    if haystacks.is_empty() {
+        return encode_empty_list(env);
+    }
+
+    match catch_internal(|| best_match_from_set(&set.set, &haystacks)) {
+        Ok(best) => encode_best_match(env, best),
+        Err(reason) => encode_error(env, reason),
+    }
+}
+
+/// `best_match` over prepared haystack sets: `{:ok, {index, score}}`, ties
+/// going to the lowest index.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_match_sets<'a>(
+    env: Env<'a>,
+    needle_set: ResourceArc<TrigramSet>,
+    haystack_sets: Vec<ResourceArc<TrigramSet>>,
+) -> NifResult<Term<'a>> {
+    if haystack_sets.is_empty() {
+        return encode_empty_list(env);
+    }
+
+    let sets: Vec<&TrigramHashSet> = haystack_sets.iter().map(|h| &h.set).collect();
+    match catch_internal(|| best_match_among_sets(&needle_set.set, &sets)) {
+        Ok(best) => encode_best_match(env, best),
+        Err(reason) => encode_error(env, reason),
+    }
+}
+
+fn best_match_among_sets(needle_set: &TrigramHashSet, sets: &[&TrigramHashSet]) -> (usize, f32) {
+    let score =
+        |(idx, set): (usize, &&TrigramHashSet)| (idx, similarity_from_sets(needle_set, set));
+    let pick = |acc: (usize, f32), x: (usize, f32)| {
+        if x.1 > acc.1 || (x.1 == acc.1 && x.0 < acc.0) {
+            x
+        } else {
+            acc
+        }
+    };
+
+    if below_parallel_threshold(sets.len()) {
+        sets.iter().enumerate().map(score).fold((0, -1.0), pick)
+    } else {
+        in_pool(|| {
+            sets.par_iter()
+                .enumerate()
+                .map(score)
+                .reduce(|| (0, -1.0), pick)
+        })
+    }
+}
+
+/// Like `best_match`, but also returns the matched haystack:
+/// `{:ok, {index, score, string}}`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_match_str<'a>(env: Env<'a>, needle: &str, haystacks: Vec<String>) -> NifResult<Term<'a>> {
+    check_input_len(&[&needle, &haystacks])?;
+    if haystacks.is_empty() {
+        return encode_empty_list(env);
+    }
+
+    let (best_idx, best_score) =
+        match catch_internal(|| best_match_from_set(&trigrams(needle), &haystacks)) {
+            Ok(best) => best,
+            Err(reason) => return encode_error(env, reason),
+        };
+
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            rustler::types::atom::Atom::from_str(env, "ok")?.to_term(env),
+            rustler::types::tuple::make_tuple(
+                env,
+                &[
+                    best_idx.encode(env),
+                    best_score.encode(env),
+                    haystacks[best_idx].encode(env),
+                ],
+            ),
+        ],
+    ))
+}
+
+/// Best haystack for every needle, aligned to needle order. Haystacks are
+/// trigrammed once and shared across needles. Raises `ArgumentError` when
+/// there are needles but no haystacks.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_match_many(needles: Vec<String>, haystacks: Vec<String>) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needles, &haystacks])?;
+    if haystacks.is_empty() && !needles.is_empty() {
+        return Err(rustler::Error::BadArg);
+    }
+
+    guard_internal(|| {
+        let haystack_sets: Vec<TrigramHashSet> = if below_parallel_threshold(haystacks.len()) {
+            haystacks.iter().map(|h| trigrams(h)).collect()
+        } else {
+            in_pool(|| haystacks.par_iter().map(|h| trigrams(h)).collect())
+        };
+        best_match_many_from_sets(&needles, &haystack_sets)
+    })
+}
+
+fn best_match_many_from_sets(
+    needles: &[String],
+    haystack_sets: &[TrigramHashSet],
+) -> Vec<(usize, f32)> {
+    if below_parallel_threshold(needles.len()) {
+        needles
+            .iter()
+            .map(|needle| best_of_sets(&trigrams(needle), haystack_sets))
+            .collect()
+    } else {
+        in_pool(|| {
+            needles
+                .par_iter()
+                .map(|needle| best_of_sets(&trigrams(needle), haystack_sets))
+                .collect()
+        })
+    }
+}
+
+// Sequential best match over precomputed sets; first index wins on ties
+fn best_of_sets(needle_set: &TrigramHashSet, sets: &[TrigramHashSet]) -> (usize, f32) {
+    sets.iter()
+        .map(|set| similarity_from_sets(needle_set, set))
+        .enumerate()
+        .fold((0, -1.0), |acc, x| if x.1 > acc.1 { x } else { acc })
+}
+
+/// Runs `f`, turning a panic (including one on a Rayon worker, which Rayon
+/// re-raises here) into `Err("internal")` for an `{:error, :internal}` reply.
+/// Rustler would otherwise raise `:nif_panicked`; the tuple keeps the
+/// `{:ok, _} | {:error, _}` contract of NIFs that already return one.
+pub(crate) fn catch_internal<T>(f: impl FnOnce() -> T) -> Result<T, &'static str> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        #[cfg(test)]
+        if PANIC_NEXT.take() {
+            panic!("injected by test");
+        }
+        f()
+    }))
+    .map_err(|_| "internal")
+}
+
+/// `catch_internal` for NIFs without an error tuple: a panic raises
+/// `:internal` rather than Rustler's generic `:nif_panicked`, so every NIF
+/// reports an internal failure the same way.
+pub(crate) fn guard_internal<T>(f: impl FnOnce() -> T) -> NifResult<T> {
+    catch_internal(f).map_err(rustler::Error::RaiseAtom)
+}
+
+// Test hook: makes this thread's next `catch_internal` panic inside the guard
+#[cfg(test)]
+thread_local! {
+    static PANIC_NEXT: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+fn encode_empty_list(env: Env<'_>) -> NifResult<Term<'_>> {
+    encode_error(env, "empty_list")
+}
+
+fn encode_error<'a>(env: Env<'a>, reason: &str) -> NifResult<Term<'a>> {
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            rustler::types::atom::Atom::from_str(env, "error")?.to_term(env),
+            rustler::types::atom::Atom::from_str(env, reason)?.to_term(env),
+        ],
+    ))
+}
+
+fn encode_best_match(env: Env<'_>, (best_idx, best_score): (usize, f32)) -> NifResult<Term<'_>> {
+    Ok(rustler::types::tuple::make_tuple(
+        env,
+        &[
+            rustler::types::atom::Atom::from_str(env, "ok")?.to_term(env),
+            rustler::types::tuple::make_tuple(env, &[best_idx.encode(env), best_score.encode(env)]),
+        ],
+    ))
+}
+
+/// Raises `:input_too_long` if any string exceeds `set_max_input_len/1`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn score_all(
+    needle: &str,
+    haystacks: Vec<String>,
+    min_threshold: f32,
+) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    guard_internal(|| crate::core::score_all(needle, &haystacks, min_threshold))
+}
+
+/// `score_all/4`: like `score_all/3`, but keeps only the best `limit` results
+/// (`0` means unlimited) using a bounded heap instead of a full sort.
+#[rustler::nif(name = "score_all", schedule = "DirtyCpu")]
+fn score_all_limit(
+    needle: &str,
+    haystacks: Vec<String>,
+    min_threshold: f32,
+    limit: usize,
+) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    guard_internal(|| {
+        let needle_set = trigrams(needle);
+        if limit == 0 {
+            score_all_from_set(&needle_set, &haystacks, min_threshold)
+        } else {
+            top_k_from_set(&needle_set, &haystacks, min_threshold, limit)
+        }
+    })
+}
+
+/// `ResultOrder`, decoded from `:by_score`, `:by_index` and `:none`.
+#[derive(rustler::NifUnitEnum, Clone, Copy)]
+enum ResultOrderArg {
+    ByScore,
+    ByIndex,
+    None,
+}
+
+impl From<ResultOrderArg> for ResultOrder {
+    fn from(arg: ResultOrderArg) -> Self {
+        match arg {
+            ResultOrderArg::ByScore => ResultOrder::ByScore,
+            ResultOrderArg::ByIndex => ResultOrder::ByIndex,
+            ResultOrderArg::None => ResultOrder::None,
+        }
+    }
+}
+
+/// `score_all/5`: `score_all/4` with the result order chosen by `order`:
+/// `:by_score` (the default elsewhere), `:by_index` for ascending haystack
+/// index, or `:none` to skip sorting and take whatever order is cheapest.
+#[rustler::nif(name = "score_all", schedule = "DirtyCpu")]
+fn score_all_order(
+    needle: &str,
+    haystacks: Vec<String>,
+    min_threshold: f32,
+    limit: usize,
+    order: ResultOrderArg,
+) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    let order = order.into();
+    guard_internal(|| scores_in_order(&trigrams(needle), &haystacks, min_threshold, limit, order))
+}
+
+fn scores_in_order(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    min_threshold: f32,
+    limit: usize,
+    order: ResultOrder,
+) -> Vec<(usize, f32)> {
+    if limit == 0 {
+        return score_all_ordered(needle_set, haystacks, min_threshold, order);
+    }
+    // The bounded heap already yields the top `limit` by score
+    let mut results = top_k_from_set(needle_set, haystacks, min_threshold, limit);
+    if order == ResultOrder::ByIndex {
+        results.sort_unstable_by_key(|&(idx, _)| idx);
+    }
+    results
+}
+
+/// Like `score_all`, with each haystack string included in its result tuple.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn score_all_str(
+    needle: &str,
+    haystacks: Vec<String>,
+    min_threshold: f32,
+) -> NifResult<Vec<(usize, f32, String)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    let results =
+        guard_internal(|| score_all_from_set(&trigrams(needle), &haystacks, min_threshold))?;
+    Ok(with_haystacks(results, &haystacks))
+}
+
+/// `score_all/3` that trigrams and scores each distinct haystack once, then
+/// reports every original index of it. Same results, faster on repetitive lists.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn score_all_dedup(
+    needle: &str,
+    haystacks: Vec<String>,
+    min_threshold: f32,
+) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    guard_internal(|| score_all_dedup_from_set(&trigrams(needle), &haystacks, min_threshold))
+}
+
+fn score_all_dedup_from_set(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    min_threshold: f32,
+) -> Vec<(usize, f32)> {
+    let mut unique: Vec<&str> = Vec::new();
+    let mut positions: Vec<Vec<usize>> = Vec::new();
+    let mut seen: FxHashMap<&str, usize> = FxHashMap::default();
+
+    for (idx, haystack) in haystacks.iter().enumerate() {
+        let slot = *seen.entry(haystack.as_str()).or_insert_with(|| {
+            unique.push(haystack.as_str());
+            positions.push(Vec::new());
+            unique.len() - 1
+        });
+        positions[slot].push(idx);
+    }
+
+    let mut results: Vec<(usize, f32)> = score_all_from_set(needle_set, &unique, min_threshold)
+        .into_iter()
+        .flat_map(|(slot, score)| positions[slot].iter().map(move |&idx| (idx, score)))
+        .collect();
+    results.sort_unstable_by(rank_order);
+    results
+}
+
+/// `score_all/3` over haystacks packed into one binary: haystack `i` is
+/// `blob[offsets[i]..offsets[i + 1]]`, the last one running to the end of
+/// `blob`. Two terms cross the NIF boundary instead of one binary per string.
+/// Raises `ArgumentError` if offsets decrease or pass the end of `blob`, or a
+/// slice is not valid UTF-8.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn score_all_packed<'a>(
+    needle: &str,
+    blob: Binary<'a>,
+    offsets: Vec<usize>,
+    min_threshold: f32,
+) -> NifResult<Vec<(usize, f32)>> {
+    let haystacks = unpack_haystacks(blob.as_slice(), &offsets).ok_or(rustler::Error::BadArg)?;
+    check_input_len(&[&needle, &haystacks])?;
+    guard_internal(|| score_all_from_set(&trigrams(needle), &haystacks, min_threshold))
+}
+
+fn unpack_haystacks<'a>(blob: &'a [u8], offsets: &[usize]) -> Option<Vec<&'a str>> {
+    let ends = offsets.iter().skip(1).copied().chain([blob.len()]);
+    offsets
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| {
+            let bytes = blob.get(start..end)?;
+            std::str::from_utf8(bytes).ok()
+        })
+        .collect()
+}
+
+fn with_haystacks(results: Vec<(usize, f32)>, haystacks: &[String]) -> Vec<(usize, f32, String)> {
+    results
+        .into_iter()
+        .map(|(idx, score)| (idx, score, haystacks[idx].clone()))
+        .collect()
+}
+
+/// `score_all/3` keeping only scores within `min_threshold..=max_threshold`,
+/// in the same order. Returns `[]` when `min_threshold > max_threshold`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn score_all_range(
+    needle: &str,
+    haystacks: Vec<String>,
+    min_threshold: f32,
+    max_threshold: f32,
+) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    guard_internal(|| {
+        score_range_from_set(&trigrams(needle), &haystacks, min_threshold, max_threshold)
+    })
+}
+
+fn score_range_from_set(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    min_threshold: f32,
+    max_threshold: f32,
+) -> Vec<(usize, f32)> {
+    if min_threshold > max_threshold {
+        return Vec::new();
+    }
+    let mut results = score_all_from_set(needle_set, haystacks, min_threshold);
+    results.retain(|(_, score)| *score <= max_threshold);
+    results
+}
+
+// Haystacks scored between `score_all_progress` messages. Smaller inputs are
+// scored in one go without any messages.
+const PROGRESS_INTERVAL: usize = 10_000;
+
+/// `score_all/3` that also sends `{:trigram_progress, done, total}` to `pid`
+/// after every `PROGRESS_INTERVAL` haystacks. Inputs no larger than that
+/// send nothing.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn score_all_progress(
+    env: Env<'_>,
+    needle: &str,
+    haystacks: Vec<String>,
+    min_threshold: f32,
+    pid: LocalPid,
+) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    let tag = rustler::types::atom::Atom::from_str(env, "trigram_progress")?;
+    guard_internal(|| {
+        score_all_in_chunks(
+            &trigrams(needle),
+            &haystacks,
+            min_threshold,
+            PROGRESS_INTERVAL,
+            |done, total| {
+                // A dead listener shouldn't abort the scoring
+                let _ = env.send(&pid, (tag, done, total));
+            },
+        )
+    })
+}
+
+/// `score_all_from_set` over `chunk`-sized slices, calling `on_progress` with
+/// `(done, total)` after each slice when there is more than one.
+fn score_all_in_chunks(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    min_threshold: f32,
+    chunk: usize,
+    mut on_progress: impl FnMut(usize, usize),
+) -> Vec<(usize, f32)> {
+    if haystacks.len() <= chunk {
+        return score_all_from_set(needle_set, haystacks, min_threshold);
+    }
+
+    let mut results = Vec::new();
+    for (i, slice) in haystacks.chunks(chunk).enumerate() {
+        let offset = i * chunk;
+        results.extend(
+            score_all_from_set(needle_set, slice, min_threshold)
+                .into_iter()
+                .map(|(idx, score)| (offset + idx, score)),
+        );
+        on_progress(offset + slice.len(), haystacks.len());
+    }
+    results.sort_unstable_by(rank_order);
+    results
+}
+
+// Lines read from disk before each `score_file` scoring pass
+const FILE_CHUNK_LINES: usize = 64 * 1024;
+
+/// `score_all/3` over the lines of the file at `path`, read in bounded chunks
+/// so the file never has to fit in memory. Returns `{:ok, results, skipped}`
+/// where indices are 0-based line numbers and `skipped` counts lines that
+/// were not valid UTF-8 or were longer than `set_max_input_len/1` allows, or
+/// `{:error, posix}` if the file can't be read.
+///
+/// `path` is taken as raw bytes, so on Unix it needn't be valid UTF-8.
+#[rustler::nif(schedule = "DirtyIo")]
+fn score_file<'a>(
+    env: Env<'a>,
+    needle: &str,
+    path: Binary<'a>,
+    min_threshold: f32,
+) -> NifResult<Term<'a>> {
+    scored_file(env, needle, path, min_threshold, 0)
+}
+
+/// `score_file/4`: like `score_file/3`, but keeps only the best `limit` lines
+/// (`0` means unlimited), so at most `limit` results per chunk are held.
+#[rustler::nif(name = "score_file", schedule = "DirtyIo")]
+fn score_file_limit<'a>(
+    env: Env<'a>,
+    needle: &str,
+    path: Binary<'a>,
+    min_threshold: f32,
+    limit: usize,
+) -> NifResult<Term<'a>> {
+    scored_file(env, needle, path, min_threshold, limit)
+}
+
+fn scored_file<'a>(
+    env: Env<'a>,
+    needle: &str,
+    path: Binary<'a>,
+    min_threshold: f32,
+    limit: usize,
+) -> NifResult<Term<'a>> {
+    check_input_len(&[&needle, &path])?;
+    let path = path_from_bytes(path.as_slice()).ok_or(rustler::Error::BadArg)?;
+    let scored = catch_internal(|| {
+        let needle_set = trigrams(needle);
+        std::fs::File::open(path).and_then(|file| {
+            let reader = std::io::BufReader::new(file);
+            let max_len = MAX_INPUT_LEN.load(AtomicOrdering::Relaxed);
+            score_lines(&needle_set, reader, min_threshold, limit, max_len)
+        })
+    });
+    let terms = match scored {
+        Err(reason) => vec![
+            rustler::types::atom::Atom::from_str(env, "error")?.to_term(env),
+            rustler::types::atom::Atom::from_str(env, reason)?.to_term(env),
+        ],
+        Ok(Ok((results, skipped))) => vec![
+            rustler::types::atom::Atom::from_str(env, "ok")?.to_term(env),
+            results.encode(env),
+            skipped.encode(env),
+        ],
+        Ok(Err(err)) => vec![
+            rustler::types::atom::Atom::from_str(env, "error")?.to_term(env),
+            rustler::types::atom::Atom::from_str(env, posix_reason(&err))?.to_term(env),
+        ],
+    };
+    Ok(rustler::types::tuple::make_tuple(env, &terms))
+}
+
+// Unix paths are arbitrary bytes; elsewhere they must be UTF-8
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> Option<std::path::PathBuf> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(std::ffi::OsStr::from_bytes(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> Option<std::path::PathBuf> {
+    std::str::from_utf8(bytes).ok().map(Into::into)
+}
+
+/// Scores each line of `reader` (without its `\n` or `\r\n`), returning the
+/// ranked matches, at most `limit` of them unless it's `0`, and the number of
+/// lines skipped as invalid UTF-8 or longer than `max_len` bytes.
+///
+/// At most `max_len` bytes plus the line ending are buffered per line; the
+/// rest of an over-long line is drained without being kept.
+fn score_lines(
+    needle_set: &TrigramHashSet,
+    mut reader: impl std::io::BufRead,
+    min_threshold: f32,
+    limit: usize,
+    max_len: usize,
+) -> std::io::Result<(Vec<(usize, f32)>, usize)> {
+    use std::io::BufRead;
+
+    let line_cap = (max_len as u64).saturating_add(2);
+    let mut results = Vec::new();
+    let mut skipped = 0;
+    let mut line_no = 0;
+    let mut buf = Vec::new();
+    let mut chunk: Vec<String> = Vec::with_capacity(FILE_CHUNK_LINES);
+    let mut chunk_lines: Vec<usize> = Vec::with_capacity(FILE_CHUNK_LINES);
+
+    loop {
+        buf.clear();
+        let eof = std::io::Read::take(&mut reader, line_cap).read_until(b'\n', &mut buf)? == 0;
+        if !eof {
+            let terminated = buf.ends_with(b"\n");
+            if terminated {
+                buf.pop();
+                if buf.ends_with(b"\r") {
+                    buf.pop();
+                }
+            }
+            if buf.len() > max_len {
+                if !terminated {
+                    reader.skip_until(b'\n')?;
+                }
+                skipped += 1;
+            } else {
+                match std::str::from_utf8(&buf) {
+                    Ok(line) => {
+                        chunk.push(line.to_owned());
+                        chunk_lines.push(line_no);
+                    }
+                    Err(_) => skipped += 1,
+                }
+            }
+            line_no += 1;
+        }
+
+        if chunk.len() == FILE_CHUNK_LINES || (eof && !chunk.is_empty()) {
+            let scored = if limit == 0 {
+                score_all_from_set(needle_set, &chunk, min_threshold)
+            } else {
+                top_k_from_set(needle_set, &chunk, min_threshold, limit)
+            };
+            results.extend(
+                scored
+                    .into_iter()
+                    .map(|(idx, score)| (chunk_lines[idx], score)),
+            );
+            if limit > 0 {
+                // Chunk order is line order, so ties still favor earlier lines
+                results.sort_unstable_by(rank_order);
+                results.truncate(limit);
+            }
+            chunk.clear();
+            chunk_lines.clear();
+        }
+        if eof {
+            break;
+        }
+    }
+
+    results.sort_unstable_by(rank_order);
+    Ok((results, skipped))
+}
+
+// POSIX reasons like Elixir's `File` functions return, for the common failures
+fn posix_reason(err: &std::io::Error) -> &'static str {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => "enoent",
+        std::io::ErrorKind::PermissionDenied => "eacces",
+        std::io::ErrorKind::IsADirectory => "eisdir",
+        _ => "eio",
+    }
+}
+
+/// Like `score_all` with no threshold, but each trigram is weighted by its
+/// inverse document frequency `ln(N / df)` across the haystacks, so shared
+/// rare trigrams count for more than ubiquitous ones like `" th"`.
+///
+/// Scores are weighted Jaccard: the weight of shared trigrams over the
+/// weight of their union. Needle trigrams found in no haystack weigh as if
+/// `df = 1`; trigrams in every haystack weigh nothing.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn idf_similarity(needle: &str, haystacks: Vec<String>) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    guard_internal(|| idf_scores(&trigrams(needle), &trigram_sets(&haystacks)))
+}
+
+fn idf_scores(needle_set: &TrigramHashSet, sets: &[TrigramHashSet]) -> Vec<(usize, f32)> {
+    let count_into = |mut df: FxHashMap<TrigramKey, usize>, set: &TrigramHashSet| {
+        for &trigram in set {
+            *df.entry(trigram).or_insert(0) += 1;
+        }
+        df
+    };
+
+    // First pass: document frequencies
+    let df: FxHashMap<TrigramKey, usize> = if below_parallel_threshold(sets.len()) {
+        sets.iter().fold(FxHashMap::default(), count_into)
+    } else {
+        in_pool(|| {
+            sets.par_iter().fold(FxHashMap::default, count_into).reduce(
+                FxHashMap::default,
+                |mut acc, other| {
+                    for (trigram, count) in other {
+                        *acc.entry(trigram).or_insert(0) += count;
+                    }
+                    acc
+                },
+            )
+        })
+    };
+
+    let total = sets.len() as f64;
+    let weight = |trigram: &TrigramKey| (total / df.get(trigram).copied().unwrap_or(1) as f64).ln();
+    let needle_weight: f64 = needle_set.iter().map(weight).sum();
+
+    // Second pass: weighted Jaccard per haystack
+    let score = |(idx, set): (usize, &TrigramHashSet)| {
+        let shared: f64 = needle_set.intersection(set).map(weight).sum();
+        let haystack_only: f64 = set.difference(needle_set).map(weight).sum();
+        let union = needle_weight + haystack_only;
+        let score = if union > 0.0 { shared / union } else { 0.0 };
+        (idx, score as f32)
+    };
+
+    let mut results: Vec<(usize, f32)> = if below_parallel_threshold(sets.len()) {
+        sets.iter().enumerate().map(score).collect()
+    } else {
+        in_pool(|| sets.par_iter().enumerate().map(score).collect())
+    };
+    results.sort_unstable_by(rank_order);
+    results
+}
+
+/// Top `k` haystacks by score (ties on ascending index), best first.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_matches(needle: &str, haystacks: Vec<String>, k: usize) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needle, &haystacks])?;
+    guard_internal(|| top_k_from_set(&trigrams(needle), &haystacks, 0.0, k))
+}
+
+/// Top `k` haystacks by their best score against any of `needles`, such as
+/// the spelling variants of one query. Each haystack appears at most once,
+/// ranked by its best-matching needle; no needles means no results.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn best_matches_multi(
+    needles: Vec<String>,
+    haystacks: Vec<String>,
+    k: usize,
+) -> NifResult<Vec<(usize, f32)>> {
+    check_input_len(&[&needles, &haystacks])?;
+    guard_internal(|| top_k_multi(&needles, &haystacks, k))
+}
+
+/// Counts of haystack scores in `num_buckets` equal-width buckets over
+/// `[0, 1]`, without returning the scores themselves. A score on a boundary
+/// counts towards the lower bucket; the first also holds `0.0` and the last
+/// `1.0`. `num_buckets` must be in `1..=65_536`.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn score_histogram(
+    needle: &str,
+    haystacks: Vec<String>,
+    num_buckets: usize,
+) -> NifResult<Vec<u32>> {
+    check_input_len(&[&needle, &haystacks])?;
+    if !(1..=MAX_HISTOGRAM_BUCKETS).contains(&num_buckets) {
+        return Err(rustler::Error::BadArg);
+    }
+    guard_internal(|| histogram_from_set(&trigrams(needle), &haystacks, num_buckets))
+}
+
+fn histogram_from_set(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    num_buckets: usize,
+) -> Vec<u32> {
+    let count_into = |mut counts: Vec<u32>, haystack: &String| {
+        let score = similarity_from_sets(needle_set, &trigrams(haystack));
+        counts[score_bucket(score, num_buckets)] += 1;
+        counts
+    };
+
+    if below_parallel_threshold(haystacks.len()) {
+        haystacks.iter().fold(vec![0; num_buckets], count_into)
+    } else {
+        in_pool(|| {
+            haystacks
+                .par_iter()
+                .fold(|| vec![0; num_buckets], count_into)
+                .reduce(
+                    || vec![0; num_buckets],
+                    |mut acc, counts| {
+                        acc.iter_mut().zip(counts).for_each(|(a, c)| *a += c);
+                        acc
+                    },
+                )
+        })
+    }
+}
+
+// Each parallel task holds its own count vector, so this bounds memory per task
+const MAX_HISTOGRAM_BUCKETS: usize = 1 << 16;
+
+// Bucket `i` holds scores in `(i / n, (i + 1) / n]`, bucket 0 also `0.0`.
+// Edges are the `f32`s nearest `i / n`, the values a score "on" an edge
+// actually has (0.3f32 is slightly above 3/10), so the estimate from the
+// product is corrected against them.
+fn score_bucket(score: f32, num_buckets: usize) -> usize {
+    let edge = |i: usize| i as f32 / num_buckets as f32;
+    let mut upper = ((score * num_buckets as f32).ceil() as usize).clamp(1, num_buckets);
+    while upper > 1 && score <= edge(upper - 1) {
+        upper -= 1;
+    }
+    while upper < num_buckets && score > edge(upper) {
+        upper += 1;
+    }
+    upper - 1
+}
+
+// -----------------------------------------------------------------------------
+// Core Logic & Helpers
+// -----------------------------------------------------------------------------
+
+/// Heap entry ordered by `rank_order`, so a max-heap keeps the worst kept
+/// result on top where it can be evicted cheaply.
+struct Ranked((usize, f32));
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        rank_order(&self.0, &other.0)
+    }
+}
+
+// Push into a heap bounded to `k` entries, evicting the worst
+fn push_bounded(mut heap: BinaryHeap<Ranked>, entry: (usize, f32), k: usize) -> BinaryHeap<Ranked> {
+    if heap.len() < k {
+        heap.push(Ranked(entry));
+    } else if heap
+        .peek()
+        .is_some_and(|worst| rank_order(&entry, &worst.0).is_lt())
+    {
+        heap.pop();
+        heap.push(Ranked(entry));
+    }
+    heap
+}
+
+/// Top `k` haystacks scoring at least `min_threshold`, best first.
+fn top_k_from_set(
+    needle_set: &TrigramHashSet,
+    haystacks: &[String],
+    min_threshold: f32,
+    k: usize,
+) -> Vec<(usize, f32)> {
+    let reachable = length_prefilter(needle_set.len(), min_threshold);
+    top_k_by(haystacks, k, |haystack| {
+        if !reachable(haystack) {
+            return None;
+        }
+        let score = with_trigrams(haystack, |set| similarity_from_sets(needle_set, set));
+        (score >= min_threshold).then_some(score)
+    })
+}
+
+/// Top `k` haystacks by their best score against any of `needles`, best first.
+/// Each haystack is trigrammed once and appears at most once.
+fn top_k_multi(needles: &[String], haystacks: &[String], k: usize) -> Vec<(usize, f32)> {
+    let needle_sets: Vec<TrigramHashSet> = needles.iter().map(|n| trigrams(n)).collect();
+    top_k_by(haystacks, k, |haystack| {
+        with_trigrams(haystack, |haystack_set| {
+            needle_sets
+                .iter()
+                .map(|needle_set| similarity_from_sets(needle_set, haystack_set))
+                .reduce(f32::max)
+        })
+    })
+}
+
+/// Top `k` of the haystacks `score` keeps (`None` drops one), best first.
+/// Never holds more than `k` results at once, so nothing beyond the top `k`
+/// is sorted.
+fn top_k_by(
+    haystacks: &[String],
+    k: usize,
+    score: impl Fn(&str) -> Option<f32> + Sync,
+) -> Vec<(usize, f32)> {
+    if k == 0 {
+        return Vec::new();
+    }
+
+    let heap = if below_parallel_threshold(haystacks.len()) {
+        haystacks
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, haystack)| Some((idx, score(haystack)?)))
+            .fold(
+                BinaryHeap::with_capacity(k.min(haystacks.len()) + 1),
+                |heap, entry| push_bounded(heap, entry, k),
+            )
+    } else {
+        in_pool(|| {
+            // Each Rayon task keeps its own bounded heap; partial heaps are merged
+            haystacks
+                .par_iter()
+                .enumerate()
+                .filter_map(|(idx, haystack)| Some((idx, score(haystack)?)))
+                .fold(BinaryHeap::new, |heap, entry| push_bounded(heap, entry, k))
+                .reduce(BinaryHeap::new, |acc, other| {
+                    other
+                        .into_iter()
+                        .fold(acc, |heap, Ranked(entry)| push_bounded(heap, entry, k))
+                })
+        })
+    };
+
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Ranked(entry)| entry)
+        .collect()
+}
+
+/// One bit per 128-codepoint block (folded onto 64 bits) for every char that
+/// can appear in a trigram.
+///
+/// Every trigram holds at least one lowercased word char, so strings whose
+/// masks don't intersect can't share a trigram. Folding and the
+/// `is_alphanumeric` superset of `[\p{L}\p{N}]` only add bits, which can
+/// make the test pass needlessly but never fail wrongly. That holds for
+/// byte keys only: distinct `pg_parity` CRC keys can collide across blocks,
+/// which is why `script_prefilter_enabled` is always false there.
+fn script_mask(text: &str) -> u64 {
+    text.chars()
+        .flat_map(char::to_lowercase)
+        .filter(|c| c.is_alphanumeric())
+        .fold(0, |mask, c| mask | 1 << ((c as u32 >> 7) % 64))
+}
+
+fn dice_from_sets(a_set: &TrigramHashSet, b_set: &TrigramHashSet) -> f32 {
+    let sizes = a_set.len() + b_set.len();
+    if sizes == 0 {
+        return 0.0;
+    }
+
+    let (shared, _) = overlap_counts(a_set, b_set);
+    (2.0 * shared as f64 / sizes as f64) as f32
+}
+
+fn distance_from_sets(a_set: &TrigramHashSet, b_set: &TrigramHashSet) -> f32 {
+    (1.0 - jaccard_from_sets(a_set, b_set)) as f32
+}
+
+fn overlap_from_sets(a_set: &TrigramHashSet, b_set: &TrigramHashSet) -> f32 {
+    let smaller = a_set.len().min(b_set.len());
+    if smaller == 0 {
+        return 0.0;
+    }
+
+    let (shared, _) = overlap_counts(a_set, b_set);
+    (shared as f64 / smaller as f64) as f32
+}
+
+fn cosine_from_counts(
+    a_counts: &FxHashMap<TrigramKey, u32>,
+    b_counts: &FxHashMap<TrigramKey, u32>,
+) -> f32 {
+    if a_counts.is_empty() || b_counts.is_empty() {
+        return 0.0;
+    }
+
+    // Iterate the smaller map for the dot product
+    let (small, large) = if a_counts.len() <= b_counts.len() {
+        (a_counts, b_counts)
+    } else {
+        (b_counts, a_counts)
+    };
+    let dot: f64 = small
+        .iter()
+        .filter_map(|(trigram, &count)| {
+            large.get(trigram).map(|&other| count as f64 * other as f64)
+        })
+        .sum();
+    let norm = |counts: &FxHashMap<TrigramKey, u32>| -> f64 {
+        counts
+            .values()
+            .map(|&count| count as f64 * count as f64)
+            .sum()
+    };
+
+    (dot / (norm(a_counts) * norm(b_counts)).sqrt()).min(1.0) as f32
+}
+
+/// Port of pg_trgm's `iterate_word_similarity`.
+///
+/// In plain mode every haystack trigram present in the needle may close an
+/// extent. In strict mode (`bounds` given) extents may only open on a
+/// `TRGM_BOUND_LEFT` trigram and close on a `TRGM_BOUND_RIGHT` one. For each
+/// upper bound the lower bound is slid forward to find the best ratio of
+/// `count / (|needle| + |extent| - count)`.
+fn word_similarity_from_sets(
+    needle_set: &TrigramHashSet,
+    haystack: &[TrigramKey],
+    bounds: Option<&[u8]>,
+) -> f32 {
+    // Assign every distinct haystack trigram a dense index so positions can be
+    // tracked in flat arrays, mirroring pg_trgm's trg2indexes.
+    let mut index_of: FxHashMap<TrigramKey, usize> = FxHashMap::default();
+    let indexes: Vec<usize> = haystack
+        .iter()
+        .map(|trigram| {
+            let next = index_of.len();
+            *index_of.entry(*trigram).or_insert(next)
+        })
+        .collect();
+
+    let mut found = vec![false; index_of.len()];
+    for (trigram, &idx) in &index_of {
+        found[idx] = needle_set.contains(trigram);
+    }
+
+    let ulen1 = needle_set.len();
+    let mut lastpos: Vec<Option<usize>> = vec![None; index_of.len()];
+    // Strict mode considers the first trigram as the initial lower bound
+    let mut lower: Option<usize> = bounds.map(|_| 0);
+    let mut count = 0;
+    let mut ulen2 = 0;
+    let mut smlr_max = 0.0f32;
+
+    for (i, &trgindex) in indexes.iter().enumerate() {
+        // Only trigrams inside the current extent are tracked
+        if lower.is_some() || found[trgindex] {
+            if lastpos[trgindex].is_none() {
+                ulen2 += 1;
+                if found[trgindex] {
+                    count += 1;
+                }
+            }
+            lastpos[trgindex] = Some(i);
+        }
+
+        let is_upper = match bounds {
+            Some(bounds) => bounds[i] & TRGM_BOUND_RIGHT != 0,
+            None => found[trgindex],
+        };
+        if !is_upper {
+            continue;
+        }
+
+        let upper = i;
+        let prev_lower = *lower.get_or_insert(i);
+        let mut best_lower = prev_lower;
+        let mut smlr_cur = calc_word_sml(count, ulen1, ulen2);
+
+        // Try moving the lower bound forward for a better ratio
+        let mut tmp_count = count;
+        let mut tmp_ulen2 = ulen2;
+        for (tmp_lower, &tmp_trgindex) in (prev_lower..).zip(&indexes[prev_lower..=upper]) {
+            let is_lower = bounds.is_none_or(|bounds| bounds[tmp_lower] & TRGM_BOUND_LEFT != 0);
+            if is_lower {
+                let smlr_tmp = calc_word_sml(tmp_count, ulen1, tmp_ulen2);
+                if smlr_tmp > smlr_cur {
+                    smlr_cur = smlr_tmp;
+                    ulen2 = tmp_ulen2;
+                    best_lower = tmp_lower;
+                    count = tmp_count;
+                }
+            }
+
+            if lastpos[tmp_trgindex] == Some(tmp_lower) {
+                tmp_ulen2 -= 1;
+                if found[tmp_trgindex] {
+                    tmp_count -= 1;
+                }
+            }
+        }
+
+        smlr_max = smlr_max.max(smlr_cur);
+
+        // Forget trigrams that fell out of the extent
+        for (tmp_lower, &tmp_trgindex) in (prev_lower..).zip(&indexes[prev_lower..best_lower]) {
+            if lastpos[tmp_trgindex] == Some(tmp_lower) {
+                lastpos[tmp_trgindex] = None;
+            }
+        }
+        lower = Some(best_lower);
+    }
+
+    smlr_max
+}
+
+// pg_trgm's CALCSML, evaluated in float4 like Postgres does
+fn calc_word_sml(count: usize, len1: usize, len2: usize) -> f32 {
+    count as f32 / (len1 + len2 - count) as f32
+}
+
+/// Case-sensitive trigrams: no lowercasing and no `\u{0307}` removal.
+fn trigrams_cs(text: &str) -> TrigramHashSet {
+    ngrams_from_normalized(text, 3)
+}
+
+/// Lowercased trigrams that keep the combining dot above.
+fn trigrams_raw(text: &str) -> TrigramHashSet {
+    ngrams_from_normalized(&downcase(text, false), 3)
+}
+
+/// Trigrams after NFC composition, so precomposed and decomposed forms of the
+/// same text (`"é"` vs `"e\u{0301}"`) agree.
+fn trigrams_nfc(text: &str) -> TrigramHashSet {
+    let composed: String = text.nfc().collect();
+    trigrams(&composed)
+}
+
+/// Accent-insensitive trigrams: NFD-decompose, then drop every nonspacing
+/// mark (`Mn`), so `"über"` and `"uber"` produce the same set.
+fn trigrams_unaccent(text: &str) -> TrigramHashSet {
+    let decomposed: String = text.nfd().collect();
+    let unaccented = NONSPACING_MARK_RE.replace_all(&decomposed, "");
+    trigrams(&unaccented)
+}
+
+/// n-grams of text that has already gone through the caller's normalization.
+fn trigrams_with_pattern(text: &str, pattern: WordPattern) -> TrigramHashSet {
+    ngrams_from_words(&pg_downcase(text), 3, pattern.regex())
+}
+
+fn trigrams_graphemes(text: &str) -> TrigramHashSet {
+    let normalized = pg_downcase(text);
+    let mut set =
+        TrigramHashSet::with_capacity_and_hasher(ngram_capacity(&normalized), Default::default());
+
+    // Same padding as the char path, with clusters borrowed from `normalized`
+    let mut unit_buf: Vec<&str> = Vec::with_capacity(64);
+
+    for mat in GRAPHEME_WORD_RE.find_iter(&normalized) {
+        unit_buf.clear();
+        unit_buf.extend([" ", " "]);
+        unit_buf.extend(mat.as_str().graphemes(true));
+        unit_buf.push(" ");
+
+        for window in unit_buf.windows(3) {
+            set.insert(compact_graphemes(window));
+        }
+    }
+    set
+}
+
+// Leading padding of the first word in `prefix_similarity`; never a word char
+const START_PADDING: char = '\u{2}';
+
+/// `trigrams` with the first word's leading padding replaced by
+/// `START_PADDING`, so its opening trigrams only match those of other strings'
+/// first words.
+fn prefix_trigrams(text: &str) -> TrigramHashSet {
+    let normalized = pg_downcase(text);
+    let mut set =
+        TrigramHashSet::with_capacity_and_hasher(ngram_capacity(&normalized), Default::default());
+    let mut char_buf: Vec<char> = Vec::with_capacity(64);
+
+    for (i, mat) in WORD_RE.find_iter(&normalized).enumerate() {
+        let padding = if i == 0 { START_PADDING } else { ' ' };
+        char_buf.clear();
+        char_buf.extend([padding, padding]);
+        char_buf.extend(mat.as_str().chars());
+        char_buf.push(' ');
+
+        for window in char_buf.windows(3) {
+            set.insert(compact_ngram(window));
+        }
+    }
+    set
+}
+
+/// Trigrams of `text` in order of appearance, duplicates included, alongside
+/// `TRGM_BOUND_*` flags marking the first and last trigram of every word.
+fn trigram_sequence(text: &str) -> (Vec<TrigramKey>, Vec<u8>) {
+    let normalized = pg_downcase(text);
+    let mut sequence = Vec::with_capacity(normalized.len() + 2);
+    let mut bounds = Vec::with_capacity(normalized.len() + 2);
+    let mut char_buf: Vec<char> = Vec::with_capacity(64);
+
+    for mat in WORD_RE.find_iter(&normalized) {
+        char_buf.clear();
+        char_buf.extend([' ', ' ']);
+        char_buf.extend(mat.as_str().chars());
+        char_buf.push(' ');
+
+        let start = sequence.len();
+        for window in char_buf.windows(3) {
+            sequence.push(compact_ngram(window));
+            bounds.push(0);
+        }
+        bounds[start] |= TRGM_BOUND_LEFT;
+        bounds[sequence.len() - 1] |= TRGM_BOUND_RIGHT;
+    }
+    (sequence, bounds)
+}
+
+fn trigram_key_bytes(text: &str) -> Vec<[u8; KEY_BYTES]> {
+    let mut keys: Vec<[u8; KEY_BYTES]> = trigrams(text)
+        .into_iter()
+        .map(|key| {
+            let mut bytes = [0u8; KEY_BYTES];
+            bytes.copy_from_slice(&key.to_le_bytes()[..KEY_BYTES]);
+            bytes
+        })
+        .collect();
+    keys.sort_unstable();
+    keys
+}
+
+fn show_trigrams(text: &str) -> Vec<String> {
+    let mut result: Vec<String> = trigram_labels(text).into_values().collect();
+    result.sort_unstable();
+    result
+}
+
+// Each distinct trigram of `text` with its `show_trgm` label
+fn trigram_labels(text: &str) -> FxHashMap<TrigramKey, String> {
+    let normalized = pg_downcase(text);
+    let mut labels: FxHashMap<TrigramKey, String> = FxHashMap::default();
+    let mut char_buf: Vec<char> = Vec::with_capacity(64);
+
+    for mat in WORD_RE.find_iter(&normalized) {
+        char_buf.clear();
+        char_buf.extend([' ', ' ']);
+        char_buf.extend(mat.as_str().chars());
+        char_buf.push(' ');
+
+        for window in char_buf.windows(3) {
+            let trigram = compact_ngram(window);
+            labels.entry(trigram).or_insert_with(|| {
+                if window.iter().all(char::is_ascii) {
+                    window.iter().collect()
+                } else {
+                    format!("0x{:08x}", trigram)
+                }
+            });
+        }
+    }
+    labels
+}
+
+fn document_frequencies(strings: &[String]) -> Vec<(String, u32)> {
+    type Frequencies = FxHashMap<TrigramKey, (String, u32)>;
+    let count_into = |mut df: Frequencies, text: &String| {
+        for (trigram, label) in trigram_labels(text) {
+            df.entry(trigram).or_insert((label, 0)).1 += 1;
+        }
+        df
+    };
+    let merge = |mut acc: Frequencies, other: Frequencies| {
+        for (trigram, (label, count)) in other {
+            acc.entry(trigram).or_insert((label, 0)).1 += count;
+        }
+        acc
+    };
+
+    let df: Frequencies = if below_parallel_threshold(strings.len()) {
+        strings.iter().fold(Frequencies::default(), count_into)
+    } else {
+        in_pool(|| {
+            strings
+                .par_iter()
+                .fold(Frequencies::default, count_into)
+                .reduce(Frequencies::default, merge)
+        })
+    };
+
+    let mut result: Vec<(String, u32)> = df.into_values().collect();
+    result.sort_unstable_by(|(label_a, count_a), (label_b, count_b)| {
+        count_b.cmp(count_a).then_with(|| label_a.cmp(label_b))
+    });
+    result
+}
+
+/// Occurrence count of every trigram of `text`.
+fn trigram_counts(text: &str) -> FxHashMap<TrigramKey, u32> {
+    let (sequence, _) = trigram_sequence(text);
+    let mut counts = FxHashMap::with_capacity_and_hasher(sequence.len(), Default::default());
+    for trigram in sequence {
+        *counts.entry(trigram).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Grapheme counterpart of `compact_ngram`: single-char clusters get the same
+/// key as the char path, longer clusters always take the CRC fallback.
+fn compact_graphemes(units: &[&str]) -> TrigramKey {
+    let len: usize = units.iter().map(|unit| unit.len()).sum();
+    if len == units.len() {
+        let mut key = [0u8; 4];
+        for (slot, unit) in key.iter_mut().zip(units) {
+            *slot = unit.as_bytes()[0];
+        }
+        TrigramKey::from_le_bytes(key)
+    } else {
+        let mut bytes = Vec::with_capacity(len);
+        for unit in units {
+            bytes.extend_from_slice(unit.as_bytes());
+        }
+        crc_key(&bytes)
+    }
+}
+
+rustler::init!("Elixir.Trigram.Native");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{
+        legacy_crc32, length_bound, overlap_counts_sorted, DEFAULT_PARALLEL_THRESHOLD,
+        MIN_PARALLEL_CHUNK,
+    };
+    use std::sync::Mutex;
+
+    // Serializes tests that change global configuration
+    static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+    fn with_parallel_threshold<R>(threshold: usize, f: impl FnOnce() -> R) -> R {
+        let _guard = CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        crate::core::set_parallel_threshold(threshold);
+        let result = f();
+        crate::core::set_parallel_threshold(DEFAULT_PARALLEL_THRESHOLD);
+        result
+    }
+
+    fn with_script_prefilter<R>(f: impl FnOnce() -> R) -> R {
+        let _guard = CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        SCRIPT_PREFILTER.store(true, AtomicOrdering::Relaxed);
+        let result = f();
+        SCRIPT_PREFILTER.store(false, AtomicOrdering::Relaxed);
+        result
+    }
+
+    fn with_length_prefilter<R>(f: impl FnOnce() -> R) -> R {
+        let _guard = CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        LENGTH_PREFILTER.store(true, AtomicOrdering::Relaxed);
+        let result = f();
+        LENGTH_PREFILTER.store(false, AtomicOrdering::Relaxed);
+        result
+    }
+
+    // Helper to compute similarity using internal functions
+    fn compute_similarity(a: &str, b: &str) -> f32 {
+        let a_set = trigrams(a);
+        let b_set = trigrams(b);
+        similarity_from_sets(&a_set, &b_set)
+    }
+
+    #[test]
+    fn test_similarity_identical() {
+        assert_eq!(compute_similarity("hello", "hello"), 1.0);
+    }
+
+    #[test]
+    fn test_similarity_empty() {
+        assert_eq!(compute_similarity("", ""), 0.0);
+    }
+
+    #[test]
+    fn test_similarity_partial() {
+        let score = compute_similarity("hello", "hallo");
+        assert!(score > 0.0 && score < 1.0);
+    }
+
+    #[test]
+    fn test_trigrams_basic() {
+        let set = trigrams("hello");
+        // Should have trigrams: "  h", " he", "hel", "ell", "llo", "lo "
+        assert_eq!(set.len(), 6);
+    }
+
+    #[test]
+    fn test_trigram_count() {
+        for (text, count) in [
+            ("hello", 6),
+            ("hello hello", 6),
+            ("東京", 3),
+            ("", 0),
+            ("!!!", 0),
+        ] {
+            assert_eq!(trigrams(text).len(), count, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_trigrams_unicode() {
+        // Test Turkish dotless i handling (İ → i when lowercased, with \u{0307} removed)
+        let set1 = trigrams("İstanbul");
+        let set2 = trigrams("istanbul");
+        // After case folding and \u{0307} removal, the trigram SETS should be identical
+        // because İ lowercases to "i" + "\u{0307}", and we filter out \u{0307}
+        assert_eq!(
+            set1, set2,
+            "İstanbul and istanbul should produce identical trigram sets"
+        );
+        // And thus similarity should be 1.0
+        let score = compute_similarity("İstanbul", "istanbul");
+        assert_eq!(score, 1.0, "Score should be 1.0, was {}", score);
+    }
+
+    #[test]
+    fn test_compact_trigram_ascii() {
+        let result = compact_ngram(&['a', 'b', 'c']);
+        assert_eq!(result.to_le_bytes(), [b'a', b'b', b'c', 0]);
+    }
+
+    #[test]
+    fn test_compact_trigram_unicode() {
+        // Multi-byte chars should use CRC32
+        let result = compact_ngram(&['é', 'é', 'é']);
+        // Should not be simple bytes, should be CRC32 based
+        assert_ne!(result.to_le_bytes()[0], 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "pg_parity"))]
+    fn test_multibyte_keys_do_not_collide() {
+        // These trigrams share the lower 3 bytes of their CRC32 (0x6f305c), so
+        // a pg_trgm-style 3-byte key counted them as the same trigram
+        let left = compact_ngram(&['а', 'ъ', 'æ']);
+        let right = compact_ngram(&['а', 'é', 'a']);
+        assert_eq!(left & 0x00FF_FFFF, right & 0x00FF_FFFF);
+        assert_ne!(left, right);
+
+        // Only "  а" is genuinely shared: 1 / (4 + 4 - 1)
+        let score = compute_similarity("аъæ", "аéa");
+        assert!(score < 1.0);
+        assert_eq!(score, (1.0f64 / 7.0) as f32);
+    }
+
+    #[test]
+    fn test_legacy_crc32() {
+        // Known CRC32 value for "abc"
+        let crc = legacy_crc32(b"abc");
+        // PostgreSQL's legacy CRC32 should produce consistent results
+        assert_ne!(crc, 0);
+    }
+
+    #[test]
+    fn test_pg_similarity_cases() {
+        // Test cases from SimilarityCases.pairs() in Elixir tests
+        // These pairs should have POSITIVE similarity (same script, accent differences)
+        let positive_similarity_cases = vec![
+            ("café", "cafe"),
+            ("naïve", "naive"),
+            ("über", "uber"),
+            ("São", "Sao"),
+            ("ångström", "angstrom"),
+            ("fiancé", "fiance"),
+            ("東京", "東京"),      // identical
+            ("foo_bar", "foobar"), // partial match
+            ("hello-world", "hello world"),
+            ("hello—world", "hello world"),
+            ("Apt #4B", "Apt 4B"),
+            ("LLC", "L.L.C."),
+            ("co-op", "coop"),
+            ("123-456", "123456"),
+            ("mid–range", "mid range"),
+            ("space   tabs", "space tabs"),
+            ("résumé", "resume"),
+            ("façade", "facade"),
+            ("İstanbul", "istanbul"),
+            ("straße", "strasse"),
+        ];
+
+        for (left, right) in positive_similarity_cases {
+            let score = compute_similarity(left, right);
+            assert!(
+                score > 0.0,
+                "Expected positive similarity for ({}, {}), got {}",
+                left,
+                right,
+                score
+            );
+        }
+
+        // These pairs have ZERO similarity (different scripts, no shared trigrams)
+        let zero_similarity_cases = vec![
+            ("привет", "privet"), // Cyrillic vs Latin
+            ("東京", "东 京"),    // Traditional vs Simplified Chinese (different chars)
+            ("Ελλάδα", "Ellada"), // Greek vs Latin
+        ];
+
+        for (left, right) in zero_similarity_cases {
+            let score = compute_similarity(left, right);
+            // These should NOT crash and should return 0 (different scripts)
+            assert!(
+                score >= 0.0,
+                "Score should be non-negative for ({}, {}), got {}",
+                left,
+                right,
+                score
+            );
+        }
+    }
+
+    #[test]
+    fn test_identical_after_normalization() {
+        // These pairs should be IDENTICAL after case normalization
+        let identical_pairs = vec![
+            ("Hello", "hello"),
+            ("WORLD", "world"),
+            ("İstanbul", "istanbul"), // Turkish İ → i after \u{0307} removal
+        ];
+
+        for (left, right) in identical_pairs {
+            let score = compute_similarity(left, right);
+            assert_eq!(
+                score, 1.0,
+                "Expected exact match for ({}, {}), got {}",
+                left, right, score
+            );
+        }
+    }
+
+    #[test]
+    fn test_trigram_set_identity() {
+        // Verify that trigram sets are identical for case-normalized equivalents
+        let set1 = trigrams("İstanbul");
+        let set2 = trigrams("istanbul");
+        assert_eq!(set1.len(), set2.len(), "Trigram set sizes differ");
+
+        // Check that all trigrams in set1 are in set2
+        for t in &set1 {
+            assert!(set2.contains(t), "Trigram {:?} not found in set2", t);
+        }
+    }
+
+    // Helper to compute word_similarity using internal functions
+    fn compute_word_similarity(needle: &str, haystack: &str) -> f32 {
+        let (sequence, _) = trigram_sequence(haystack);
+        word_similarity_from_sets(&trigrams(needle), &sequence, None)
+    }
+
+    // Helper to compute strict_word_similarity using internal functions
+    fn compute_strict_word_similarity(needle: &str, haystack: &str) -> f32 {
+        let (sequence, bounds) = trigram_sequence(haystack);
+        word_similarity_from_sets(&trigrams(needle), &sequence, Some(&bounds))
+    }
+
+    #[test]
+    fn test_word_similarity_pg_values() {
+        // Values from the pg_trgm documentation
+        assert_eq!(compute_similarity("word", "two words"), 0.36363637);
+        assert_eq!(compute_word_similarity("word", "two words"), 0.8);
+        assert_eq!(compute_word_similarity("word", "two words apart"), 0.8);
+        assert_eq!(compute_word_similarity("word", "word"), 1.0);
+    }
+
+    #[test]
+    fn test_word_similarity_edge_cases() {
+        assert_eq!(compute_word_similarity("", ""), 0.0);
+        assert_eq!(compute_word_similarity("word", ""), 0.0);
+        assert_eq!(compute_word_similarity("", "word"), 0.0);
+        assert_eq!(compute_word_similarity("word", "xyz"), 0.0);
+
+        // Never lower than whole-string similarity
+        let pairs = [("hello", "hello world"), ("main st", "123 main st apt 4")];
+        for (needle, haystack) in pairs {
+            assert!(
+                compute_word_similarity(needle, haystack) >= compute_similarity(needle, haystack)
+            );
+        }
+    }
+
+    #[test]
+    fn test_strict_word_similarity_pg_values() {
+        // Values from the pg_trgm documentation
+        assert_eq!(
+            compute_strict_word_similarity("word", "two words"),
+            0.5714286
+        );
+        assert_eq!(compute_strict_word_similarity("word", "two word"), 1.0);
+        assert_eq!(
+            compute_strict_word_similarity("main st", "123 main st apt 4"),
+            1.0
+        );
+        assert_eq!(compute_strict_word_similarity("", ""), 0.0);
+    }
+
+    #[test]
+    fn test_strict_word_similarity_diverges_on_partial_words() {
+        // Loose extents may end mid-word; strict ones must cover whole words
+        let loose = compute_word_similarity("word", "two words");
+        let strict = compute_strict_word_similarity("word", "two words");
+        assert!(strict < loose, "strict {} vs loose {}", strict, loose);
+
+        // Both agree when the needle is a whole word of the haystack
+        assert_eq!(
+            compute_word_similarity("main", "123 main st"),
+            compute_strict_word_similarity("main", "123 main st")
+        );
+    }
+
+    #[test]
+    fn test_show_trigrams_ascii() {
+        assert_eq!(
+            show_trigrams("Hello"),
+            vec!["  h", " he", "ell", "hel", "llo", "lo "]
+        );
+        assert_eq!(show_trigrams("hi hi"), vec!["  h", " hi", "hi "]);
+        assert!(show_trigrams("").is_empty());
+    }
+
+    #[test]
+    fn test_show_trigrams_multibyte() {
+        let shown = show_trigrams("café");
+        assert_eq!(shown.len(), trigrams("café").len());
+        assert!(shown.contains(&"caf".to_string()));
+
+        // "afé" and "fé " contain a multi-byte char and are shown as packed keys
+        let hex: Vec<&String> = shown.iter().filter(|t| t.starts_with("0x")).collect();
+        assert_eq!(hex.len(), 2);
+        assert!(hex.iter().all(|t| t.len() == 10));
+        assert_eq!(show_trigrams("café"), shown, "output must be stable");
+    }
+
+    #[test]
+    fn test_precomputed_set_matches_similarity() {
+        let inputs = [
+            "hello",
+            "hallo",
+            "",
+            "café",
+            "İstanbul",
+            "two words apart",
+            "東京",
+        ];
+
+        for a in inputs {
+            let prepared = TrigramSet { set: trigrams(a) };
+            for b in inputs {
+                assert_eq!(
+                    similarity_from_sets(&prepared.set, &trigrams(b)),
+                    compute_similarity(a, b),
+                    "Mismatch for ({}, {})",
+                    a,
+                    b
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_best_match_from_precomputed_set() {
+        let haystacks = strings(&["world", "hallo", "hello", "help"]);
+        let prepared = TrigramSet {
+            set: trigrams("hello"),
+        };
+
+        assert_eq!(best_match_from_set(&prepared.set, &haystacks), (2, 1.0));
+    }
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    // score_all without the NIF boundary, for comparison against ranked helpers
+    fn compute_score_all(needle: &str, haystacks: &[String]) -> Vec<(usize, f32)> {
+        let mut results: Vec<(usize, f32)> = haystacks
+            .iter()
+            .enumerate()
+            .map(|(idx, haystack)| (idx, compute_similarity(needle, haystack)))
+            .collect();
+        results.sort_unstable_by(rank_order);
+        results
+    }
+
+    #[test]
+    fn test_top_k_matches_sorted_prefix() {
+        let haystacks = strings(&["world", "hallo", "hello", "help", "hello", "yellow", ""]);
+        let needle_set = trigrams("hello");
+        let full = compute_score_all("hello", &haystacks);
+
+        for k in 0..=haystacks.len() + 2 {
+            let expected: Vec<_> = full.iter().copied().take(k).collect();
+            assert_eq!(
+                top_k_from_set(&needle_set, &haystacks, 0.0, k),
+                expected,
+                "k = {}",
+                k
+            );
+        }
+
+        // Ties break on ascending index
+        assert_eq!(
+            top_k_from_set(&needle_set, &haystacks, 0.0, 2),
+            vec![(2, 1.0), (4, 1.0)]
+        );
+        assert!(top_k_from_set(&needle_set, &[], 0.0, 5).is_empty());
+    }
+
+    #[test]
+    fn test_top_k_parallel_path() {
+        let haystacks: Vec<String> = (0..DEFAULT_PARALLEL_THRESHOLD * 4)
+            .map(|i| format!("item {}", i % 97))
+            .collect();
+        let needle_set = trigrams("item 42");
+        let full = compute_score_all("item 42", &haystacks);
+
+        let expected: Vec<_> = full.into_iter().take(10).collect();
+        assert_eq!(top_k_from_set(&needle_set, &haystacks, 0.0, 10), expected);
+    }
+
+    #[test]
+    fn test_ngrams_trigram_default() {
+        for (a, b) in [("hello", "hallo"), ("café", "cafe"), ("two words", "word")] {
+            assert_eq!(ngrams(a, 3), trigrams(a));
+            assert_eq!(
+                similarity_from_sets(&ngrams(a, 3), &ngrams(b, 3)),
+                compute_similarity(a, b)
+            );
+        }
+    }
+
+    #[test]
+    fn test_ngrams_padding_and_ascii_keys() {
+        // " a", "ab", "b "
+        assert_eq!(ngrams("ab", 2).len(), 3);
+        // "   a", "  ab", " abc", "abcd", "bcde", ..., "fgh "
+        assert_eq!(ngrams("abcdefgh", 4).len(), 9);
+
+        // ASCII keys are packed bytes, so distinct quadgrams never collide
+        assert_eq!(compact_ngram(&['a', 'b', 'c', 'd']).to_le_bytes(), *b"abcd");
+        assert_ne!(compact_ngram(&['a', 'b']), compact_ngram(&['b', 'a']));
+    }
+
+    #[test]
+    fn test_ngrams_short_codes() {
+        let score_n =
+            |a: &str, b: &str, n: usize| similarity_from_sets(&ngrams(a, n), &ngrams(b, n));
+
+        // A shifted short code keeps bigrams but loses longer grams
+        let bigram = score_n("XABC", "ABCX", 2);
+        let trigram = score_n("XABC", "ABCX", 3);
+        let quadgram = score_n("XABC", "ABCX", 4);
+        assert_eq!(bigram, 0.25);
+        assert!(bigram > trigram && trigram > quadgram);
+        assert_eq!(quadgram, 0.0);
+
+        assert_eq!(score_n("SKU1", "sku1", 2), 1.0);
+        assert_eq!(score_n("SKU1", "sku1", 4), 1.0);
+    }
+
+    #[test]
+    fn test_distance_from_sets() {
+        let distance = |a: &str, b: &str| distance_from_sets(&trigrams(a), &trigrams(b));
+
+        assert_eq!(distance("hello", "hello"), 0.0);
+        assert_eq!(distance("İstanbul", "istanbul"), 0.0);
+        assert_eq!(distance("hello", "xyz"), 1.0);
+        assert_eq!(distance("привет", "privet"), 1.0);
+
+        let score = compute_similarity("hello", "hallo");
+        assert!((distance("hello", "hallo") - (1.0 - score)).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn test_overlap_counts_consistent_with_similarity() {
+        // "hello"/"hallo" share "  h", "llo" and "lo " out of 9 distinct trigrams
+        assert_eq!(
+            overlap_counts(&trigrams("hello"), &trigrams("hallo")),
+            (3, 9)
+        );
+        assert_eq!(overlap_counts(&trigrams(""), &trigrams("")), (0, 0));
+
+        for (a, b) in [
+            ("hello", "hallo"),
+            ("x", "x"),
+            ("", "abc"),
+            ("café", "cafe"),
+        ] {
+            let (shared, total) = overlap_counts(&trigrams(a), &trigrams(b));
+            assert_eq!(
+                jaccard_from_counts(shared, total) as f32,
+                compute_similarity(a, b)
+            );
+        }
+    }
+
+    #[test]
+    fn test_cosine_similarity() {
+        let cosine = |a: &str, b: &str| cosine_from_counts(&trigram_counts(a), &trigram_counts(b));
+
+        assert_eq!(cosine("hello world", "hello world"), 1.0);
+        assert_eq!(cosine("HELLO", "hello"), 1.0);
+        assert_eq!(cosine("hello", "xyz"), 0.0);
+        assert_eq!(cosine("", ""), 0.0);
+        assert_eq!(cosine("", "hello"), 0.0);
+
+        // Same trigram set, but "na " repeats: Jaccard ignores it, cosine does not
+        let repeated = "na na na na batman";
+        assert_eq!(compute_similarity(repeated, "na batman"), 1.0);
+        let score = cosine(repeated, "na batman");
+        assert!(score > 0.0 && score < 1.0, "cosine was {}", score);
+    }
+
+    #[test]
+    fn test_overlap_similarity() {
+        let overlap = |a: &str, b: &str| overlap_from_sets(&trigrams(a), &trigrams(b));
+
+        // A short needle contained in a long haystack is a full overlap
+        let haystack = "the quick brown fox jumps over the lazy dog";
+        assert_eq!(overlap("fox", haystack), 1.0);
+        assert!(compute_similarity("fox", haystack) < 0.2);
+
+        // 3 shared out of 6 trigrams each
+        assert_eq!(overlap("hello", "hallo"), 0.5);
+        assert_eq!(overlap("", ""), 0.0);
+        assert_eq!(overlap("", "hello"), 0.0);
+    }
+
+    #[test]
+    fn test_parallel_threshold_paths_agree() {
+        let haystacks: Vec<String> = (0..300).map(|i| format!("entry {}", i % 37)).collect();
+        let pairs: Vec<(String, String)> = haystacks
+            .iter()
+            .map(|h| ("entry 7".to_string(), h.clone()))
+            .collect();
+        let needle_set = trigrams("entry 7");
+
+        let run = || {
+            (
+                similarity_pairs(&pairs),
+                best_match_from_set(&needle_set, &haystacks),
+                score_all_from_set(&needle_set, &haystacks, 0.3),
+                top_k_from_set(&needle_set, &haystacks, 0.0, 5),
+            )
+        };
+        let parallel = with_parallel_threshold(0, run);
+        let sequential = with_parallel_threshold(usize::MAX, run);
+
+        assert_eq!(parallel.0, sequential.0);
+        assert_eq!(parallel.1 .1, sequential.1 .1);
+        assert_eq!(parallel.2, sequential.2);
+        assert_eq!(parallel.3, sequential.3);
+    }
+
+    #[test]
+    fn test_case_sensitive_trigrams() {
+        let similarity_cs =
+            |a: &str, b: &str| similarity_from_sets(&trigrams_cs(a), &trigrams_cs(b));
+
+        assert_eq!(similarity_cs("ABC", "abc"), 0.0);
+        assert_eq!(compute_similarity("ABC", "abc"), 1.0);
+        assert_eq!(similarity_cs("ABC", "ABC"), 1.0);
+        assert!(similarity_cs("GATTACA", "GATTAca") > 0.0);
+
+        // Raw mode keeps the combining dot above
+        assert_ne!(trigrams_cs("i\u{0307}stanbul"), trigrams_cs("istanbul"));
+        assert_eq!(trigrams_cs("hello"), trigrams("hello"));
+    }
+
+    #[test]
+    fn test_raw_mode_keeps_dot_above() {
+        let similarity_raw =
+            |a: &str, b: &str| similarity_from_sets(&trigrams_raw(a), &trigrams_raw(b));
+
+        // İ lowercases to i + \u{0307}; keeping the mark changes the trigrams
+        assert_eq!(compute_similarity("İstanbul", "istanbul"), 1.0);
+        let score = similarity_raw("İstanbul", "istanbul");
+        assert!(score < 1.0, "raw score was {}", score);
+
+        // Still case-insensitive
+        assert_eq!(similarity_raw("HELLO", "hello"), 1.0);
+    }
+
+    #[test]
+    fn test_nfc_normalization() {
+        let similarity_nfc =
+            |a: &str, b: &str| similarity_from_sets(&trigrams_nfc(a), &trigrams_nfc(b));
+        let precomposed = "caf\u{00e9}";
+        let decomposed = "cafe\u{0301}";
+
+        assert!(compute_similarity(precomposed, decomposed) < 1.0);
+        assert_eq!(similarity_nfc(precomposed, decomposed), 1.0);
+        assert_eq!(similarity_nfc("CAFE\u{0301}", precomposed), 1.0);
+        assert_eq!(
+            similarity_nfc("hello", "hallo"),
+            compute_similarity("hello", "hallo")
+        );
+    }
+
+    #[test]
+    fn test_unaccent_mode() {
+        let similarity_unaccent =
+            |a: &str, b: &str| similarity_from_sets(&trigrams_unaccent(a), &trigrams_unaccent(b));
+
+        for (accented, plain) in [
+            ("café", "cafe"),
+            ("ångström", "angstrom"),
+            ("über", "uber"),
+            ("São", "Sao"),
+        ] {
+            assert!(compute_similarity(accented, plain) < 1.0);
+            assert_eq!(
+                similarity_unaccent(accented, plain),
+                1.0,
+                "({}, {})",
+                accented,
+                plain
+            );
+        }
+
+        assert_eq!(similarity_unaccent("cafe\u{0301}", "café"), 1.0);
+
+        // Letters without a decomposition are untouched
+        assert!(similarity_unaccent("straße", "strasse") < 1.0);
+    }
+
+    #[test]
+    fn test_best_match_index_recovers_string() {
+        let haystacks = strings(&["world", "mellow", "hallo", "yellow"]);
+        let (idx, score) = best_match_from_set(&trigrams("hello"), &haystacks);
+
+        assert_eq!(haystacks[idx], "hallo");
+        assert_eq!(score, compute_similarity("hello", &haystacks[idx]));
+    }
+
+    #[test]
+    fn test_score_all_with_strings() {
+        let haystacks = strings(&["hello", "hallo", "help", "world", "hello"]);
+        let results = score_all_from_set(&trigrams("hello"), &haystacks, 0.3);
+        let with_strings = with_haystacks(results.clone(), &haystacks);
+
+        assert_eq!(with_strings.len(), results.len());
+        for ((idx, score, string), (expected_idx, expected_score)) in
+            with_strings.iter().zip(&results)
+        {
+            assert_eq!((idx, score), (expected_idx, expected_score));
+            assert_eq!(string, &haystacks[*idx]);
+        }
+        assert_eq!(with_strings[0], (0, 1.0, "hello".to_string()));
+    }
+
+    #[test]
+    fn test_score_all_limit_is_prefix_of_full_output() {
+        let haystacks: Vec<String> = (0..DEFAULT_PARALLEL_THRESHOLD * 2)
+            .map(|i| format!("hello {}", i % 53))
+            .chain(strings(&["hello", "hallo", "help", "world"]))
+            .collect();
+        let needle_set = trigrams("hello 7");
+
+        for min_threshold in [0.0, 0.3, 0.6, 1.1] {
+            let full = score_all_from_set(&needle_set, &haystacks, min_threshold);
+            for limit in [1, 5, 20, full.len() + 1] {
+                let expected: Vec<_> = full.iter().copied().take(limit).collect();
+                assert_eq!(
+                    top_k_from_set(&needle_set, &haystacks, min_threshold, limit),
+                    expected,
+                    "threshold {} limit {}",
+                    min_threshold,
+                    limit
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_similarity_matrix() {
+        let inputs = ["hello", "hallo", "", "world", "hello"];
+        let sets: Vec<_> = inputs.iter().map(|s| sorted_keys(&trigrams(s))).collect();
+        let matrix = similarity_matrix_from_sets(&sets);
+
+        assert_eq!(matrix.len(), inputs.len());
+        for (i, row) in matrix.iter().enumerate() {
+            assert_eq!(row.len(), inputs.len());
+            let expected_diagonal = if inputs[i].is_empty() { 0.0 } else { 1.0 };
+            assert_eq!(row[i], expected_diagonal);
+            for (j, &score) in row.iter().enumerate() {
+                assert_eq!(score, matrix[j][i], "not symmetric at ({}, {})", i, j);
+                if i != j {
+                    assert_eq!(score, compute_similarity(inputs[i], inputs[j]));
+                }
+            }
+        }
+        assert!(similarity_matrix_from_sets(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_similarity_matrix_parallel_path() {
+        let sets = sorted_trigram_sets(&(0..40).map(|i| format!("name {}", i)).collect::<Vec<_>>());
+        let sequential = with_parallel_threshold(usize::MAX, || similarity_matrix_from_sets(&sets));
+        let parallel = with_parallel_threshold(0, || similarity_matrix_from_sets(&sets));
+        assert_eq!(sequential, parallel);
+    }
+
+    #[test]
+    fn test_best_match_many_matches_looped_best_match() {
+        let haystacks = strings(&["apple pie", "banana split", "cherry tart", "apple tart"]);
+        let haystack_sets: Vec<_> = haystacks.iter().map(|h| trigrams(h)).collect();
+        let needles = strings(&["apple", "banana", "tart", "cherry pie", "zzz"]);
+
+        let expected: Vec<_> = with_parallel_threshold(usize::MAX, || {
+            needles
+                .iter()
+                .map(|needle| best_match_from_set(&trigrams(needle), &haystacks))
+                .collect()
+        });
+        assert_eq!(
+            best_match_many_from_sets(&needles, &haystack_sets),
+            expected
+        );
+
+        let parallel =
+            with_parallel_threshold(0, || best_match_many_from_sets(&needles, &haystack_sets));
+        assert_eq!(parallel, expected);
+        assert!(best_match_many_from_sets(&[], &haystack_sets).is_empty());
+    }
+
+    #[test]
+    fn test_similarity_batch_consistency() {
+        // Test that batch processing gives same results as individual
+        let pairs = [
+            ("hello".to_string(), "world".to_string()),
+            ("foo".to_string(), "bar".to_string()),
+            ("test".to_string(), "testing".to_string()),
+        ];
+
+        let expected: Vec<f32> = pairs
+            .iter()
+            .map(|(a, b)| compute_similarity(a, b))
+            .collect();
+
+        // Compare element by element
+        for (i, (a, b)) in pairs.iter().enumerate() {
+            let score = compute_similarity(a, b);
+            assert_eq!(score, expected[i], "Mismatch at index {}", i);
+        }
+    }
+
+    #[test]
+    fn test_length_prefilter_preserves_results() {
+        let needle = "international business machines";
+        let needle_set = trigrams(needle);
+        let mut haystacks = strings(&["a", "ab", "intl", "business", "", "machines ltd"]);
+        haystacks.extend(strings(&[
+            "international business machine",
+            "zzzzzzzzzzzzzzzzzzzzzzzz",
+        ]));
+        haystacks.extend((0..DEFAULT_PARALLEL_THRESHOLD).map(|i| format!("biz {}", i)));
+
+        let skipped = haystacks
+            .iter()
+            .filter(|haystack| length_bound(needle_set.len(), haystack) < 0.3)
+            .count();
+        assert!(skipped > DEFAULT_PARALLEL_THRESHOLD);
+
+        let exact = (
+            score_all_from_set(&needle_set, &haystacks, 0.3),
+            top_k_from_set(&needle_set, &haystacks, 0.1, 3),
+            best_match_from_set(&needle_set, &haystacks[..8]),
+        );
+        let filtered = with_length_prefilter(|| {
+            (
+                score_all_from_set(&needle_set, &haystacks, 0.3),
+                top_k_from_set(&needle_set, &haystacks, 0.1, 3),
+                best_match_from_set(&needle_set, &haystacks[..8]),
+            )
+        });
+        assert_eq!(filtered, exact);
+        assert_eq!(exact.2 .0, 6);
+    }
+
+    #[test]
+    fn test_length_bound_is_an_upper_bound() {
+        let needles = ["hello world", "İstanbul", "東京タワー", "a b c d e f g"];
+        let haystacks = ["h", "he", "İ", "東", "ab cd", "hello", "aaaaaaaaaaaaaaaa"];
+        for needle in needles {
+            let needle_set = trigrams(needle);
+            for haystack in haystacks {
+                let score = similarity_from_sets(&needle_set, &trigrams(haystack));
+                assert!(score <= length_bound(needle_set.len(), haystack));
+            }
+        }
+    }
+
+    #[test]
+    fn test_word_patterns() {
+        let default = trigrams("foo_bar");
+        let word = trigrams_with_pattern("foo_bar", WordPattern::Word);
+
+        assert_ne!(word, default);
+        assert!(word.contains(&compact_ngram(&['o', '_', 'b'])));
+        assert!(!default.contains(&compact_ngram(&['o', '_', 'b'])));
+        assert_eq!(
+            trigrams_with_pattern("foo_bar", WordPattern::Alnum),
+            default
+        );
+
+        let dotted = trigrams_with_pattern("lib.rs", WordPattern::Whitespace);
+        assert!(dotted.contains(&compact_ngram(&['b', '.', 'r'])));
+        assert!(!trigrams_with_pattern("lib.rs", WordPattern::Word)
+            .contains(&compact_ngram(&['b', '.', 'r'])));
+    }
+
+    #[test]
+    fn test_grapheme_mode() {
+        // Man, ZWJ, woman, ZWJ, girl: one grapheme, five chars
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(
+            trigrams_with_pattern(family, WordPattern::Whitespace).len(),
+            6
+        );
+        assert_eq!(trigrams_graphemes(family).len(), 2);
+
+        let skin_tones = "\u{1F44D}\u{1F3FB} \u{1F44D}\u{1F3FF}";
+        assert_eq!(trigrams_graphemes(skin_tones).len(), 4);
+
+        for text in ["hello world", "Привет", "word two words"] {
+            assert_eq!(trigrams_graphemes(text), trigrams(text));
+        }
+    }
+
+    #[test]
+    fn test_similarity_bytes() {
+        for (a, b) in [("word", "two words"), ("İstanbul", "ISTANBUL"), ("", "abc")] {
+            assert_eq!(
+                similarity_bytes(a.as_bytes(), b.as_bytes()).unwrap(),
+                compute_similarity(a, b)
+            );
+        }
+
+        assert!(similarity_bytes(b"ok", b"\xff\xfe").is_err());
+        assert!(similarity_bytes(b"caf\xc3", b"cafe").is_err());
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(pg_downcase("İstanbul"), pg_downcase("istanbul"));
+        assert_eq!(pg_downcase("İstanbul"), "istanbul");
+        assert_eq!(pg_downcase("HeLLo Wörld"), "hello wörld");
+    }
+
+    #[test]
+    fn test_similarity_pct() {
+        let pct = |a: &str, b: &str| pct_from_sets(&trigrams(a), &trigrams(b));
+        assert_eq!(pct("word", "two words"), 36);
+        assert_eq!(pct("ab", "abc"), 40);
+        assert_eq!(pct("hello", "hello"), 100);
+        assert_eq!(pct("abc", "xyz"), 0);
+        assert_eq!(pct("", ""), 0);
+
+        // Round half to even
+        assert_eq!(pct_from_counts(1, 8), 12);
+        assert_eq!(pct_from_counts(3, 8), 38);
+        assert_eq!(pct_from_counts(1, 200), 0);
+        assert_eq!(pct_from_counts(3, 200), 2);
+        assert_eq!(pct_from_counts(2, 3), 67);
+    }
+
+    #[test]
+    fn test_best_match_tie_break() {
+        // "abc", "abc abc" and "ABC!" all score 1.0 against "abc"
+        let mut haystacks = strings(&["xyz", "abc abc", "ABC!", "abd", "abc"]);
+        let needle_set = trigrams("abc");
+
+        let expected = [
+            (TieBreak::First, 1),
+            (TieBreak::Longest, 1),
+            (TieBreak::Shortest, 4),
+        ];
+        for threshold in [usize::MAX, 0] {
+            with_parallel_threshold(threshold, || {
+                for (tie_break, idx) in expected {
+                    assert_eq!(
+                        best_match_by(&needle_set, &haystacks, tie_break),
+                        (idx, 1.0),
+                        "{:?}",
+                        tie_break
+                    );
+                }
+            });
+        }
+
+        // Equal lengths fall back to the lowest index
+        haystacks.push("cab abc".to_string());
+        assert_eq!(
+            best_match_by(&needle_set, &haystacks, TieBreak::Longest),
+            (1, 1.0)
+        );
+        haystacks.push("ABC?".to_string());
+        assert_eq!(
+            best_match_by(&needle_set, &haystacks, TieBreak::Shortest),
+            (4, 1.0)
+        );
+    }
+
+    #[test]
+    fn test_best_match_parallel_ties_are_stable() {
+        // Every third haystack ties at 1.0; the rest score lower
+        let haystacks: Vec<String> = (0..DEFAULT_PARALLEL_THRESHOLD * 4)
+            .map(|i| {
+                if i % 3 == 2 {
+                    "tie tie".to_string()
+                } else {
+                    format!("tiX {}", i)
+                }
+            })
+            .collect();
+        let needle_set = trigrams("tie");
+
+        let sequential =
+            with_parallel_threshold(usize::MAX, || best_match_from_set(&needle_set, &haystacks));
+        assert_eq!(sequential, (2, 1.0));
+
+        with_parallel_threshold(0, || {
+            for _ in 0..50 {
+                assert_eq!(best_match_from_set(&needle_set, &haystacks), sequential);
+            }
+        });
+    }
+
+    #[test]
+    fn test_similarity_checked() {
+        for (a, b) in [
+            ("", ""),
+            ("", "abc"),
+            ("   ", "abc"),
+            ("!!!", "???"),
+            ("abc", "\t\n"),
+        ] {
+            assert_eq!(checked_similarity(a, b), None, "({:?}, {:?})", a, b);
+            assert_eq!(compute_similarity(a, b), 0.0);
+        }
+
+        assert_eq!(checked_similarity("abc", "xyz"), Some(0.0));
+        assert_eq!(checked_similarity("word", "two words"), Some(0.36363637));
+        assert_eq!(checked_similarity("!hello!", "hello"), Some(1.0));
+    }
+
+    #[test]
+    fn test_dice_similarity() {
+        let dice = |a: &str, b: &str| dice_from_sets(&trigrams(a), &trigrams(b));
+
+        // 3 shared of 6 + 6 trigrams
+        assert_eq!(dice("hello", "hallo"), 0.5);
+        assert_eq!(compute_similarity("hello", "hallo"), 1.0 / 3.0);
+        assert_eq!(dice("word", "two words"), 8.0 / 15.0);
+        assert_eq!(dice("hello", "hello"), 1.0);
+        assert_eq!(dice("", ""), 0.0);
+    }
+
+    #[test]
+    fn test_configure_pool() {
+        let haystacks: Vec<String> = (0..DEFAULT_PARALLEL_THRESHOLD * 2)
+            .map(|i| format!("pool item {}", i))
+            .collect();
+        let needle_set = trigrams("pool item 42");
+        let expected = with_parallel_threshold(usize::MAX, || {
+            (
+                score_all_from_set(&needle_set, &haystacks, 0.3),
+                best_match_from_set(&needle_set, &haystacks),
+            )
+        });
+
+        // Installing `POOL` would pin every later parallel test to one
+        // worker, so run on a local single-thread pool instead
+        let pool = new_pool(1).unwrap();
+        assert_eq!(pool.install(rayon::current_num_threads), 1);
+
+        with_parallel_threshold(0, || {
+            assert_eq!(
+                pool.install(|| (
+                    score_all_from_set(&needle_set, &haystacks, 0.3),
+                    best_match_from_set(&needle_set, &haystacks),
+                )),
+                expected
+            );
+        });
+        assert!(POOL.get().is_none());
+    }
+
+    #[test]
+    fn test_script_prefilter_matches_full_computation() {
+        let corpus = [
+            "hello world",
+            "привет мир",
+            "hello мир",
+            "東京タワー",
+            "ΚΑΛΗΜΕΡΑ",
+            "καλημέρα",
+            "Straße",
+            "\u{212A}elvin",
+            "kelvin",
+            "İstanbul",
+            "istanbul",
+            "123",
+            "!!!",
+            "",
+        ];
+        let pairs: Vec<(String, String)> = corpus
+            .iter()
+            .flat_map(|a| corpus.iter().map(move |b| (a.to_string(), b.to_string())))
+            .collect();
+
+        let disjoint = pairs
+            .iter()
+            .filter(|(a, b)| script_mask(a) & script_mask(b) == 0)
+            .count();
+        assert!(disjoint > pairs.len() / 3);
+
+        let full = similarity_pairs(&pairs);
+        assert_eq!(with_script_prefilter(|| similarity_pairs(&pairs)), full);
+        assert_eq!(compute_similarity("\u{212A}elvin", "kelvin"), 1.0);
+    }
+
+    #[test]
+    fn test_similarity_f64() {
+        for (a, b) in [
+            ("word", "two words"),
+            ("hello", "hallo"),
+            ("東京", "東京タワー"),
+            ("", ""),
+        ] {
+            let precise = jaccard_from_sets(&trigrams(a), &trigrams(b));
+            assert_eq!(precise as f32, compute_similarity(a, b));
+        }
+        assert_eq!(
+            jaccard_from_sets(&trigrams("word"), &trigrams("two words")),
+            4.0 / 11.0
+        );
+    }
+
+    #[test]
+    fn test_cluster() {
+        let strings = strings(&[
+            "apple iphone 15",
+            "samsung galaxy s24",
+            "Apple iPhone 15 Pro",
+            "zebra",
+            "samsung galaxy s24 ultra",
+            "apple iphone 15 pro max",
+        ]);
+        let sets = sorted_trigram_sets(&strings);
+
+        let expected = vec![vec![0, 2, 5], vec![1, 4], vec![3]];
+        assert_eq!(cluster_sets(&sets, 0.5), expected);
+        with_parallel_threshold(0, || assert_eq!(cluster_sets(&sets, 0.5), expected));
+
+        assert_eq!(cluster_sets(&sets, 1.1).len(), strings.len());
+        assert!(cluster_sets(&[], 0.5).is_empty());
+
+        // Unions land in whatever order workers finish; the groups can't tell
+        let many: Vec<Vec<TrigramKey>> = (0..400)
+            .map(|i| sorted_keys(&trigrams(&format!("{} {}", strings[i % 6], i % 17))))
+            .collect();
+        for threshold in [0.0, 0.4, 0.7] {
+            let sequential = with_parallel_threshold(usize::MAX, || cluster_sets(&many, threshold));
+            let parallel = with_parallel_threshold(0, || cluster_sets(&many, threshold));
+            assert_eq!(sequential, parallel);
+        }
+    }
+
+    #[test]
+    fn test_ngram_capacity_avoids_growth() {
+        let cjk: String = (0..3000u32)
+            .map(|i| char::from_u32(0x4E00 + i).unwrap())
+            .collect::<Vec<_>>()
+            .chunks(7)
+            .map(|word| word.iter().collect::<String>())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii: String = (0..2000).map(|i| format!("w{} ", i)).collect();
+
+        for text in [&cjk, &ascii] {
+            let normalized = pg_downcase(text);
+            let hint = TrigramHashSet::with_capacity_and_hasher(
+                ngram_capacity(&normalized),
+                Default::default(),
+            )
+            .capacity();
+            let set = ngrams_from_normalized(&normalized, 3);
+            assert!(set.len() > 1000);
+            // The set never rehashed into a bigger table
+            assert_eq!(set.capacity(), hint);
+            assert_eq!(set, trigrams(text));
+        }
+    }
+
+    #[test]
+    fn test_idf_similarity_favors_rare_trigrams() {
+        let needle_set = trigrams("the quokka");
+        let haystacks = strings(&["the", "the cat", "the dog", "qux"]);
+        let sets: Vec<TrigramHashSet> = haystacks.iter().map(|h| trigrams(h)).collect();
+
+        // Plain Jaccard prefers the haystack sharing the common word...
+        let plain = score_all_from_set(&needle_set, &haystacks, 0.0);
+        let position = |results: &[(usize, f32)], idx| results.iter().position(|r| r.0 == idx);
+        assert!(position(&plain, 0) < position(&plain, 3));
+
+        // ...IDF prefers the one sharing the rare " qu" prefix
+        let weighted = idf_scores(&needle_set, &sets);
+        assert_eq!(weighted[0].0, 3);
+        assert!(position(&weighted, 3) < position(&weighted, 0));
+        assert_eq!(weighted.len(), haystacks.len());
+
+        let parallel = with_parallel_threshold(0, || idf_scores(&needle_set, &sets));
+        assert_eq!(parallel, weighted);
+        assert!(idf_scores(&needle_set, &[]).is_empty());
+    }
+
+    #[test]
+    fn test_score_all_progress() {
+        let haystacks: Vec<String> = (0..2_500).map(|i| format!("item {}", i % 400)).collect();
+        let needle_set = trigrams("item 42");
+        let expected = score_all_from_set(&needle_set, &haystacks, 0.3);
+
+        let mut progress = Vec::new();
+        let results = score_all_in_chunks(&needle_set, &haystacks, 0.3, 1_000, |done, total| {
+            progress.push((done, total))
+        });
+        assert_eq!(results, expected);
+        assert_eq!(progress, [(1_000, 2_500), (2_000, 2_500), (2_500, 2_500)]);
+
+        let mut calls = 0;
+        score_all_in_chunks(&needle_set, &haystacks, 0.3, 2_500, |_, _| calls += 1);
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn test_score_all_range() {
+        let needle_set = trigrams("hello");
+        // Scores: 1.0, 0.5, 0.333.., 0.0, 0.5
+        let haystacks = strings(&["hello", "hell", "hallo", "xyz", "help hello x"]);
+        let scores = score_all_from_set(&needle_set, &haystacks, 0.0);
+
+        let middle = score_range_from_set(&needle_set, &haystacks, 0.3, 0.7);
+        let expected: Vec<(usize, f32)> = scores
+            .iter()
+            .copied()
+            .filter(|(_, score)| (0.3..=0.7).contains(score))
+            .collect();
+        assert_eq!(middle, expected);
+        assert!(!middle.is_empty());
+        assert!(middle.iter().all(|(idx, _)| *idx != 0 && *idx != 3));
+
+        assert_eq!(
+            score_range_from_set(&needle_set, &haystacks, 0.0, 1.0),
+            scores
+        );
+        assert!(score_range_from_set(&needle_set, &haystacks, 0.7, 0.3).is_empty());
+    }
+
+    #[test]
+    fn test_trigram_keys_are_sorted_little_endian_bytes() {
+        let keys = trigram_key_bytes("ab");
+        assert_eq!(keys.len(), 3);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(&keys[2][..3], b"ab ");
+        assert!(keys[2][3..].iter().all(|&byte| byte == 0));
+
+        assert!(trigram_key_bytes("").is_empty());
+    }
+
+    // Strings whose trigrams differ from Postgres unless built with `pg_parity`:
+    // `\p{N}` counts `½`, `²` and circled digits as word chars, while `\p{L}`
+    // misses circled letters and Devanagari vowel signs.
+    #[cfg(not(feature = "pg_parity"))]
+    const PG_DIVERGENT: &[&str] = &["①②③", "½ cup", "हिन्दी भाषा", "x² + y³", "Ⓐⓑⓒ"];
+    #[cfg(feature = "pg_parity")]
+    const PG_DIVERGENT: &[&str] = &[];
+
+    // A key as Postgres `show_trgm` prints it: the text if every byte is
+    // alphanumeric or a space, else `0x%06x` of the 3 bytes read big-endian.
+    fn pg_label(key: &[u8]) -> String {
+        let key = &key[..3];
+        if key
+            .iter()
+            .all(|&byte| byte.is_ascii_alphanumeric() || byte == b' ')
+        {
+            String::from_utf8(key.to_vec()).unwrap()
+        } else {
+            let int = key.iter().fold(0u32, |acc, &byte| acc << 8 | byte as u32);
+            format!("0x{:06x}", int)
+        }
+    }
+
+    #[test]
+    fn test_pg_trgm_conformance_corpus() {
+        // Captured from PostgreSQL 15 (C.UTF-8) with
+        // `select s, array_to_string(show_trgm(s), '|')`
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pg_trgm.csv");
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_path(path)
+            .unwrap();
+
+        let mut divergent = Vec::new();
+        let mut rows = 0;
+        for record in reader.records() {
+            let record = record.unwrap();
+            let (text, expected) = (&record[0], &record[1]);
+            let mut expected: Vec<&str> = expected.split('|').filter(|t| !t.is_empty()).collect();
+            expected.sort_unstable();
+
+            let mut actual: Vec<String> = trigram_key_bytes(text)
+                .iter()
+                .map(|key| pg_label(key))
+                .collect();
+            actual.sort_unstable();
+
+            if actual != expected {
+                assert!(
+                    PG_DIVERGENT.contains(&text),
+                    "{text:?}: got {actual:?}, Postgres has {expected:?}"
+                );
+                divergent.push(text.to_string());
+            }
+            rows += 1;
+        }
+
+        assert!(rows > 50);
+        assert_eq!(divergent, PG_DIVERGENT);
+    }
+
+    #[test]
+    fn test_score_file_reports_line_numbers_and_skips_invalid_utf8() {
+        let path =
+            std::env::temp_dir().join(format!("trigram_score_file_{}.txt", std::process::id()));
+        std::fs::write(&path, b"world\r\nhello\n\xff\xfe\nhelp\n\nhallo").unwrap();
+
+        let needle_set = trigrams("hello");
+        let file = std::fs::File::open(&path).unwrap();
+        let (results, skipped) = score_lines(
+            &needle_set,
+            std::io::BufReader::new(file),
+            0.3,
+            0,
+            usize::MAX,
+        )
+        .unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        let (top, top_skipped) = score_lines(
+            &needle_set,
+            std::io::BufReader::new(file),
+            0.3,
+            2,
+            usize::MAX,
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(top, results[..2]);
+        assert_eq!(top_skipped, 1);
+
+        assert_eq!(skipped, 1);
+        let lines: Vec<usize> = results.iter().map(|&(line, _)| line).collect();
+        assert_eq!(lines, vec![1, 3, 5]);
+        assert_eq!(results[0].1, 1.0);
+        assert_eq!(results[2].1, compute_similarity("hello", "hallo"));
+
+        let missing = std::fs::File::open(&path).unwrap_err();
+        assert_eq!(posix_reason(&missing), "enoent");
+
+        // Over-long lines are skipped, even unterminated or split by `\r\n`
+        let (results, skipped) = score_lines(
+            &needle_set,
+            std::io::Cursor::new(b"hello\nhello world\nhelp\r\nhelp!!\r\nhello, world"),
+            0.3,
+            0,
+            5,
+        )
+        .unwrap();
+        assert_eq!(skipped, 3);
+        let lines: Vec<usize> = results.iter().map(|&(line, _)| line).collect();
+        assert_eq!(lines, vec![0, 2]);
+
+        // Paths are bytes: a non-UTF-8 name round-trips on Unix
+        #[cfg(unix)]
+        {
+            let mut name = std::env::temp_dir().into_os_string().into_encoded_bytes();
+            name.extend_from_slice(b"/trigram_\xff_score_file.txt");
+            let path = path_from_bytes(&name).unwrap();
+            std::fs::write(&path, "hello\n").unwrap();
+            let file = std::fs::File::open(&path).unwrap();
+            let (results, _) = score_lines(
+                &needle_set,
+                std::io::BufReader::new(file),
+                0.3,
+                0,
+                usize::MAX,
+            )
+            .unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(results, vec![(0, 1.0)]);
+        }
+    }
+
+    #[test]
+    fn test_score_all_dedup_expands_duplicates() {
+        let haystacks = strings(&["hallo", "world", "hello", "hallo", "help", "hello"]);
+        let needle_set = trigrams("hello");
+
+        let results = score_all_dedup_from_set(&needle_set, &haystacks, 0.3);
+        assert_eq!(results, score_all_from_set(&needle_set, &haystacks, 0.3));
+
+        let indices: Vec<usize> = results.iter().map(|&(idx, _)| idx).collect();
+        assert_eq!(indices, vec![2, 5, 4, 0, 3]);
+        assert_eq!(results[0].1, results[1].1);
+        assert_eq!(results[3].1, compute_similarity("hello", "hallo"));
+    }
+
+    #[test]
+    fn test_sorted_keys_overlap_matches_hash_sets() {
+        let texts = [
+            "hello",
+            "hallo",
+            "help",
+            "",
+            "héllo wörld",
+            "world hello",
+            "zzz",
+        ];
+        for a in texts {
+            for b in texts {
+                let (a_set, b_set) = (trigrams(a), trigrams(b));
+                let (a_keys, b_keys) = (sorted_keys(&a_set), sorted_keys(&b_set));
+                assert_eq!(
+                    overlap_counts_sorted(&a_keys, &b_keys),
+                    overlap_counts(&a_set, &b_set)
+                );
+                assert_eq!(
+                    similarity_from_sorted(&a_keys, &b_keys),
+                    compute_similarity(a, b)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_hybrid_similarity_uses_jaro_winkler_for_short_words() {
+        let _guard = CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let trigram = compute_similarity("jon", "john");
+        let hybrid_score = hybrid("jon", "john");
+        assert!(trigram < 0.3);
+        assert!((hybrid_score - 0.933).abs() < 1e-3);
+
+        // Reference values for the classic pairs
+        assert!((jaro_winkler("martha", "marhta") - 0.961).abs() < 1e-3);
+        assert!((jaro_winkler("dixon", "dicksonx") - 0.813).abs() < 1e-3);
+        assert_eq!(jaro_winkler("", ""), 0.0);
+        assert_eq!(hybrid("LAX", "lax"), 1.0);
+        // Punctuation around the words doesn't reach Jaro-Winkler
+        assert_eq!(hybrid("--- jon ---", "john!!!"), hybrid_score);
+        let noise = "!".repeat(1_000_000);
+        assert_eq!(hybrid(&format!("{}ab{}", noise, noise), &noise), 0.0);
+
+        // Long enough on both sides: plain trigram similarity
+        assert_eq!(
+            hybrid("hello", "hallo"),
+            compute_similarity("hello", "hallo")
+        );
+
+        HYBRID_MIN_CHARS.store(0, AtomicOrdering::Relaxed);
+        assert_eq!(hybrid("jon", "john"), trigram);
+        HYBRID_MIN_CHARS.store(DEFAULT_HYBRID_MIN_CHARS, AtomicOrdering::Relaxed);
+    }
+
+    #[test]
+    fn test_levenshtein_and_combined_score() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("héllo", "hello"), 1);
+        assert_eq!(
+            edit_distance(&pg_downcase("HELLO"), &pg_downcase("hello")),
+            0
+        );
+
+        // Same trigram set, so only the edit distance tells them apart
+        let (exact, swapped) = ("john smith", "smith john");
+        assert_eq!(compute_similarity("john smith", exact), 1.0);
+        assert_eq!(compute_similarity("john smith", swapped), 1.0);
+        assert_eq!(combined("john smith", exact, 0.5), 1.0);
+        let swapped_score = combined("john smith", swapped, 0.5);
+        assert!(swapped_score < 0.75);
+        assert_eq!(combined("john smith", swapped, 1.0), 1.0);
+        assert_eq!(combined("", "", 0.0), 1.0);
+
+        let at_limit = "é".repeat(MAX_EDIT_CHARS);
+        assert!(check_edit_len(&at_limit, "x").is_ok());
+        let over = "a".repeat(MAX_EDIT_CHARS + 1);
+        assert!(check_edit_len("x", &over).is_err());
+        assert!(check_edit_len(&over, "").is_err());
+    }
+
+    #[test]
+    fn test_prefix_similarity_favours_leading_matches() {
+        let prefix = |haystack: &str| {
+            similarity_from_sets(&prefix_trigrams("app"), &prefix_trigrams(haystack))
+        };
+
+        assert!(prefix("apple") > prefix("snapple"));
+        assert!(prefix("apple") > prefix("my apple"));
+        assert_eq!(prefix("apple"), compute_similarity("app", "apple"));
+
+        // Plain padding can't tell a leading word from a later one
+        assert!(prefix("my apple") < compute_similarity("app", "my apple"));
+        assert_eq!(prefix("App"), 1.0);
+        assert_eq!(prefix(""), 0.0);
+    }
+
+    #[test]
+    fn test_similarity_minword_drops_short_words() {
+        let (s1, s2) = ("a motion to dismiss", "motion to dismiss");
+        assert_eq!(trigrams_minword(s1, 1), trigrams(s1));
+        assert_eq!(trigrams_minword(s2, 0), trigrams(s2));
+
+        let unfiltered = similarity_from_sets(&trigrams_minword(s1, 1), &trigrams_minword(s2, 1));
+        let filtered = similarity_from_sets(&trigrams_minword(s1, 2), &trigrams_minword(s2, 2));
+        assert_eq!(unfiltered, compute_similarity(s1, s2));
+        assert!(unfiltered < 1.0);
+        assert_eq!(filtered, 1.0);
+
+        assert!(trigrams_minword("a b c", 2).is_empty());
+        assert_eq!(
+            similarity_from_sets(&trigrams_minword("a b", 2), &trigrams_minword("a b", 2)),
+            0.0
+        );
+    }
+
+    #[test]
+    fn test_similarity_strict_names_the_empty_side() {
+        assert_eq!(strict_similarity("", "abc"), Err(EmptySide::Left));
+        assert_eq!(strict_similarity("!!!", "abc"), Err(EmptySide::Left));
+        assert_eq!(strict_similarity("abc", "\t\n"), Err(EmptySide::Right));
+        assert_eq!(strict_similarity("", "   "), Err(EmptySide::Both));
+        assert_eq!(strict_similarity("!!!", "???"), Err(EmptySide::Both));
+
+        assert_eq!(strict_similarity("abc", "xyz"), Ok(0.0));
+        assert_eq!(strict_similarity("word", "two words"), Ok(0.36363637));
+        assert_eq!(EmptySide::Both.name(), "both");
+    }
+
+    #[test]
+    fn test_trigram_set_counts_give_jaccard() {
+        for (a, b) in [
+            ("word", "two words"),
+            ("hello", "hallo"),
+            ("abc", ""),
+            ("", ""),
+        ] {
+            let (union, intersection, difference) = set_algebra_counts(a, b);
+            assert_eq!(union, intersection + difference);
+            assert_eq!(union + intersection, trigrams(a).len() + trigrams(b).len());
+            assert_eq!(
+                jaccard_from_counts(intersection, union) as f32,
+                compute_similarity(a, b)
+            );
+        }
+
+        assert_eq!(set_algebra_counts("word", "two words"), (11, 4, 7));
+    }
+
+    fn with_min_parallel_chunk<R>(min_len: usize, f: impl FnOnce() -> R) -> R {
+        MIN_PARALLEL_CHUNK.store(min_len, AtomicOrdering::Relaxed);
+        let result = f();
+        MIN_PARALLEL_CHUNK.store(1, AtomicOrdering::Relaxed);
+        result
+    }
+
+    fn short_strings(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("k{}", i % 997)).collect()
+    }
+
+    #[test]
+    fn test_min_parallel_chunk_keeps_results() {
+        let haystacks = short_strings(5000);
+        let pairs: Vec<(String, String)> = haystacks
+            .iter()
+            .map(|h| ("k42".to_string(), h.clone()))
+            .collect();
+        let needle_set = trigrams("k42");
+
+        with_parallel_threshold(0, || {
+            let scores = score_all_from_set(&needle_set, &haystacks, 0.1);
+            let batch = similarity_pairs(&pairs);
+            with_min_parallel_chunk(1024, || {
+                assert_eq!(score_all_from_set(&needle_set, &haystacks, 0.1), scores);
+                assert_eq!(similarity_pairs(&pairs), batch);
+            });
+        });
+    }
+
+    #[test]
+    fn test_similarity_and_best_match_on_prepared_sets() {
+        let prepared = |text: &str| TrigramSet {
+            set: trigrams(text),
+        };
+        for (a, b) in [("hello", "hallo"), ("word", "two words"), ("", "abc")] {
+            let (set_a, set_b) = (prepared(a), prepared(b));
+            assert_eq!(
+                similarity_from_sets(&set_a.set, &set_b.set),
+                compute_similarity(a, b)
+            );
+        }
+
+        let needle = prepared("hello");
+        let haystacks: Vec<TrigramSet> = ["world", "hallo", "hello", "help", "hello"]
+            .iter()
+            .map(|h| prepared(h))
+            .collect();
+        let sets: Vec<&TrigramHashSet> = haystacks.iter().map(|h| &h.set).collect();
+        assert_eq!(best_match_among_sets(&needle.set, &sets), (2, 1.0));
+        with_parallel_threshold(0, || {
+            assert_eq!(best_match_among_sets(&needle.set, &sets), (2, 1.0));
+        });
+    }
+
+    #[test]
+    fn test_similarity_padded() {
+        let padded = |s1: &str, s2: &str, leading, trailing| {
+            padded_similarity(s1, s2, leading, trailing).unwrap()
+        };
+
+        for text in ["hello world", "a", "", "héllo   wörld"] {
+            assert_eq!(trigrams_padded(text, 2, 1), trigrams(text));
+        }
+        let (s1, s2) = ("new york city", "york new town");
+        assert_eq!(padded(s1, s2, 2, 1), compute_similarity(s1, s2));
+
+        // Fewer pads drop boundary trigrams and move the score
+        assert!(padded(s1, s2, 0, 0) < padded(s1, s2, 2, 1));
+        assert!(padded("hello", "jello", 0, 1) > padded("hello", "jello", 2, 1));
+
+        assert!(padded_similarity(s1, s2, 3, 1).is_err());
+        assert!(padded_similarity(s1, s2, 2, 3).is_err());
+    }
+
+    #[test]
+    fn test_similarity_ws_distinguishes_whitespace_runs() {
+        let ws = |s1: &str, s2: &str| similarity_from_sets(&trigrams_ws(s1), &trigrams_ws(s2));
+
+        assert_eq!(compute_similarity("a  b", "a b"), 1.0);
+        assert!(ws("a  b", "a b") < 1.0);
+        assert!(ws("space\ttabs", "space tabs") < 1.0);
+        assert!(
+            ws("space   tabs", "space tabs") < compute_similarity("space   tabs", "space tabs")
+        );
+
+        assert_eq!(ws("a b", "A B"), 1.0);
+        assert_eq!(ws("hello", "hello"), compute_similarity("hello", "hello"));
+        assert_eq!(trigrams_ws("hello"), trigrams("hello"));
+    }
+
+    #[test]
+    fn test_normalize_batch_matches_normalize() {
+        let inputs = strings(&["Hello", "", "İSTANBUL", "ÀÉÎ õü", "  MiXeD  "]);
+        let expected: Vec<String> = inputs.iter().map(|s| pg_downcase(s)).collect();
+
+        assert_eq!(normalize_all(&inputs), expected);
+        assert_eq!(normalize_all(&inputs)[1], "");
+        with_parallel_threshold(0, || assert_eq!(normalize_all(&inputs), expected));
+        assert!(normalize_all(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_similarity_batch_binary_layout() {
+        let pairs: Vec<(String, String)> = [("hello", "hallo"), ("", ""), ("word", "two words")]
+            .iter()
+            .map(|(a, b)| (a.to_string(), b.to_string()))
+            .collect();
+        let scores = similarity_pairs(&pairs);
+
+        let mut bytes = vec![0u8; scores.len() * 4];
+        pack_f32_le(&scores, &mut bytes);
+        let decoded: Vec<f32> = bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+
+        assert_eq!(decoded, scores);
+        assert_eq!(&bytes[8..12], &0.36363637f32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_score_all_packed_matches_score_all() {
+        let haystacks = strings(&["world", "hallo", "", "hello", "héllo wörld", "help"]);
+        let blob: String = haystacks.concat();
+        let mut offsets = Vec::new();
+        let mut start = 0;
+        for haystack in &haystacks {
+            offsets.push(start);
+            start += haystack.len();
+        }
+
+        let unpacked = unpack_haystacks(blob.as_bytes(), &offsets).unwrap();
+        assert_eq!(unpacked, haystacks);
+        let needle_set = trigrams("hello");
+        for min_threshold in [0.0, 0.3] {
+            assert_eq!(
+                score_all_from_set(&needle_set, &unpacked, min_threshold),
+                score_all_from_set(&needle_set, &haystacks, min_threshold)
+            );
+        }
+
+        assert_eq!(unpack_haystacks(b"", &[]), Some(vec![]));
+        assert_eq!(unpack_haystacks(b"abc", &[2, 1]), None);
+        assert_eq!(unpack_haystacks(b"abc", &[4]), None);
+        assert_eq!(unpack_haystacks("é".as_bytes(), &[0, 1]), None);
+        // Offsets past 4 GiB are compared whole, never truncated to 32 bits
+        assert_eq!(unpack_haystacks(b"abc", &[0, (1 << 32) + 1]), None);
+    }
+
+    #[test]
+    fn test_best_match_threshold() {
+        let haystacks = strings(&["world", "hallo", "help"]);
+        let needle_set = trigrams("hello");
+
+        let best = best_match_from_set(&needle_set, &haystacks);
+        assert_eq!(best_match_above(&needle_set, &haystacks, 0.3), Some(best));
+        assert_eq!(
+            best_match_above(&needle_set, &haystacks, best.1),
+            Some(best)
+        );
+        assert_eq!(best_match_above(&needle_set, &haystacks, 0.9), None);
+        assert_eq!(
+            best_match_above(&trigrams("zzz"), &haystacks, 0.0),
+            Some((0, 0.0))
+        );
+        assert_eq!(best_match_above(&trigrams("zzz"), &haystacks, 0.01), None);
+    }
+
+    #[test]
+    fn test_tversky_generalizes_jaccard_and_dice() {
+        for (a, b) in [
+            ("hello", "hallo"),
+            ("word", "two words"),
+            ("abc", "xyz"),
+            ("", ""),
+        ] {
+            let (a_set, b_set) = (trigrams(a), trigrams(b));
+            assert_eq!(
+                tversky_from_sets(&a_set, &b_set, 1.0, 1.0),
+                compute_similarity(a, b)
+            );
+            assert_eq!(
+                tversky_from_sets(&a_set, &b_set, 0.5, 0.5),
+                dice_from_sets(&a_set, &b_set)
+            );
+        }
+
+        // Weighting only the needle's misses: "two" is fully inside "two words"
+        let (needle, haystack) = (trigrams("two"), trigrams("two words"));
+        assert_eq!(tversky_from_sets(&needle, &haystack, 1.0, 0.0), 1.0);
+        assert!(tversky_from_sets(&needle, &haystack, 0.0, 1.0) < 1.0);
+    }
+
+    #[test]
+    fn test_similarity_digitclass_ignores_which_digits() {
+        let digitclass = |s1: &str, s2: &str| {
+            similarity_from_sets(&trigrams_digitclass(s1), &trigrams_digitclass(s2))
+        };
+
+        assert_eq!(digitclass("ABC-123", "abc-456"), 1.0);
+        assert!(compute_similarity("ABC-123", "abc-456") < 0.5);
+        assert_eq!(digitclass("order ٣٤٥", "order 987"), 1.0);
+
+        // Letters still matter; runs of 3+ digits all share one trigram set
+        assert!(digitclass("ABC-123", "ABD-123") < 1.0);
+        assert_eq!(digitclass("ABC-123", "ABC-1234"), 1.0);
+        assert_eq!(
+            digitclass("hello", "hallo"),
+            compute_similarity("hello", "hallo")
+        );
+    }
+
+    #[test]
+    fn test_fuzzy_contains() {
+        let sentence = "the quick brown fox jumps over the lazzy dog";
+        let needle_set = trigrams("lazy");
+        assert!(contains_similar_word(&needle_set, sentence, 0.5));
+        // The whole sentence is far below the threshold; the one word isn't
+        assert!(compute_similarity("lazy", sentence) < 0.5);
+        assert!(contains_similar_word(&needle_set, "LAZZY!", 0.5));
 
-    let mut results: Vec<(usize, f32)> = if haystacks.len() < PARALLEL_THRESHOLD {
-        haystacks
-            .iter()
-            .enumerate()
-            .map(|(idx, haystack)| {
-                let haystack_set = trigrams(haystack);
-                (idx, similarity_from_sets(&needle_set, &haystack_set))
-            })
-            .filter(|(_, score)| *score >= min_threshold)
-            .collect()
-    } else {
-        haystacks
-            .par_iter()
-            .enumerate()
-            .map(|(idx, haystack)| {
-                let haystack_set = trigrams(haystack);
-                (idx, similarity_from_sets(&needle_set, &haystack_set))
-            })
-            .filter(|(_, score)| *score >= min_threshold)
-            .collect()
-    };
+        assert!(!contains_similar_word(
+            &needle_set,
+            "completely unrelated words",
+            0.5
+        ));
+        assert!(!contains_similar_word(&needle_set, sentence, 0.6));
+        assert!(!contains_similar_word(&needle_set, "", 0.0));
+    }
 
-    // Use unstable sort (faster), order of equal elements not guaranteed
-    results.sort_unstable_by(|(idx_a, score_a), (idx_b, score_b)| {
-        score_b
-            .partial_cmp(score_a)
-            .unwrap_or(Ordering::Equal)
-            .then_with(|| idx_a.cmp(idx_b))
-    });
+    #[test]
+    fn test_best_matches_multi_keeps_best_needle_score() {
+        let needles = strings(&["colour", "color"]);
+        let haystacks = strings(&["colorado", "color", "colour", "collar", "red"]);
 
-    results
-}
+        let top = top_k_multi(&needles, &haystacks, 3);
+        // "color" ranks on its exact match with the second needle, even though
+        // the first scores it lower
+        assert_eq!(top[0], (1, 1.0));
+        assert_eq!(top[1], (2, 1.0));
+        assert!(compute_similarity("colour", "color") < 1.0);
+        assert_eq!(top.len(), 3);
+        assert!(top.windows(2).all(|w| rank_order(&w[0], &w[1]).is_lt()));
 
-// -----------------------------------------------------------------------------
-// Core Logic & Helpers
-// -----------------------------------------------------------------------------
+        let all = top_k_multi(&needles, &haystacks, 10);
+        let mut indices: Vec<usize> = all.iter().map(|&(idx, _)| idx).collect();
+        indices.sort_unstable();
+        assert_eq!(indices, [0, 1, 2, 3, 4]);
+        for &(idx, score) in &all {
+            let best = needles
+                .iter()
+                .map(|n| compute_similarity(n, &haystacks[idx]))
+                .fold(0.0, f32::max);
+            assert_eq!(score, best);
+        }
 
-fn similarity_from_sets(a_set: &FxHashSet<[u8; 3]>, b_set: &FxHashSet<[u8; 3]>) -> f32 {
-    let shared = a_set.intersection(b_set).count() as f64;
-    let total = (a_set.len() + b_set.len()) as f64 - shared;
+        let parallel = with_parallel_threshold(0, || top_k_multi(&needles, &haystacks, 3));
+        assert_eq!(parallel, top);
+        assert!(top_k_multi(&[], &haystacks, 3).is_empty());
+    }
 
-    let value = if total == 0.0 { 0.0 } else { shared / total };
-    value as f32
-}
+    #[test]
+    fn test_max_input_len() {
+        let _guard = CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let limit = 1 << 20;
+        MAX_INPUT_LEN.store(limit, AtomicOrdering::Relaxed);
 
-fn trigrams(text: &str) -> FxHashSet<[u8; 3]> {
-    // CRITICAL: Must normalize (lowercase + remove \u{0307}) BEFORE regex matching
-    // to match PostgreSQL pg_trgm behavior exactly. This order matters for edge cases.
-    let normalized = pg_downcase(text);
+        let words = ["alpha", "beta", "gamma", "delta", "épsilon"];
+        let mut blob = String::with_capacity(limit);
+        for i in 0.. {
+            let word = format!("{}{} ", words[i % words.len()], i % 1000);
+            if blob.len() + word.len() > limit {
+                break;
+            }
+            blob.push_str(&word);
+        }
+        blob.extend(std::iter::repeat_n('x', limit - blob.len()));
 
-    // Heuristic: Bytes/3 prevents massive over-allocation for CJK
-    // but ensures enough space for ASCII. Min 16 to avoid tiny reallocs.
-    let capacity = (normalized.len() / 3).max(16);
+        assert_eq!(blob.len(), limit);
+        assert!(check_input_len(&[&blob.as_str(), &"alpha"]).is_ok());
+        let blob_set = trigrams(&blob);
+        assert_eq!(similarity_from_sets(&blob_set, &blob_set), 1.0);
+        // Every trigram of "alpha1" also occurs in the blob
+        let score = compute_similarity("alpha1", &blob);
+        assert_eq!(score, 7.0 / blob_set.len() as f32);
 
-    // Use FxHasher (fast) instead of default SipHasher (secure/slow)
-    let mut set = FxHashSet::with_capacity_and_hasher(capacity, Default::default());
+        blob.push('x');
+        assert!(check_input_len(&[&"alpha", &blob.as_str()]).is_err());
+        assert!(check_input_len(&[&"alpha"]).is_ok());
+        // Batch shapes are checked element by element
+        let pairs = vec![
+            ("alpha".to_string(), "beta".to_string()),
+            ("gamma".to_string(), blob.clone()),
+        ];
+        assert!(check_input_len(&[&pairs]).is_err());
+        assert!(check_input_len(&[&pairs[..1].to_vec()]).is_ok());
+        assert!(check_input_len(&[&"alpha", &vec!["beta".to_string(), blob.clone()]]).is_err());
 
-    // Reusable buffer to avoid allocating a new Vec for every word
-    let mut char_buf: Vec<char> = Vec::with_capacity(64);
+        MAX_INPUT_LEN.store(DEFAULT_MAX_INPUT_LEN, AtomicOrdering::Relaxed);
+    }
 
-    for mat in WORD_RE.find_iter(&normalized) {
-        char_buf.clear();
-        char_buf.extend([' ', ' ']); // Pre-padding
+    #[test]
+    fn test_weighted_similarity_favors_word_edges() {
+        let score = |a: &str, b: &str| weighted_jaccard(&trigram_weights(a), &trigram_weights(b));
 
-        // Text is already lowercased and \u{0307} removed by pg_downcase
-        char_buf.extend(mat.as_str().chars());
+        // Both edits leave 3 of 6 trigrams shared, so plain similarity ties
+        assert_eq!(
+            compute_similarity("smith", "xmith"),
+            compute_similarity("smith", "smxth")
+        );
+        let start_edit = score("smith", "xmith");
+        let middle_edit = score("smith", "smxth");
+        assert!(
+            start_edit < middle_edit,
+            "{} vs {}",
+            start_edit,
+            middle_edit
+        );
+        // Shared weight 1 + 1 + 2 over 9 + 9 - 4, and 2 + 2 + 2 over 9 + 9 - 6
+        assert_eq!(start_edit, (4.0 / 14.0) as f32);
+        assert_eq!(middle_edit, 0.5);
 
-        char_buf.push(' '); // Post-padding
+        assert_eq!(score("Smith", "smith"), 1.0);
+        assert_eq!(score("", "smith"), 0.0);
+        assert_eq!(score("", ""), 0.0);
+    }
 
-        for window in char_buf.windows(3) {
-            let trigram = compact_trigram(window[0], window[1], window[2]);
-            set.insert(trigram);
+    #[test]
+    fn test_score_histogram_buckets() {
+        assert_eq!(score_bucket(0.0, 4), 0);
+        assert_eq!(score_bucket(0.25, 4), 0);
+        assert_eq!(score_bucket(0.26, 4), 1);
+        assert_eq!(score_bucket(0.5, 4), 1);
+        assert_eq!(score_bucket(0.75, 4), 2);
+        assert_eq!(score_bucket(0.9, 4), 3);
+        assert_eq!(score_bucket(1.0, 4), 3);
+        assert_eq!(score_bucket(1.0, 1), 0);
+        // Edges that aren't exact in binary still go to the lower bucket
+        assert_eq!(score_bucket(0.3, 10), 2);
+        assert_eq!(score_bucket(0.7, 10), 6);
+        assert_eq!(score_bucket(0.1, 10), 0);
+        assert_eq!(score_bucket(0.30000004, 10), 3);
+        for i in 1..=10 {
+            assert_eq!(score_bucket(i as f32 / 10.0, 10), i - 1);
         }
+
+        // "hello" scores 1.0 against itself, 0.0 against "world" and "",
+        // 3/8 against "help" and 6/12 against "hello world", on a boundary
+        let needle_set = trigrams("hello");
+        let haystacks = strings(&["hello", "world", "", "help", "hello world", "hello!"]);
+        assert_eq!(compute_similarity("hello", "help"), 0.375);
+        assert_eq!(compute_similarity("hello", "hello world"), 0.5);
+        assert_eq!(histogram_from_set(&needle_set, &haystacks, 4), [2, 2, 0, 2]);
+        assert_eq!(histogram_from_set(&needle_set, &haystacks, 1), [6]);
+
+        let many: Vec<String> = (0..2_000).map(|i| haystacks[i % 6].clone()).collect();
+        let sequential = histogram_from_set(&needle_set, &many, 10);
+        let parallel = with_parallel_threshold(0, || histogram_from_set(&needle_set, &many, 10));
+        assert_eq!(sequential, parallel);
+        assert_eq!(sequential.iter().sum::<u32>(), 2_000);
     }
-    set
-}
 
-/// Normalize text to match PostgreSQL pg_trgm behavior:
-/// lowercase + remove combining dot above (\u{0307})
-fn pg_downcase(text: &str) -> String {
-    let mut result = String::with_capacity(text.len());
-    for c in text.chars() {
-        for lc in c.to_lowercase() {
-            if lc != '\u{0307}' {
-                result.push(lc);
-            }
+    // Pinned on the default FxHash build; `cargo test --features ahash` must
+    // reproduce every value, since sets are only ever counted, never ordered
+    #[test]
+    fn test_scores_are_hasher_independent() {
+        let pinned = [
+            ("hello", "help", 3.0 / 8.0),
+            ("hello", "hello world", 0.5),
+            ("word", "two words", 4.0 / 11.0),
+            ("Straße", "strasse", 4.0 / 11.0),
+            ("İstanbul", "istanbul", 1.0),
+        ];
+        for (a, b, expected) in pinned {
+            assert_eq!(compute_similarity(a, b), expected as f32, "{:?} {:?}", a, b);
         }
+
+        let haystacks = strings(&["help", "hello world", "hello", "world"]);
+        assert_eq!(
+            crate::core::score_all("hello", &haystacks, 0.0),
+            [(2, 1.0), (1, 0.5), (0, 0.375), (3, 0.0)]
+        );
     }
-    result
-}
 
-fn compact_trigram(a: char, b: char, c: char) -> [u8; 3] {
-    // OPTIMIZATION: Stack allocation instead of Heap Vec
-    let mut buf = [0u8; 4];
-    let mut bytes = [0u8; 12]; // Max UTF-8 size for 3 chars
-    let mut len = 0;
+    #[test]
+    fn test_best_match_all_ties() {
+        let haystacks = strings(&["hallo", "HELLO", "help", "hello", "world", "Hello!"]);
+        let needle_set = trigrams("hello");
+        assert_eq!(
+            best_ties_from_set(&needle_set, &haystacks),
+            (1.0, vec![1, 3, 5])
+        );
 
-    for ch in [a, b, c] {
-        let encoded = ch.encode_utf8(&mut buf);
-        // SAFETY: We have pre-allocated 12 bytes, max 3 chars * 4 bytes = 12.
-        bytes[len..len + encoded.len()].copy_from_slice(encoded.as_bytes());
-        len += encoded.len();
-    }
+        let no_tie = strings(&["hallo", "help", "hello world"]);
+        assert_eq!(best_ties_from_set(&needle_set, &no_tie), (0.5, vec![2]));
 
-    if len == 3 {
-        // Fast path for ASCII (1 byte per char)
-        [bytes[0], bytes[1], bytes[2]]
-    } else {
-        // Fallback for multi-byte chars: Calculate CRC32
-        let crc = legacy_crc32(&bytes[..len]);
-        let crc_bytes = crc.to_le_bytes();
-        [crc_bytes[0], crc_bytes[1], crc_bytes[2]]
-    }
-}
-
-fn legacy_crc32(bytes: &[u8]) -> u32 {
-    let mut crc: u32 = 0xFFFF_FFFF;
-
-    for &byte in bytes {
-        let idx = ((crc >> 24) as u8 ^ byte) as usize & 0xFF;
-        // SAFETY: Table size is 256, idx is masked & 0xFF.
-        let table_val = unsafe { *PG_CRC32_TABLE.get_unchecked(idx) };
-        crc = (table_val ^ (crc << 8)) & 0xFFFF_FFFF;
-    }
-
-    crc ^ 0xFFFF_FFFF
-}
-
-// Full PostgreSQL CRC32 Table
-const PG_CRC32_TABLE: [u32; 256] = [
-    0x00000000, 0x77073096, 0xEE0E612C, 0x990951BA, 0x076DC419, 0x706AF48F, 0xE963A535, 0x9E6495A3,
-    0x0EDB8832, 0x79DCB8A4, 0xE0D5E91E, 0x97D2D988, 0x09B64C2B, 0x7EB17CBD, 0xE7B82D07, 0x90BF1D91,
-    0x1DB71064, 0x6AB020F2, 0xF3B97148, 0x84BE41DE, 0x1ADAD47D, 0x6DDDE4EB, 0xF4D4B551, 0x83D385C7,
-    0x136C9856, 0x646BA8C0, 0xFD62F97A, 0x8A65C9EC, 0x14015C4F, 0x63066CD9, 0xFA0F3D63, 0x8D080DF5,
-    0x3B6E20C8, 0x4C69105E, 0xD56041E4, 0xA2677172, 0x3C03E4D1, 0x4B04D447, 0xD20D85FD, 0xA50AB56B,
-    0x35B5A8FA, 0x42B2986C, 0xDBBBC9D6, 0xACBCF940, 0x32D86CE3, 0x45DF5C75, 0xDCD60DCF, 0xABD13D59,
-    0x26D930AC, 0x51DE003A, 0xC8D75180, 0xBFD06116, 0x21B4F4B5, 0x56B3C423, 0xCFBA9599, 0xB8BDA50F,
-    0x2802B89E, 0x5F058808, 0xC60CD9B2, 0xB10BE924, 0x2F6F7C87, 0x58684C11, 0xC1611DAB, 0xB6662D3D,
-    0x76DC4190, 0x01DB7106, 0x98D220BC, 0xEFD5102A, 0x71B18589, 0x06B6B51F, 0x9FBFE4A5, 0xE8B8D433,
-    0x7807C9A2, 0x0F00F934, 0x9609A88E, 0xE10E9818, 0x7F6A0DBB, 0x086D3D2D, 0x91646C97, 0xE6635C01,
-    0x6B6B51F4, 0x1C6C6162, 0x856530D8, 0xF262004E, 0x6C0695ED, 0x1B01A57B, 0x8208F4C1, 0xF50FC457,
-    0x65B0D9C6, 0x12B7E950, 0x8BBEB8EA, 0xFCB9887C, 0x62DD1DDF, 0x15DA2D49, 0x8CD37CF3, 0xFBD44C65,
-    0x4DB26158, 0x3AB551CE, 0xA3BC0074, 0xD4BB30E2, 0x4ADFA541, 0x3DD895D7, 0xA4D1C46D, 0xD3D6F4FB,
-    0x4369E96A, 0x346ED9FC, 0xAD678846, 0xDA60B8D0, 0x44042D73, 0x33031DE5, 0xAA0A4C5F, 0xDD0D7CC9,
-    0x5005713C, 0x270241AA, 0xBE0B1010, 0xC90C2086, 0x5768B525, 0x206F85B3, 0xB966D409, 0xCE61E49F,
-    0x5EDEF90E, 0x29D9C998, 0xB0D09822, 0xC7D7A8B4, 0x59B33D17, 0x2EB40D81, 0xB7BD5C3B, 0xC0BA6CAD,
-    0xEDB88320, 0x9ABFB3B6, 0x03B6E20C, 0x74B1D29A, 0xEAD54739, 0x9DD277AF, 0x04DB2615, 0x73DC1683,
-    0xE3630B12, 0x94643B84, 0x0D6D6A3E, 0x7A6A5AA8, 0xE40ECF0B, 0x9309FF9D, 0x0A00AE27, 0x7D079EB1,
-    0xF00F9344, 0x8708A3D2, 0x1E01F268, 0x6906C2FE, 0xF762575D, 0x806567CB, 0x196C3671, 0x6E6B06E7,
-    0xFED41B76, 0x89D32BE0, 0x10DA7A5A, 0x67DD4ACC, 0xF9B9DF6F, 0x8EBEEFF9, 0x17B7BE43, 0x60B08ED5,
-    0xD6D6A3E8, 0xA1D1937E, 0x38D8C2C4, 0x4FDFF252, 0xD1BB67F1, 0xA6BC5767, 0x3FB506DD, 0x48B2364B,
-    0xD80D2BDA, 0xAF0A1B4C, 0x36034AF6, 0x41047A60, 0xDF60EFC3, 0xA867DF55, 0x316E8EEF, 0x4669BE79,
-    0xCB61B38C, 0xBC66831A, 0x256FD2A0, 0x5268E236, 0xCC0C7795, 0xBB0B4703, 0x220216B9, 0x5505262F,
-    0xC5BA3BBE, 0xB2BD0B28, 0x2BB45A92, 0x5CB36A04, 0xC2D7FFA7, 0xB5D0CF31, 0x2CD99E8B, 0x5BDEAE1D,
-    0x9B64C2B0, 0xEC63F226, 0x756AA39C, 0x026D930A, 0x9C0906A9, 0xEB0E363F, 0x72076785, 0x05005713,
-    0x95BF4A82, 0xE2B87A14, 0x7BB12BAE, 0x0CB61B38, 0x92D28E9B, 0xE5D5BE0D, 0x7CDCEFB7, 0x0BDBDF21,
-    0x86D3D2D4, 0xF1D4E242, 0x68DDB3F8, 0x1FDA836E, 0x81BE16CD, 0xF6B9265B, 0x6FB077E1, 0x18B74777,
-    0x88085AE6, 0xFF0F6A70, 0x66063BCA, 0x11010B5C, 0x8F659EFF, 0xF862AE69, 0x616BFFD3, 0x166CCF45,
-    0xA00AE278, 0xD70DD2EE, 0x4E048354, 0x3903B3C2, 0xA7672661, 0xD06016F7, 0x4969474D, 0x3E6E77DB,
-    0xAED16A4A, 0xD9D65ADC, 0x40DF0B66, 0x37D83BF0, 0xA9BCAE53, 0xDEBB9EC5, 0x47B2CF7F, 0x30B5FFE9,
-    0xBDBDF21C, 0xCABAC28A, 0x53B39330, 0x24B4A3A6, 0xBAD03605, 0xCDD70693, 0x54DE5729, 0x23D967BF,
-    0xB3667A2E, 0xC4614AB8, 0x5D681B02, 0x2A6F2B94, 0xB40BBE37, 0xC30C8EA1, 0x5A05DF1B, 0x2D02EF8D,
-];
+        let many: Vec<String> = (0..1_000).map(|i| haystacks[i % 6].clone()).collect();
+        let expected = best_ties_from_set(&needle_set, &many);
+        assert_eq!(expected.1.len(), 500);
+        assert!(expected.1.windows(2).all(|w| w[0] < w[1]));
+        let parallel = with_parallel_threshold(0, || best_ties_from_set(&needle_set, &many));
+        assert_eq!(parallel, expected);
 
-rustler::init!("Elixir.Trigram.Native");
+        // Nothing shares a trigram: every haystack ties at 0.0
+        let unrelated = strings(&["abc", "xyz"]);
+        assert_eq!(
+            best_ties_from_set(&needle_set, &unrelated),
+            (0.0, vec![0, 1])
+        );
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_ordered_similarity_penalizes_word_swaps() {
+        let ordered = ordered_pair_similarity;
 
-    // Helper to compute similarity using internal functions
-    fn compute_similarity(a: &str, b: &str) -> f32 {
-        let a_set = trigrams(a);
-        let b_set = trigrams(b);
-        similarity_from_sets(&a_set, &b_set)
+        assert_eq!(compute_similarity("John Smith", "Smith John"), 1.0);
+        let swapped = ordered("John Smith", "Smith John");
+        assert!(swapped < 0.7, "{}", swapped);
+        assert_eq!(ordered("John Smith", "john,  SMITH"), 1.0);
+
+        // A single word pads exactly like `similarity`
+        assert_eq!(ordered_trigrams("hello"), trigrams("hello"));
+        assert!(ordered_trigrams("  ,, ").is_empty());
+        assert_eq!(ordered("", ""), 0.0);
     }
 
     #[test]
-    fn test_similarity_identical() {
-        assert_eq!(compute_similarity("hello", "hello"), 1.0);
+    fn test_similarity_boundary_modes() {
+        let score = boundary_similarity;
+        let has = |text: &str, mode, trigram: &str| {
+            let chars: Vec<char> = trigram.chars().collect();
+            let set = match mode {
+                BoundaryMode::PerWord => trigrams(text),
+                BoundaryMode::WholeString => ordered_trigrams(text),
+            };
+            set.contains(&compact_ngram(&chars))
+        };
+
+        // Per word, "york" starts afresh; whole-string trades that for "w y"
+        assert!(has("new york", BoundaryMode::PerWord, "  y"));
+        assert!(!has("new york", BoundaryMode::PerWord, "w y"));
+        assert!(has("new york", BoundaryMode::WholeString, "w y"));
+        assert!(!has("new york", BoundaryMode::WholeString, "  y"));
+
+        // Against "newyork" both modes share the same 6 of 11 trigrams...
+        assert_eq!(
+            score("new york", "newyork", BoundaryMode::PerWord),
+            6.0 / 11.0
+        );
+        assert_eq!(
+            score("new york", "newyork", BoundaryMode::WholeString),
+            6.0 / 11.0
+        );
+        // ...but only whole-string padding tells the word order apart
+        assert_eq!(score("new york", "york new", BoundaryMode::PerWord), 1.0);
+        assert!(score("new york", "york new", BoundaryMode::WholeString) < 0.7);
+
+        for (a, b) in [("new york", "newyork"), ("hello", "help"), ("", "abc")] {
+            assert_eq!(score(a, b, BoundaryMode::PerWord), compute_similarity(a, b));
+            assert_eq!(
+                score(a, b, BoundaryMode::WholeString),
+                ordered_pair_similarity(a, b)
+            );
+        }
     }
 
     #[test]
-    fn test_similarity_empty() {
-        assert_eq!(compute_similarity("", ""), 0.0);
+    fn test_similarity_matrix_binary_layout() {
+        let strings = strings(&["hello", "hallo", "", "world", "hello"]);
+        let n = strings.len();
+        let sets = sorted_trigram_sets(&strings);
+        let mut bytes = vec![0u8; n * n * 4];
+        write_matrix_le(&sets, &mut bytes);
+        let mut parallel = vec![0u8; n * n * 4];
+        with_parallel_threshold(0, || write_matrix_le(&sets, &mut parallel));
+        assert_eq!(parallel, bytes);
+
+        let matrix: Vec<Vec<f32>> = bytes
+            .chunks_exact(n * 4)
+            .map(|row| {
+                row.chunks_exact(4)
+                    .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                    .collect()
+            })
+            .collect();
+        for i in 0..n {
+            let expected = if strings[i].is_empty() { 0.0 } else { 1.0 };
+            assert_eq!(matrix[i][i], expected);
+            for j in 0..n {
+                assert_eq!(matrix[i][j], matrix[j][i]);
+                assert_eq!(matrix[i][j], compute_similarity(&strings[i], &strings[j]));
+            }
+        }
+        assert_eq!(matrix, similarity_matrix_from_sets(&sets));
+        write_matrix_le(&[], &mut []);
     }
 
     #[test]
-    fn test_similarity_partial() {
-        let score = compute_similarity("hello", "hallo");
-        assert!(score > 0.0 && score < 1.0);
+    fn test_normalize_and_count_matches_separate_calls() {
+        for text in [
+            "Hello World",
+            "",
+            "İSTANBUL",
+            "東京タワー",
+            "a",
+            "!!",
+            "hello hello",
+        ] {
+            let (normalized, count) = normalized_with_count(text);
+            assert_eq!(normalized, pg_downcase(text), "{:?}", text);
+            assert_eq!(count, trigrams(text).len(), "{:?}", text);
+        }
+        assert_eq!(normalized_with_count("Hi"), ("hi".to_string(), 3));
     }
 
     #[test]
-    fn test_trigrams_basic() {
-        let set = trigrams("hello");
-        // Should have trigrams: "  h", " he", "hel", "ell", "llo", "lo "
-        assert_eq!(set.len(), 6);
+    fn test_panics_become_internal_errors() {
+        let haystacks = strings(&["hallo", "hello"]);
+        let needle_set = trigrams("hello");
+
+        PANIC_NEXT.set(true);
+        let result = catch_internal(|| best_match_from_set(&needle_set, &haystacks));
+        assert_eq!(result, Err("internal"));
+        // The hook fires once; the next call runs normally
+        let result = catch_internal(|| best_match_from_set(&needle_set, &haystacks));
+        assert_eq!(result, Ok((1, 1.0)));
+
+        // A panic on a Rayon worker is re-raised on this thread and caught too
+        let from_worker = catch_internal(|| {
+            in_pool(|| {
+                (0..1_000).into_par_iter().for_each(|i| {
+                    if i == 777 {
+                        panic!("worker {}", i);
+                    }
+                })
+            })
+        });
+        assert_eq!(from_worker, Err("internal"));
+
+        // Without an error tuple to return, the panic raises `:internal`
+        PANIC_NEXT.set(true);
+        let raised = guard_internal(|| similarity_matrix_from_sets(&[]));
+        assert!(matches!(raised, Err(rustler::Error::RaiseAtom("internal"))));
+        assert!(guard_internal(|| similarity_matrix_from_sets(&[])).is_ok());
     }
 
     #[test]
-    fn test_trigrams_unicode() {
-        // Test Turkish dotless i handling (İ → i when lowercased, with \u{0307} removed)
-        let set1 = trigrams("İstanbul");
-        let set2 = trigrams("istanbul");
-        // After case folding and \u{0307} removal, the trigram SETS should be identical
-        // because İ lowercases to "i" + "\u{0307}", and we filter out \u{0307}
+    fn test_similarity_joinshort_merges_initialisms() {
+        let joined =
+            |a: &str, b: &str| similarity_from_sets(&trigrams_joinshort(a), &trigrams_joinshort(b));
+
+        assert!(compute_similarity("U.S.A.", "usa") < 0.3);
+        assert_eq!(joined("U.S.A.", "usa"), 1.0);
+        assert_eq!(joined("U S A", "usa"), 1.0);
         assert_eq!(
-            set1, set2,
-            "İstanbul and istanbul should produce identical trigram sets"
+            trigrams_joinshort("made in the U.S.A."),
+            trigrams("made in the usa")
         );
-        // And thus similarity should be 1.0
-        let score = compute_similarity("İstanbul", "istanbul");
-        assert_eq!(score, 1.0, "Score should be 1.0, was {}", score);
-    }
 
-    #[test]
-    fn test_compact_trigram_ascii() {
-        let result = compact_trigram('a', 'b', 'c');
-        assert_eq!(result, [b'a', b'b', b'c']);
+        // Other separators, and words of several chars, stay apart
+        assert_eq!(trigrams_joinshort("U-S-A"), trigrams("U S A"));
+        assert_eq!(trigrams_joinshort("a bc d"), trigrams("a bc d"));
+        assert_eq!(trigrams_joinshort("hello world"), trigrams("hello world"));
+        assert!(trigrams_joinshort("").is_empty());
     }
 
     #[test]
-    fn test_compact_trigram_unicode() {
-        // Multi-byte chars should use CRC32
-        let result = compact_trigram('é', 'é', 'é');
-        // Should not be simple bytes, should be CRC32 based
-        assert_ne!(result[0], 0);
+    fn test_similarity_any_is_max_of_similarity() {
+        let needles = strings(&["colour", "color", "colr", "東京", ""]);
+        for input in ["color", "Colours", "kolor", "東京都", "", "unrelated"] {
+            let expected = needles
+                .iter()
+                .map(|needle| pair_similarity(input, needle))
+                .fold(0.0, f32::max);
+            assert_eq!(max_similarity(input, &needles), expected, "{:?}", input);
+        }
+        assert_eq!(max_similarity("color", &needles), 1.0);
+        assert_eq!(max_similarity("color", &[]), 0.0);
     }
 
     #[test]
-    fn test_legacy_crc32() {
-        // Known CRC32 value for "abc"
-        let crc = legacy_crc32(b"abc");
-        // PostgreSQL's legacy CRC32 should produce consistent results
-        assert_ne!(crc, 0);
+    fn test_detailed_batch_matches_similarity_detailed() {
+        let words = ["hello", "hallo", "world", "two words", "", "東京", "a"];
+        let pairs: Vec<(String, String)> = (0..600)
+            .map(|i| {
+                (
+                    words[i % words.len()].to_string(),
+                    format!("{} {}", words[(i / 7) % words.len()], i % 5),
+                )
+            })
+            .collect();
+        let expected: Vec<(u32, u32, f32)> = pairs
+            .iter()
+            .map(|(s1, s2)| {
+                let (shared, total, score) = detailed_counts(s1, s2);
+                (shared as u32, total as u32, score)
+            })
+            .collect();
+
+        for threshold in [1, usize::MAX] {
+            let batch = with_parallel_threshold(threshold, || detailed_pairs(&pairs));
+            assert_eq!(batch, expected);
+        }
+        for (&(shared, total, score), (s1, s2)) in expected.iter().zip(&pairs) {
+            assert_eq!(score, compute_similarity(s1, s2));
+            if total > 0 {
+                assert_eq!(score, (f64::from(shared) / f64::from(total)) as f32);
+            }
+        }
+        assert!(detailed_pairs(&[]).is_empty());
     }
 
     #[test]
-    fn test_pg_similarity_cases() {
-        // Test cases from SimilarityCases.pairs() in Elixir tests
-        // These pairs should have POSITIVE similarity (same script, accent differences)
-        let positive_similarity_cases = vec![
-            ("café", "cafe"),
-            ("naïve", "naive"),
-            ("über", "uber"),
-            ("São", "Sao"),
-            ("ångström", "angstrom"),
-            ("fiancé", "fiance"),
-            ("東京", "東京"),      // identical
-            ("foo_bar", "foobar"), // partial match
-            ("hello-world", "hello world"),
-            ("hello—world", "hello world"),
-            ("Apt #4B", "Apt 4B"),
-            ("LLC", "L.L.C."),
-            ("co-op", "coop"),
-            ("123-456", "123456"),
-            ("mid–range", "mid range"),
-            ("space   tabs", "space tabs"),
-            ("résumé", "resume"),
-            ("façade", "facade"),
-            ("İstanbul", "istanbul"),
-            ("straße", "strasse"),
-        ];
-
-        for (left, right) in positive_similarity_cases {
-            let score = compute_similarity(left, right);
-            assert!(
-                score > 0.0,
-                "Expected positive similarity for ({}, {}), got {}",
-                left,
-                right,
-                score
+    fn test_similarity_capped_bounds_huge_input() {
+        // Under the cap nothing changes
+        for (a, b) in [
+            ("hello world", "hello there"),
+            ("東京 タワー", "東京"),
+            ("", "x"),
+        ] {
+            assert_eq!(
+                similarity_from_sets(&trigrams_capped(a, 1000), &trigrams_capped(b, 1000)),
+                compute_similarity(a, b)
             );
         }
 
-        // These pairs have ZERO similarity (different scripts, no shared trigrams)
-        let zero_similarity_cases = vec![
-            ("привет", "privet"), // Cyrillic vs Latin
-            ("東京", "东 京"),    // Traditional vs Simplified Chinese (different chars)
-            ("Ελλάδα", "Ellada"), // Greek vs Latin
-        ];
+        let huge: String = (0..50_000)
+            .map(|i| char::from(b'a' + (i * 7 % 26) as u8))
+            .chain((0..50_000).map(|i| char::from_u32(0x4e00 + i % 20_000).unwrap()))
+            .collect();
+        let start = std::time::Instant::now();
+        let capped = trigrams_capped(&huge, 500);
+        assert_eq!(capped.len(), 500);
+        // The first trigrams in text order are kept, whatever the cap
+        assert!(trigrams_capped(&huge, 100)
+            .iter()
+            .all(|key| capped.contains(key)));
+        let score = similarity_from_sets(&capped, &trigrams_capped(&huge[..40_000], 500));
+        assert!(start.elapsed() < std::time::Duration::from_secs(2));
+        assert!((0.0..=1.0).contains(&score));
+        assert!(trigrams_capped(&huge, 0).is_empty());
+    }
 
-        for (left, right) in zero_similarity_cases {
-            let score = compute_similarity(left, right);
-            // These should NOT crash and should return 0 (different scripts)
-            assert!(
-                score >= 0.0,
-                "Score should be non-negative for ({}, {}), got {}",
-                left,
-                right,
-                score
+    #[test]
+    fn test_one_to_many_matches_similarity() {
+        let candidates: Vec<String> = (0..400)
+            .map(|i| {
+                format!(
+                    "{} {}",
+                    ["hello", "hallo", "東京", "", "help"][i % 5],
+                    i % 9
+                )
+            })
+            .collect();
+        for reference in ["hello 3", "東京", ""] {
+            let expected: Vec<f32> = candidates
+                .iter()
+                .map(|candidate| pair_similarity(reference, candidate))
+                .collect();
+            for threshold in [1, usize::MAX] {
+                let scores =
+                    with_parallel_threshold(threshold, || one_to_many(reference, &candidates));
+                assert_eq!(scores, expected);
+            }
+            assert_eq!(
+                with_script_prefilter(|| one_to_many(reference, &candidates)),
+                expected
             );
         }
+        assert!(one_to_many("hello", &[]).is_empty());
     }
 
     #[test]
-    fn test_identical_after_normalization() {
-        // These pairs should be IDENTICAL after case normalization
-        let identical_pairs = vec![
-            ("Hello", "hello"),
-            ("WORLD", "world"),
-            ("İstanbul", "istanbul"), // Turkish İ → i after \u{0307} removal
-        ];
+    fn test_score_all_result_orders() {
+        let haystacks: Vec<String> = (0..600)
+            .map(|i| format!("{} {}", ["hello", "hallo", "help", "world"][i % 4], i % 11))
+            .collect();
+        let needle_set = trigrams("hello 3");
+        let by_score = score_all_from_set(&needle_set, &haystacks, 0.2);
+        let mut by_index = by_score.clone();
+        by_index.sort_unstable_by_key(|&(idx, _)| idx);
 
-        for (left, right) in identical_pairs {
-            let score = compute_similarity(left, right);
-            assert_eq!(
-                score, 1.0,
-                "Expected exact match for ({}, {}), got {}",
-                left, right, score
-            );
+        for threshold in [1, usize::MAX] {
+            with_parallel_threshold(threshold, || {
+                let scores = |order| scores_in_order(&needle_set, &haystacks, 0.2, 0, order);
+                assert_eq!(scores(ResultOrder::ByScore), by_score);
+                assert_eq!(scores(ResultOrder::ByIndex), by_index);
+                // Unspecified order, but the same results
+                let mut unsorted = scores(ResultOrder::None);
+                unsorted.sort_unstable_by(rank_order);
+                assert_eq!(unsorted, by_score);
+            });
         }
+
+        let top = |order| scores_in_order(&needle_set, &haystacks, 0.2, 5, order);
+        assert_eq!(top(ResultOrder::ByScore), by_score[..5]);
+        let mut top_by_index = by_score[..5].to_vec();
+        top_by_index.sort_unstable_by_key(|&(idx, _)| idx);
+        assert_eq!(top(ResultOrder::ByIndex), top_by_index);
     }
 
     #[test]
-    fn test_trigram_set_identity() {
-        // Verify that trigram sets are identical for case-normalized equivalents
-        let set1 = trigrams("İstanbul");
-        let set2 = trigrams("istanbul");
-        assert_eq!(set1.len(), set2.len(), "Trigram set sizes differ");
+    fn test_fuzzy_join() {
+        let left = strings(&["john smith", "acme corp", "東京タワー", "zzz", ""]);
+        let right = strings(&[
+            "acme corporation",
+            "jon smith",
+            "john smith",
+            "東京タワー",
+            "unrelated entry",
+            "smith john",
+        ]);
+        let sets = |strings: &[String]| strings.iter().map(|s| sorted_keys(&trigrams(s))).collect();
+        let (left_sets, right_sets): (Vec<_>, Vec<_>) = (sets(&left), sets(&right));
 
-        // Check that all trigrams in set1 are in set2
-        for t in &set1 {
-            assert!(set2.contains(t), "Trigram {:?} not found in set2", t);
+        for threshold in [0.0, 0.3, 0.5, 1.0] {
+            let expected: Vec<(usize, usize, f32)> = (0..left.len())
+                .flat_map(|i| (0..right.len()).map(move |j| (i, j)))
+                .map(|(i, j)| (i, j, compute_similarity(&left[i], &right[j])))
+                .filter(|&(_, _, score)| score >= threshold)
+                .collect();
+            for parallel in [1, usize::MAX] {
+                let joined = with_parallel_threshold(parallel, || {
+                    join_sets(&left_sets, &right_sets, threshold)
+                });
+                assert_eq!(joined, expected, "threshold {}", threshold);
+            }
         }
+
+        let joined = join_sets(&left_sets, &right_sets, 0.5);
+        assert!(joined.contains(&(0, 2, 1.0)));
+        assert!(joined.contains(&(2, 3, 1.0)));
+        assert!(joined.iter().any(|&(i, j, _)| (i, j) == (0, 1)));
+        assert!(joined.iter().all(|&(_, _, score)| score >= 0.5));
+        assert!(join_sets(&[], &right_sets, 0.5).is_empty());
     }
 
     #[test]
-    fn test_similarity_batch_consistency() {
-        // Test that batch processing gives same results as individual
-        let pairs = vec![
-            ("hello".to_string(), "world".to_string()),
-            ("foo".to_string(), "bar".to_string()),
-            ("test".to_string(), "testing".to_string()),
+    fn test_fuzzy_join_count_matches_join() {
+        let words = [
+            "john smith",
+            "jon smith",
+            "acme corp",
+            "acme",
+            "東京",
+            "",
+            "smith",
         ];
-
-        let expected: Vec<f32> = pairs
-            .iter()
-            .map(|(a, b)| compute_similarity(a, b))
+        let left: Vec<Vec<TrigramKey>> = (0..300)
+            .map(|i| sorted_keys(&trigrams(&format!("{} {}", words[i % 7], i % 4))))
+            .collect();
+        let right: Vec<Vec<TrigramKey>> = (0..200)
+            .map(|i| sorted_keys(&trigrams(&format!("{} {}", words[(i / 3) % 7], i % 5))))
             .collect();
 
-        // Compare element by element
-        for (i, (a, b)) in pairs.iter().enumerate() {
-            let score = compute_similarity(a, b);
-            assert_eq!(score, expected[i], "Mismatch at index {}", i);
+        for threshold in [0.0, 0.2, 0.5, 0.9, 1.0] {
+            for parallel in [1, usize::MAX] {
+                with_parallel_threshold(parallel, || {
+                    let joined = join_sets(&left, &right, threshold);
+                    assert_eq!(join_count(&left, &right, threshold), joined.len() as u64);
+                });
+            }
+        }
+        assert_eq!(join_count(&left, &[], 0.0), 0);
+    }
+
+    #[test]
+    fn test_document_frequencies() {
+        let corpus = strings(&[
+            "the cat", "the hat", "THE END", "cat nap", "東京", "東京", "the",
+        ]);
+        let df = document_frequencies(&corpus);
+        // "the" is in four documents, more than any other word
+        assert_eq!(
+            df[..4],
+            [
+                ("  t".to_string(), 4),
+                (" th".to_string(), 4),
+                ("he ".to_string(), 4),
+                ("the".to_string(), 4),
+            ]
+        );
+        let count = |label: &str| df.iter().find(|(l, _)| l == label).map(|&(_, c)| c);
+        assert_eq!(count("at "), Some(3));
+        assert_eq!(count("cat"), Some(2));
+        assert_eq!(count("nap"), Some(1));
+        // Multi-byte trigrams keep their show_trgm label
+        for label in show_trigrams("東京") {
+            assert!(label.starts_with("0x"));
+            assert_eq!(count(&label), Some(2));
+        }
+        assert!(df.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+        assert_eq!(
+            with_parallel_threshold(1, || document_frequencies(&corpus)),
+            df
+        );
+        assert!(document_frequencies(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_identical_strings_short_circuit() {
+        let pairs = [
+            ("hello world", "hello world"),
+            ("Hello World", "HELLO world"),
+            ("", ""),
+            ("!!! ???", "!!!"),
+            ("İstanbul", "i\u{307}stanbul"),
+            ("東京", "東京"),
+            ("hello", "help"),
+        ];
+        for (s1, s2) in pairs {
+            let full = similarity_from_sets(&trigrams(s1), &trigrams(s2));
+            assert_eq!(pair_similarity(s1, s2), full, "({:?}, {:?})", s1, s2);
+        }
+        assert_eq!(pair_similarity("Hello", "hello"), 1.0);
+        assert_eq!(pair_similarity("", ""), 0.0);
+        assert_eq!(pair_similarity("...", "..."), 0.0);
+    }
+
+    #[test]
+    fn test_is_similar_matches_similarity() {
+        let words = strings(&[
+            "hello",
+            "hallo",
+            "help",
+            "hello world",
+            "world",
+            "",
+            "!!!",
+            "東京",
+            "東京タワー",
+            "the quick brown fox",
+            "quick brown",
+            "a",
+        ]);
+        let thresholds = [
+            -1.0,
+            0.0,
+            0.1,
+            0.25,
+            0.3,
+            0.5,
+            0.75,
+            0.9,
+            1.0,
+            1.5,
+            f32::NAN,
+        ];
+        for s1 in &words {
+            for s2 in &words {
+                let score = pair_similarity(s1, s2);
+                for threshold in thresholds {
+                    assert_eq!(
+                        pair_reaches(s1, s2, threshold),
+                        score >= threshold,
+                        "({:?}, {:?}) at {}",
+                        s1,
+                        s2,
+                        threshold
+                    );
+                }
+                // Exactly at the score, rounding must not flip the answer
+                assert!(pair_reaches(s1, s2, score));
+            }
         }
+        assert!(with_script_prefilter(|| !pair_reaches(
+            "hello", "東京", 0.1
+        )));
     }
 }
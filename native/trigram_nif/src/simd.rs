@@ -0,0 +1,104 @@
+//! SSE2 block merge for counting the keys two `sorted_keys` slices share,
+//! enabled by the `simd` feature on x86_64. Counts are exactly those of the
+//! scalar merge walk; only the speed differs.
+
+use std::arch::x86_64::{
+    __m128i, _mm_castsi128_ps, _mm_cmpeq_epi32, _mm_loadu_si128, _mm_movemask_ps, _mm_or_si128,
+    _mm_shuffle_epi32,
+};
+
+use crate::core::{merge_shared_count, TrigramKey};
+
+// Keys compared per block
+const LANES: usize = 4;
+
+/// Number of keys present in both `a` and `b`, which must be sorted and free
+/// of duplicates. Compares 4x4 blocks at a time, then finishes the tails with
+/// the scalar merge.
+pub(crate) fn shared_count(a: &[TrigramKey], b: &[TrigramKey]) -> usize {
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+
+    while i + LANES <= a.len() && j + LANES <= b.len() {
+        // SAFETY: SSE2 is part of the x86_64 baseline, and both blocks were
+        // bounds-checked above; unaligned loads accept any `u32` slice.
+        shared += unsafe {
+            let va = _mm_loadu_si128(a[i..].as_ptr() as *const __m128i);
+            let vb = _mm_loadu_si128(b[j..].as_ptr() as *const __m128i);
+            // Every lane of `va` against every rotation of `vb`
+            let matches = _mm_or_si128(
+                _mm_or_si128(
+                    _mm_cmpeq_epi32(va, vb),
+                    _mm_cmpeq_epi32(va, _mm_shuffle_epi32(vb, 0b00_11_10_01)),
+                ),
+                _mm_or_si128(
+                    _mm_cmpeq_epi32(va, _mm_shuffle_epi32(vb, 0b01_00_11_10)),
+                    _mm_cmpeq_epi32(va, _mm_shuffle_epi32(vb, 0b10_01_00_11)),
+                ),
+            );
+            _mm_movemask_ps(_mm_castsi128_ps(matches)).count_ones() as usize
+        };
+
+        // Whichever block ends lower can't match anything further on
+        let (a_last, b_last) = (a[i + LANES - 1], b[j + LANES - 1]);
+        if a_last <= b_last {
+            i += LANES;
+        }
+        if b_last <= a_last {
+            j += LANES;
+        }
+    }
+
+    shared + merge_shared_count(&a[i..], &b[j..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift64*, enough to scatter test keys
+    fn scramble(seed: u64) -> u64 {
+        let mut x = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    // Sorted, distinct keys drawn from `0..range`
+    fn random_keys(seed: u64, len: usize, range: u64) -> Vec<TrigramKey> {
+        let mut keys: Vec<TrigramKey> = (0..len as u64)
+            .map(|i| (scramble(seed << 32 | i) % range) as TrigramKey)
+            .collect();
+        keys.sort_unstable();
+        keys.dedup();
+        keys
+    }
+
+    #[test]
+    fn test_shared_count_matches_scalar_merge() {
+        for seed in 0..200u64 {
+            let len_a = (scramble(seed) % 5000) as usize;
+            let len_b = (scramble(seed + 1000) % 5000) as usize;
+            let range = 1 + scramble(seed + 2000) % 20_000;
+            let a = random_keys(seed, len_a, range);
+            let b = random_keys(seed + 7, len_b, range);
+
+            assert_eq!(
+                shared_count(&a, &b),
+                merge_shared_count(&a, &b),
+                "seed {}",
+                seed
+            );
+            assert_eq!(
+                shared_count(&b, &a),
+                merge_shared_count(&a, &b),
+                "seed {}",
+                seed
+            );
+        }
+
+        let a = random_keys(1, 3000, 10_000);
+        assert_eq!(shared_count(&a, &a), a.len());
+        assert_eq!(shared_count(&a, &[]), 0);
+    }
+}
@@ -0,0 +1,211 @@
+//! Compact sketches of trigram sets for approximate near-duplicate detection.
+//!
+//! MinHash signatures estimate Jaccard similarity (what `similarity` computes)
+//! from a fixed number of `u32`s, and LSH banding turns them into bucket keys
+//! so only likely near-duplicates need exact scoring. SimHash instead folds a
+//! whole document into one 64-bit fingerprint compared by Hamming distance. All
+//! hashing uses fixed seeds, so sketches are stable across runs and comparable
+//! across nodes.
+
+use rustler::NifResult;
+
+use crate::{check_input_len, guard_internal, trigram_counts, trigrams, TrigramHashSet};
+
+/// SplitMix64 finalizer: a fast, well-mixed 64-bit permutation.
+fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// Seed for the `i`th MinHash function
+fn seed(i: usize) -> u64 {
+    mix64((i as u64).wrapping_add(0x9e37_79b9_7f4a_7c15))
+}
+
+fn minhash_from_set(set: &TrigramHashSet, num_hashes: usize) -> Vec<u32> {
+    (0..num_hashes)
+        .map(|i| {
+            let seed = seed(i);
+            set.iter()
+                .map(|&key| (mix64(u64::from(key) ^ seed) >> 32) as u32)
+                .min()
+                .unwrap_or(u32::MAX)
+        })
+        .collect()
+}
+
+fn estimate_jaccard(sig1: &[u32], sig2: &[u32]) -> f32 {
+    if sig1.is_empty() {
+        return 0.0;
+    }
+    let matching = sig1.iter().zip(sig2).filter(|(a, b)| a == b).count();
+    matching as f32 / sig1.len() as f32
+}
+
+/// Band `b` of a signature split into `num_bands` bands of
+/// `rows = signature.len() / num_bands` consecutive positions, hashed together
+/// with its band number so equal rows in different bands don't collide.
+fn band_hashes(signature: &[u32], num_bands: usize) -> Vec<u64> {
+    let rows = signature.len() / num_bands;
+    signature
+        .chunks(rows)
+        .enumerate()
+        .map(|(band, values)| {
+            values
+                .iter()
+                .fold(mix64(band as u64), |acc, &v| mix64(acc ^ u64::from(v)))
+        })
+        .collect()
+}
+
+// Each trigram votes on every bit of its hash, weighted by occurrence count
+fn simhash_of(text: &str) -> u64 {
+    let mut votes = [0i64; 64];
+    for (&key, &count) in &trigram_counts(text) {
+        let hash = mix64(u64::from(key));
+        for (bit, vote) in votes.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *vote += i64::from(count);
+            } else {
+                *vote -= i64::from(count);
+            }
+        }
+    }
+    votes
+        .iter()
+        .enumerate()
+        .filter(|(_, &vote)| vote > 0)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit)
+}
+
+// Cost is `num_hashes` passes over the trigram set
+const MAX_HASHES: usize = 1024;
+
+/// MinHash signature of `text`'s trigram set: the minimum of each of
+/// `num_hashes` seeded hash functions. Empty input yields all `u32::MAX`.
+/// Raises `ArgumentError` for more than 1024 hashes.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn minhash(text: &str, num_hashes: usize) -> NifResult<Vec<u32>> {
+    check_input_len(&[&text])?;
+    if num_hashes > MAX_HASHES {
+        return Err(rustler::Error::BadArg);
+    }
+    guard_internal(|| minhash_from_set(&trigrams(text), num_hashes))
+}
+
+/// Estimated Jaccard similarity: the fraction of positions where two
+/// signatures agree. Signatures must have the same length.
+#[rustler::nif]
+fn minhash_similarity(sig1: Vec<u32>, sig2: Vec<u32>) -> NifResult<f32> {
+    if sig1.len() != sig2.len() {
+        return Err(rustler::Error::BadArg);
+    }
+    guard_internal(|| estimate_jaccard(&sig1, &sig2))
+}
+
+/// Per-band bucket hashes of a MinHash signature; two strings sharing any band
+/// hash are candidate near-duplicates.
+///
+/// With `r` rows per band (`signature length / num_bands`, which must divide
+/// evenly) and true Jaccard `s`, a pair becomes a candidate with probability
+/// `1 - (1 - s^r)^num_bands`. More rows per band make the cut-off sharper and
+/// higher; more bands lower it.
+#[rustler::nif]
+fn lsh_bands(signature: Vec<u32>, num_bands: usize) -> NifResult<Vec<u64>> {
+    if num_bands == 0 || !signature.len().is_multiple_of(num_bands) {
+        return Err(rustler::Error::BadArg);
+    }
+    guard_internal(|| band_hashes(&signature, num_bands))
+}
+
+/// 64-bit SimHash fingerprint of `text`'s trigrams. Similar documents get
+/// fingerprints a small Hamming distance apart.
+#[rustler::nif]
+fn simhash(text: &str) -> NifResult<u64> {
+    check_input_len(&[&text])?;
+    guard_internal(|| simhash_of(text))
+}
+
+/// Number of differing bits between two fingerprints, `0..=64`.
+#[rustler::nif]
+fn simhash_distance(h1: u64, h2: u64) -> u32 {
+    (h1 ^ h2).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::similarity_from_sets;
+
+    #[test]
+    fn test_minhash_estimates_jaccard() {
+        let pairs = [
+            ("hello world", "hello world"),
+            ("the quick brown fox", "the quick brown dog"),
+            ("international business machines", "international business"),
+            ("postgres trigram matching", "trigram matching in postgres"),
+            ("completely", "different"),
+        ];
+        for (a, b) in pairs {
+            let (a_set, b_set) = (trigrams(a), trigrams(b));
+            let exact = similarity_from_sets(&a_set, &b_set);
+            let estimate = estimate_jaccard(
+                &minhash_from_set(&a_set, 128),
+                &minhash_from_set(&b_set, 128),
+            );
+            assert!(
+                (estimate - exact).abs() < 0.1,
+                "({:?}, {:?}): {} vs {}",
+                a,
+                b,
+                estimate,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn test_minhash_is_stable() {
+        let sig = minhash_from_set(&trigrams("abc"), 4);
+        assert_eq!(sig, minhash_from_set(&trigrams("ABC"), 4));
+        assert_eq!(sig.len(), 4);
+        assert_eq!(sig, minhash_from_set(&trigrams("abc"), 8)[..4]);
+        // Pinned so a change to the hash functions can't slip through unnoticed
+        assert_eq!(sig, [490877921, 1432820004, 1405768044, 1417632079]);
+        assert_eq!(minhash_from_set(&trigrams(""), 2), [u32::MAX, u32::MAX]);
+    }
+
+    #[test]
+    fn test_lsh_bands() {
+        let bands = |text: &str| band_hashes(&minhash_from_set(&trigrams(text), 128), 32);
+        let shares_band = |a: &[u64], b: &[u64]| a.iter().zip(b).any(|(x, y)| x == y);
+
+        let original = bands("the quick brown fox jumps over the lazy dog");
+        assert_eq!(original.len(), 32);
+
+        let near = bands("the quick brown fox jumped over the lazy dog");
+        assert!(shares_band(&original, &near));
+
+        let unrelated = bands("lorem ipsum dolor sit amet");
+        assert!(!shares_band(&original, &unrelated));
+    }
+
+    #[test]
+    fn test_simhash_distance() {
+        let doc = "trigram similarity ranks candidate strings by the share of \
+                   three character windows they have in common with the query";
+        let edited = doc.replace("candidate", "possible");
+        let unrelated = "the weather in lisbon was sunny and warm for most of the week";
+
+        let distance = |a: &str, b: &str| (simhash_of(a) ^ simhash_of(b)).count_ones();
+        assert_eq!(simhash_of(doc), simhash_of(&doc.to_uppercase()));
+        assert!(distance(doc, &edited) <= 12, "{}", distance(doc, &edited));
+        assert!(
+            distance(doc, unrelated) >= 20,
+            "{}",
+            distance(doc, unrelated)
+        );
+        assert_eq!(simhash_of(""), 0);
+    }
+}
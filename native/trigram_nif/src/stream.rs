@@ -0,0 +1,154 @@
+//! Incremental trigramming of text that arrives in pieces, such as log lines
+//! read fragment by fragment.
+//!
+//! A word may be split across `stream_feed/2` calls, so the trailing word of
+//! the text seen so far is held back until a later chunk shows where it ends
+//! (or `stream_finalize/1` does). Every word is thus padded exactly once, and
+//! the final set equals `trigrams/1` of the concatenated chunks.
+
+use rustler::{NifResult, ResourceArc};
+use std::sync::atomic::Ordering as AtomicOrdering;
+use std::sync::Mutex;
+
+use crate::{
+    check_input_len, guard_internal, ngrams_from_normalized, pg_downcase, TrigramHashSet,
+    TrigramSet, MAX_INPUT_LEN, WORD_RE,
+};
+
+#[derive(Default)]
+struct StreamState {
+    set: TrigramHashSet,
+    // Normalized tail that may still be the start of a longer word
+    pending: String,
+}
+
+pub(crate) struct StreamTrigrammer {
+    state: Mutex<StreamState>,
+}
+
+#[rustler::resource_impl]
+impl rustler::Resource for StreamTrigrammer {}
+
+impl StreamTrigrammer {
+    fn new() -> Self {
+        StreamTrigrammer {
+            state: Mutex::new(StreamState::default()),
+        }
+    }
+
+    /// Returns `false`, leaving the stream unchanged, if the held-back word
+    /// would grow past `max_len` bytes.
+    fn feed(&self, chunk: &str, max_len: usize) -> bool {
+        let normalized = pg_downcase(chunk);
+        if normalized.is_empty() {
+            return true;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let StreamState { set, pending } = &mut *state;
+
+        // Everything before a word touching the end is final. `pending` is at
+        // most one unfinished word, so only the new text needs scanning.
+        let cut = match WORD_RE.find_iter(&normalized).last() {
+            Some(word) if word.end() == normalized.len() && word.start() == 0 => 0,
+            Some(word) if word.end() == normalized.len() => pending.len() + word.start(),
+            _ => pending.len() + normalized.len(),
+        };
+        if pending.len() + normalized.len() - cut > max_len {
+            return false;
+        }
+        pending.push_str(&normalized);
+        set.extend(ngrams_from_normalized(&pending[..cut], 3));
+        pending.drain(..cut);
+        true
+    }
+
+    /// The trigrams of everything fed so far, leaving the stream empty.
+    fn finalize(&self) -> TrigramHashSet {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let StreamState { mut set, pending } = std::mem::take(&mut *state);
+        set.extend(ngrams_from_normalized(&pending, 3));
+        set
+    }
+}
+
+#[rustler::nif]
+fn stream_new() -> ResourceArc<StreamTrigrammer> {
+    ResourceArc::new(StreamTrigrammer::new())
+}
+
+/// Add `chunk` to the stream. A word may continue from the previous chunk
+/// into this one. Raises `:input_too_long` if the chunk, or a word spanning
+/// several chunks, exceeds `set_max_input_len/1`.
+#[rustler::nif]
+fn stream_feed(stream: ResourceArc<StreamTrigrammer>, chunk: &str) -> NifResult<rustler::Atom> {
+    check_input_len(&[&chunk])?;
+    let max_len = MAX_INPUT_LEN.load(AtomicOrdering::Relaxed);
+    if !guard_internal(|| stream.feed(chunk, max_len))? {
+        return Err(rustler::Error::RaiseAtom("input_too_long"));
+    }
+    Ok(rustler::types::atom::ok())
+}
+
+/// Trigram set of all chunks fed so far, as a `prepare/1` resource usable
+/// with `similarity_precomputed/2` and `similarity_sets/2`. The stream is
+/// emptied and can be fed again.
+#[rustler::nif]
+fn stream_finalize(stream: ResourceArc<StreamTrigrammer>) -> ResourceArc<TrigramSet> {
+    ResourceArc::new(TrigramSet {
+        set: stream.finalize(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trigrams;
+
+    fn streamed(chunks: &[&str]) -> TrigramHashSet {
+        let stream = StreamTrigrammer::new();
+        for chunk in chunks {
+            assert!(stream.feed(chunk, usize::MAX));
+        }
+        stream.finalize()
+    }
+
+    #[test]
+    fn test_split_words_match_whole_text() {
+        assert_eq!(streamed(&["hel", "lo"]), trigrams("hello"));
+        assert_eq!(streamed(&["hel", "", "lo"]), trigrams("hello"));
+
+        let text = "ERROR: Connection to İstanbul-db1 timed out after 30s";
+        let expected = trigrams(text);
+        for split in 1..text.len() {
+            if !text.is_char_boundary(split) {
+                continue;
+            }
+            let (head, tail) = text.split_at(split);
+            assert_eq!(streamed(&[head, tail]), expected, "split at {}", split);
+        }
+        let chars: Vec<String> = text.chars().map(String::from).collect();
+        let chars: Vec<&str> = chars.iter().map(String::as_str).collect();
+        assert_eq!(streamed(&chars), expected);
+
+        // Finalizing empties the stream for reuse
+        let stream = StreamTrigrammer::new();
+        assert!(stream.feed("first line ", usize::MAX));
+        assert_eq!(stream.finalize(), trigrams("first line"));
+        assert!(stream.feed("sec", usize::MAX));
+        assert!(stream.feed("ond", usize::MAX));
+        assert_eq!(stream.finalize(), trigrams("second"));
+        assert!(stream.finalize().is_empty());
+    }
+
+    #[test]
+    fn test_unfinished_word_is_bounded() {
+        let stream = StreamTrigrammer::new();
+        assert!(stream.feed("ab cd", 4));
+        assert!(stream.feed("ef", 4));
+        // "cdef" is held back, so one more letter is too many
+        assert!(!stream.feed("g", 4));
+        // A rejected feed changes nothing, and a word end releases the hold
+        assert!(stream.feed(" gh", 4));
+        assert_eq!(stream.finalize(), trigrams("ab cdef gh"));
+    }
+}
@@ -34,6 +34,17 @@ defmodule Trigram.SimilarityTest do
     assert Trigram.similarity_batch(pairs) == expected
   end
 
+  test "similarity_batch_chunked matches similarity_batch for any chunk size" do
+    pairs = SimilarityCases.pairs()
+    expected = Trigram.similarity_batch(pairs)
+
+    for chunk_size <- [1, 3, length(pairs), length(pairs) + 10] do
+      assert Trigram.similarity_batch_chunked(pairs, chunk_size) == expected
+    end
+
+    assert Trigram.similarity_batch_chunked([], 5) == []
+  end
+
   test "best_match returns lowest index on ties" do
     needle = "hello world"
     haystacks = ["hello world", "hello world", "hullo world"]
@@ -50,4 +61,13 @@ defmodule Trigram.SimilarityTest do
     assert results == Enum.sort_by(results, fn {idx, score} -> {-score, idx} end)
     assert Enum.all?(results, fn {_idx, score} -> score >= 0.3 end)
   end
+
+  test "best_match_threshold reports match, no_match and empty_list" do
+    haystacks = ["world", "hallo", "help"]
+
+    assert {:ok, {2, score}} = Trigram.Native.best_match_threshold("hello", haystacks, 0.3)
+    assert score >= 0.3
+    assert {:error, :no_match} = Trigram.Native.best_match_threshold("hello", haystacks, 0.9)
+    assert {:error, :empty_list} = Trigram.Native.best_match_threshold("hello", [], 0.3)
+  end
 end